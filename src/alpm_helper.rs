@@ -1,209 +1,766 @@
-use crate::{pkg_utils, utils};
+use crate::pkginfo::PackageInfo;
+use crate::repo_db;
 
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
-use std::{env, fs};
 
-use alpm::Alpm;
 use anyhow::{Context, Result};
 
-#[derive(Debug, PartialEq)]
-struct RepoData {
-    repo_name: String,
-    repo_server: String,
+/// Package record of the repo DB, as described by the `desc` entry
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DbPackage {
+    pub name: String,
+    pub base: String,
+    pub version: String,
+    pub filename: String,
+    pub arch: String,
+    pub packager: String,
+    pub builddate: i64,
+    /// Size of the package file in bytes
+    pub csize: u64,
+    /// Installed size of the package in bytes
+    pub isize: u64,
+    pub sha256sum: String,
+    pub replaces: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub provides: Vec<String>,
+    pub depends: Vec<String>,
+    pub optdepends: Vec<String>,
 }
 
-fn init_alpm(pacman_path: &str, repo_list: &[RepoData]) -> Result<Alpm> {
-    let pacman_db_path = format!("{pacman_path}/db");
-    // make sure that it exists, create overwise
-    fs::create_dir_all(&pacman_db_path)?;
+impl DbPackage {
+    /// Parses the `desc` entry of the repo DB
+    pub fn from_desc(desc: &str) -> Result<Self> {
+        let mut db_pkg = Self::default();
+        for (section_name, values) in repo_db::parse_desc_sections(desc) {
+            let first_value = values.first().copied().unwrap_or_default();
+            let values = values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+            match section_name {
+                "NAME" => db_pkg.name = first_value.to_owned(),
+                "BASE" => db_pkg.base = first_value.to_owned(),
+                "VERSION" => db_pkg.version = first_value.to_owned(),
+                "FILENAME" => db_pkg.filename = first_value.to_owned(),
+                "ARCH" => db_pkg.arch = first_value.to_owned(),
+                "PACKAGER" => db_pkg.packager = first_value.to_owned(),
+                "BUILDDATE" => {
+                    db_pkg.builddate = first_value.parse().context("Invalid BUILDDATE")?
+                },
+                "CSIZE" => db_pkg.csize = first_value.parse().context("Invalid CSIZE")?,
+                "ISIZE" => db_pkg.isize = first_value.parse().context("Invalid ISIZE")?,
+                "SHA256SUM" => db_pkg.sha256sum = first_value.to_owned(),
+                "REPLACES" => db_pkg.replaces = values,
+                "CONFLICTS" => db_pkg.conflicts = values,
+                "PROVIDES" => db_pkg.provides = values,
+                "DEPENDS" => db_pkg.depends = values,
+                "OPTDEPENDS" => db_pkg.optdepends = values,
+                _ => {},
+            }
+        }
 
-    let mut handle = Alpm::new(pacman_path, &pacman_db_path)?;
+        if db_pkg.name.is_empty() || db_pkg.version.is_empty() || db_pkg.filename.is_empty() {
+            anyhow::bail!("desc entry doesn't contain NAME, VERSION or FILENAME");
+        }
+        if db_pkg.base.is_empty() {
+            db_pkg.base = db_pkg.name.clone();
+        }
 
-    // add our repos
-    for repo_data in repo_list {
-        let tmprepo = handle
-            .register_syncdb_mut(repo_data.repo_name.clone(), alpm::SigLevel::USE_DEFAULT)
-            .unwrap();
+        Ok(db_pkg)
+    }
 
-        tmprepo.add_server(repo_data.repo_server.clone()).unwrap();
+    /// Creates the record the package file would get in the repo DB, without the file checksums
+    pub fn from_pkginfo(pkginfo: &PackageInfo, filename: &str) -> Self {
+        Self {
+            name: pkginfo.pkgname.clone(),
+            base: pkginfo.pkgbase.clone(),
+            version: pkginfo.pkgver.clone(),
+            filename: filename.to_owned(),
+            arch: pkginfo.arch.clone(),
+            packager: pkginfo.packager.clone(),
+            builddate: pkginfo.builddate,
+            isize: pkginfo.size,
+            replaces: pkginfo.replaces.clone(),
+            conflicts: pkginfo.conflicts.clone(),
+            provides: pkginfo.provides.clone(),
+            depends: pkginfo.depends.clone(),
+            optdepends: pkginfo.optdepends.clone(),
+            ..Default::default()
+        }
     }
 
-    // update the databases
-    handle.syncdbs_mut().update(false)?;
+    pub fn version(&self) -> alpm::Version {
+        alpm::Version::new(self.version.as_str())
+    }
 
-    Ok(handle)
+    /// Returns `pkgname-pkgver` pair as it appears in the repo DB
+    pub fn db_pair(&self) -> String {
+        format!("{}-{}", self.name, self.version)
+    }
 }
 
-fn init_profile_repo(repo_filepath: &str) -> Result<Alpm> {
-    let temp_dir = utils::create_temporary_directory(None).expect("Failed to create temp dir");
+// Reads all packages listed in the repo DB directly from the DB archive.
+// Missing DB is treated as an empty one
+pub fn read_repo_db(repo_db_path: &str) -> Result<Vec<DbPackage>> {
+    if !Path::new(repo_db_path).exists() {
+        log::debug!("Repo DB '{repo_db_path}' doesn't exist yet");
+        return Ok(vec![]);
+    }
 
-    let repo_dir =
-        Path::new(repo_filepath).parent().expect("Failed to get parent dir from repo filepath");
-    let repo_url = format!("file://{}", repo_dir.to_str().unwrap());
+    let mut db_pkgs = vec![];
+    for db_entry in repo_db::read_db_entries(repo_db_path)
+        .with_context(|| format!("Failed to read repo DB '{repo_db_path}'"))?
+    {
+        let db_pkg = DbPackage::from_desc(&db_entry.desc).with_context(|| {
+            format!("Invalid entry '{}' in '{repo_db_path}'", db_entry.dirname())
+        })?;
+        db_pkgs.push(db_pkg);
+    }
 
-    let repo_db_prefix = pkg_utils::get_repo_db_prefix(repo_filepath);
+    Ok(db_pkgs)
+}
 
-    let repo_list = vec![RepoData { repo_name: repo_db_prefix, repo_server: repo_url }];
-    init_alpm(&temp_dir, &repo_list)
+// Gets packages of the repo DB whose package files don't exist anymore in the repo directory,
+// as checked by the provided function
+pub fn get_stale_packages(
+    db_pkgs: &[DbPackage],
+    repo_dir: &Path,
+    is_present: impl Fn(&str) -> bool,
+) -> Vec<DbPackage> {
+    // just check if those package exist, if not insert into state pkgs
+    db_pkgs
+        .iter()
+        .filter(|db_pkg| !is_present(repo_dir.join(&db_pkg.filename).to_str().unwrap()))
+        .cloned()
+        .collect()
 }
 
-// Gets names of stale packages from the repo DB
-pub fn get_stale_packages(repo_db_path: &str) -> Result<Vec<String>> {
-    // we iterate through DB with alpm crate, and check for each package
-    // if the package file still exist in the repo directory
-    let alpm_handle =
-        init_profile_repo(repo_db_path).context("Failed to init alpm for stale packages")?;
+// Gets package files which are not yet present in the repo DB
+pub fn get_brand_new_packages(
+    db_pkgs: &[DbPackage],
+    pkginfos: &[(String, PackageInfo)],
+) -> Vec<String> {
+    // if package was not found, then we assume its the new package
+    pkginfos
+        .iter()
+        .filter(|(_, pkginfo)| !db_pkgs.iter().any(|db_pkg| db_pkg.name == pkginfo.pkgname))
+        .map(|(pkg_filepath, _)| pkg_filepath.clone())
+        .collect()
+}
 
-    let repo_dir = Path::new(&repo_db_path).parent().unwrap();
+// Finds pkgbases of the incoming packages which would leave their split packages at different
+// versions in the repo DB, e.g when only some of them are uploaded. The packages of the pkgbase
+// in the DB are complete if they are at the incoming version already, or there are package files
+// of that version among the available ones. Returns the pkgbases with the reason
+pub fn get_partial_pkgbases(
+    db_pkgs: &[DbPackage],
+    incoming_pkgs: &[(String, PackageInfo)],
+    available_pkgs: &[(String, PackageInfo)],
+) -> Vec<(String, String)> {
+    let mut pkgbase_map: BTreeMap<&str, Vec<&PackageInfo>> = BTreeMap::new();
+    for (_, pkginfo) in incoming_pkgs {
+        pkgbase_map.entry(&pkginfo.pkgbase).or_default().push(pkginfo);
+    }
 
-    // iterate through every package in the database using map iter
-    let stale_pkgs: Vec<String> = alpm_handle
-        .syncdbs()
-        .iter()
-        .map(alpm::Db::pkgs)
-        .flatten()
-        .filter(|x| {
-            // just check if those package exist, if not insert into state pkgs which contains
-            // package names
-            let pkg_filename = x.filename().expect("Invalid package doesn't have filename");
-            let pkg_filepath = format!("{}/{pkg_filename}", repo_dir.to_str().unwrap());
-            !Path::new(&pkg_filepath).exists()
-        })
-        .map(|x| x.name().to_string())
-        .collect();
+    let mut partial_pkgbases = vec![];
+    for (pkgbase, pkginfos) in pkgbase_map {
+        let mut versions = pkginfos.iter().map(|x| x.pkgver.as_str()).collect::<Vec<_>>();
+        versions.sort();
+        versions.dedup();
+        if versions.len() > 1 {
+            partial_pkgbases.push((
+                pkgbase.to_owned(),
+                format!("packages of pkgbase '{pkgbase}' have different versions {versions:?}"),
+            ));
+            continue;
+        }
 
-    // cleanup temp dir after we are done
-    cleanup_alpm_tempdir(&alpm_handle)?;
+        let version = versions[0];
+        let missing_pkgnames = db_pkgs
+            .iter()
+            .filter(|db_pkg| db_pkg.base == pkgbase && db_pkg.version != version)
+            .filter(|db_pkg| {
+                !available_pkgs.iter().any(|(_, x)| x.pkgname == db_pkg.name && x.pkgver == version)
+            })
+            .map(|db_pkg| db_pkg.name.as_str())
+            .collect::<Vec<_>>();
+        if !missing_pkgnames.is_empty() {
+            partial_pkgbases.push((
+                pkgbase.to_owned(),
+                format!(
+                    "packages {missing_pkgnames:?} of pkgbase '{pkgbase}' are missing at version \
+                     '{version}'"
+                ),
+            ));
+        }
+    }
+    partial_pkgbases
+}
 
-    Ok(stale_pkgs)
+// Gets pkgbases whose packages are at different versions in the repo DB
+pub fn get_pkgbase_version_mismatches(db_pkgs: &[DbPackage]) -> Vec<(String, Vec<DbPackage>)> {
+    let mut pkgbase_map: BTreeMap<&str, Vec<DbPackage>> = BTreeMap::new();
+    for db_pkg in db_pkgs {
+        pkgbase_map.entry(&db_pkg.base).or_default().push(db_pkg.clone());
+    }
+    pkgbase_map
+        .into_iter()
+        .filter(|(_, pkgbase_pkgs)| {
+            pkgbase_pkgs.iter().any(|x| x.version != pkgbase_pkgs[0].version)
+        })
+        .map(|(pkgbase, pkgbase_pkgs)| (pkgbase.to_owned(), pkgbase_pkgs))
+        .collect()
 }
 
-// Gets filenames of stale packages from the repo DB
-pub fn get_stale_filenames(repo_db_path: &str) -> Result<Vec<String>> {
-    // we iterate through DB with alpm crate, and check for each package
-    // if the package file still exist in the repo directory
-    let alpm_handle =
-        init_profile_repo(repo_db_path).context("Failed to init alpm for stale packages")?;
+/// Splits the dependency into the name and the version constraint, e.g `glibc>=2.40`
+pub fn parse_dependency(dependency: &str) -> (&str, Option<(&str, &str)>) {
+    for operator in [">=", "<=", "=", ">", "<"] {
+        if let Some((name, version)) = dependency.split_once(operator) {
+            return (name, Some((operator, version)));
+        }
+    }
+    (dependency, None)
+}
 
-    let repo_dir = Path::new(&repo_db_path).parent().unwrap();
+// Checks the version against the constraint of the dependency
+fn satisfies_constraint(version: &str, (operator, required_version): (&str, &str)) -> bool {
+    let ordering = alpm::Version::new(version).vercmp(alpm::Version::new(required_version));
+    match operator {
+        ">=" => ordering != Ordering::Less,
+        "<=" => ordering != Ordering::Greater,
+        ">" => ordering == Ordering::Greater,
+        "<" => ordering == Ordering::Less,
+        _ => ordering == Ordering::Equal,
+    }
+}
 
-    // iterate through every package in the database using map iter
-    let stale_pkgs: Vec<String> = alpm_handle
-        .syncdbs()
-        .iter()
-        .map(alpm::Db::pkgs)
-        .flatten()
-        .filter(|x| {
-            // just check if those package exist, if not insert into state pkgs which contains
-            // package names
-            let pkg_filename = x.filename().expect("Invalid package doesn't have filename");
-            let pkg_filepath = format!("{}/{pkg_filename}", repo_dir.to_str().unwrap());
-            !Path::new(&pkg_filepath).exists()
-        })
-        .map(|x| x.filename().unwrap().to_string())
-        .collect();
+// Gets the runtime dependencies of the repo packages which none of the packages satisfies,
+// by name or by provides. Unversioned provides satisfy only the dependencies without constraint,
+// the same as pacman does
+pub fn get_broken_dependencies<'a>(
+    repo_pkgs: &'a [DbPackage],
+    all_pkgs: &[&DbPackage],
+) -> Vec<(&'a DbPackage, Vec<String>)> {
+    // name -> versions of the packages and provides, if they are versioned
+    let mut provided: HashMap<&str, Vec<Option<&str>>> = HashMap::new();
+    for db_pkg in all_pkgs {
+        provided.entry(&db_pkg.name).or_default().push(Some(&db_pkg.version));
+        for provide in &db_pkg.provides {
+            let (name, version) = match provide.split_once('=') {
+                Some((name, version)) => (name, Some(version)),
+                None => (provide.as_str(), None),
+            };
+            provided.entry(name).or_default().push(version);
+        }
+    }
 
-    // cleanup temp dir after we are done
-    cleanup_alpm_tempdir(&alpm_handle)?;
+    let mut broken_pkgs = vec![];
+    for db_pkg in repo_pkgs {
+        let missing_deps = db_pkg
+            .depends
+            .iter()
+            .filter(|dependency| {
+                let (name, constraint) = parse_dependency(dependency);
+                let versions = provided.get(name).map(Vec::as_slice).unwrap_or_default();
+                !versions.iter().any(|version| match (version, constraint) {
+                    (_, None) => true,
+                    (Some(version), Some(constraint)) => satisfies_constraint(version, constraint),
+                    (None, Some(_)) => false,
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if !missing_deps.is_empty() {
+            broken_pkgs.push((db_pkg, missing_deps));
+        }
+    }
+    broken_pkgs
+}
 
-    Ok(stale_pkgs)
+/// Soname which the incoming package drops while other packages still depend on it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SonameBreakage {
+    /// Package file which drops the soname
+    pub pkg_filepath: String,
+    pub pkgname: String,
+    /// Provided soname, e.g `libfoo.so=3-64`
+    pub soname: String,
+    /// `pkgname-pkgver` of the packages which depend on the soname
+    pub dependents: Vec<String>,
 }
 
-// gets packages which are not yet present in the DB
-pub fn get_brand_new_packages(repo_db_path: &str) -> Result<Vec<String>> {
-    let repo_dir = Path::new(&repo_db_path).parent().unwrap();
+// Gets the provided sonames, e.g `libfoo.so=3-64`
+fn get_provided_sonames(provides: &[String]) -> Vec<&str> {
+    provides
+        .iter()
+        .filter(|provide| provide.split('=').next().unwrap().ends_with(".so"))
+        .map(String::as_str)
+        .collect()
+}
 
-    // get all local packages
-    let pkgs_list = glob::glob(&format!("{}/*.pkg.tar.zst", repo_dir.to_str().unwrap()))?
-        .map(|x| x.unwrap().to_str().unwrap().to_owned())
+// Finds sonames which the incoming packages don't provide anymore, compared to their versions in
+// the repo DB, while the other packages (the incoming ones included) still depend on them and
+// nothing else satisfies the dependency after the update. The packages of the repo are resolved
+// against the repo only, the ones of each sibling repo against its own packages and the repo
+pub fn get_soname_breakages(
+    db_pkgs: &[DbPackage],
+    incoming_pkgs: &[(String, PackageInfo)],
+    sibling_dbs: &[Vec<DbPackage>],
+) -> Vec<SonameBreakage> {
+    // packages of the repo as they would be after the update
+    let mut repo_pkgs = db_pkgs
+        .iter()
+        .filter(|db_pkg| !incoming_pkgs.iter().any(|(_, x)| x.pkgname == db_pkg.name))
+        .cloned()
         .collect::<Vec<_>>();
+    repo_pkgs.extend(
+        incoming_pkgs.iter().map(|(pkg_filepath, x)| DbPackage::from_pkginfo(x, pkg_filepath)),
+    );
+    let new_sonames = incoming_pkgs
+        .iter()
+        .flat_map(|(_, x)| get_provided_sonames(&x.provides))
+        .collect::<Vec<_>>();
+    // only the dependencies which nothing satisfies after the update are broken
+    let mut broken_pkgs =
+        get_broken_dependencies(&repo_pkgs, &repo_pkgs.iter().collect::<Vec<_>>());
+    for sibling_pkgs in sibling_dbs {
+        let all_pkgs = sibling_pkgs.iter().chain(&repo_pkgs).collect::<Vec<_>>();
+        broken_pkgs.extend(get_broken_dependencies(sibling_pkgs, &all_pkgs));
+    }
 
-    // we iterate through DB with alpm crate, and check for each package in the list
-    // if it doesn't, then we found "brand new" package (which doesn't exist yet in DB)
-    let alpm_handle =
-        init_profile_repo(repo_db_path).context("Failed to init alpm for stale packages")?;
-
-    // iterate through all files and check if they exist in the repo
-    let mut new_pkgs: Vec<String> = vec![];
-    for pkg_filepath in pkgs_list {
-        let pkg_filename = Path::new(&pkg_filepath).file_name().unwrap().to_str().unwrap();
-        let pkg_name = crate::pkg_utils::get_pkgname_from_filename(pkg_filename);
-
-        // iterate through each database
-        for db in alpm_handle.syncdbs() {
-            // if package was not found, then we assume its the new package
-            if db.pkg(pkg_name).is_err() {
-                new_pkgs.push(pkg_filepath.clone());
+    let mut soname_breakages = vec![];
+    for (pkg_filepath, pkginfo) in incoming_pkgs {
+        let Some(db_pkg) = db_pkgs.iter().find(|x| x.name == pkginfo.pkgname) else {
+            continue;
+        };
+        for soname in get_provided_sonames(&db_pkg.provides) {
+            if new_sonames.contains(&soname) {
+                continue;
+            }
+            let (soname_name, soname_version) = soname.split_once('=').unwrap_or((soname, ""));
+            let dependents = broken_pkgs
+                .iter()
+                .filter(|(_, missing_deps)| {
+                    // the dependency which the dropped soname used to satisfy
+                    missing_deps.iter().any(|dependency| match parse_dependency(dependency) {
+                        (name, _) if name != soname_name => false,
+                        (_, None) => true,
+                        (_, Some(constraint)) => satisfies_constraint(soname_version, constraint),
+                    })
+                })
+                .map(|(dependent_pkg, _)| dependent_pkg.db_pair())
+                .collect::<Vec<_>>();
+            if !dependents.is_empty() {
+                soname_breakages.push(SonameBreakage {
+                    pkg_filepath: pkg_filepath.clone(),
+                    pkgname: pkginfo.pkgname.clone(),
+                    soname: soname.to_owned(),
+                    dependents,
+                });
             }
         }
     }
+    soname_breakages
+}
+
+// Reads the file lists of the packages from the files DB of the repo, by package file name.
+// Missing files DB is treated as an empty one
+pub fn read_repo_files_db(repo_db_path: &str) -> Result<HashMap<String, Vec<String>>> {
+    let files_db_path = repo_db::get_files_db_path(repo_db_path)?;
+    if !Path::new(&files_db_path).exists() {
+        log::debug!("Files DB '{files_db_path}' doesn't exist yet");
+        return Ok(HashMap::new());
+    }
 
-    // cleanup temp dir after we are done
-    cleanup_alpm_tempdir(&alpm_handle)?;
+    let mut filelists = HashMap::new();
+    for db_entry in repo_db::read_db_entries(&files_db_path)
+        .with_context(|| format!("Failed to read files DB '{files_db_path}'"))?
+    {
+        let (Some(pkg_filename), Some(files)) = (db_entry.desc_value("FILENAME"), &db_entry.files)
+        else {
+            continue;
+        };
+        let filelist = files
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('%'))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        filelists.insert(pkg_filename.to_owned(), filelist);
+    }
+    Ok(filelists)
+}
 
-    Ok(new_pkgs)
+// Checks whether the package declares conflicts/replaces on the other one, by its name or by
+// something it provides. Versions of the declarations are not taken into account
+fn declares_conflict(db_pkg: &DbPackage, other_pkg: &DbPackage) -> bool {
+    db_pkg.conflicts.iter().chain(&db_pkg.replaces).any(|declaration| {
+        let (name, _) = parse_dependency(declaration);
+        name == other_pkg.name || other_pkg.provides.iter().any(|x| parse_dependency(x).0 == name)
+    })
+}
+
+// Finds pairs of the packages which own the same paths, while neither of them declares
+// conflicts/replaces on the other. Directories are shared by the packages and don't count
+pub fn get_file_conflicts(
+    pkgs: &[(DbPackage, Vec<String>)],
+) -> Vec<(&DbPackage, &DbPackage, Vec<String>)> {
+    let mut path_owners: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (pkg_idx, (_, filelist)) in pkgs.iter().enumerate() {
+        for path in filelist.iter().filter(|x| !x.ends_with('/')) {
+            path_owners.entry(path).or_default().push(pkg_idx);
+        }
+    }
+
+    let mut conflicting_paths: BTreeMap<(usize, usize), Vec<String>> = BTreeMap::new();
+    for (path, owners) in path_owners {
+        for (owner_pos, first_idx) in owners.iter().enumerate() {
+            for second_idx in &owners[owner_pos + 1..] {
+                conflicting_paths.entry((*first_idx, *second_idx)).or_default().push(path.into());
+            }
+        }
+    }
+
+    conflicting_paths
+        .into_iter()
+        .map(|((first_idx, second_idx), paths)| (&pkgs[first_idx].0, &pkgs[second_idx].0, paths))
+        .filter(|(first_pkg, second_pkg, _)| {
+            !declares_conflict(first_pkg, second_pkg) && !declares_conflict(second_pkg, first_pkg)
+        })
+        .collect()
 }
 
 // Checks the reference repository for newer package versions and returns a list of package
-// filepaths to copy.
+// filepaths to copy, together with their reference DB entries.
 pub fn get_newer_packages_from_reference(
-    repo_db_path: &str,
+    db_pkgs: &[DbPackage],
     reference_repo_path: &str,
-) -> Result<Vec<String>> {
-    let alpm_handle =
-        init_profile_repo(repo_db_path).context("Failed to init alpm for profile repo")?;
-    let reference_alpm_handle =
-        init_profile_repo(reference_repo_path).context("Failed to init alpm for reference repo")?;
+) -> Result<Vec<(String, DbPackage)>> {
+    let reference_db_pkgs =
+        read_repo_db(reference_repo_path).context("Failed to read reference repo")?;
+    let reference_repo_dir = Path::new(reference_repo_path).parent().unwrap();
 
     let mut packages_to_copy = Vec::new();
 
     // Iterate over packages in the profile repository
-    for db in alpm_handle.syncdbs() {
-        for pkg in db.pkgs() {
-            // Check if the package exists in the reference repository
-            if let Some(reference_pkg) = reference_alpm_handle
-                .syncdbs()
-                .iter()
-                .find_map(|ref_db| ref_db.pkg(pkg.name()).ok())
-            {
-                // Compare versions
-                if reference_pkg.version() > pkg.version() {
-                    // Newer version found in reference repository
-                    let pkg_filename =
-                        reference_pkg.filename().expect("Invalid package doesn't have filename");
-                    let reference_repo_dir = Path::new(reference_repo_path).parent().unwrap();
-                    let pkgfile_path =
-                        format!("{}/{pkg_filename}", reference_repo_dir.to_str().unwrap());
-
-                    // skip if the package file doesn't exist in the reference repo
-                    if !Path::new(&pkgfile_path).exists() {
-                        log::error!("Package file doesn't in ref repo: {pkgfile_path}");
-                        continue;
-                    }
-                    packages_to_copy.push(pkgfile_path);
-                }
+    for db_pkg in db_pkgs {
+        // Check if the package exists in the reference repository
+        let Some(reference_pkg) =
+            reference_db_pkgs.iter().find(|reference_pkg| reference_pkg.name == db_pkg.name)
+        else {
+            continue;
+        };
+
+        // Compare versions
+        if reference_pkg.version().vercmp(db_pkg.version()).is_gt() {
+            // Newer version found in reference repository
+            let pkgfile_path = reference_repo_dir.join(&reference_pkg.filename);
+
+            // skip if the package file doesn't exist in the reference repo
+            if !pkgfile_path.exists() {
+                log::error!("Package file doesn't in ref repo: {}", pkgfile_path.display());
+                continue;
             }
+            packages_to_copy
+                .push((pkgfile_path.to_str().unwrap().to_owned(), reference_pkg.clone()));
         }
     }
 
-    // Cleanup temp dirs after we are done
-    cleanup_alpm_tempdir(&alpm_handle)?;
-    cleanup_alpm_tempdir(&reference_alpm_handle)?;
-
     Ok(packages_to_copy)
 }
 
-fn cleanup_alpm_tempdir(alpm_handle: &Alpm) -> Result<()> {
-    let tmp_dir = env::temp_dir();
+#[cfg(test)]
+pub mod test_utils {
+    use crate::alpm_helper::DbPackage;
+
+    /// Creates the repo DB package with the dependencies and provides
+    pub fn db_pkg(name: &str, version: &str, depends: &[&str], provides: &[&str]) -> DbPackage {
+        DbPackage {
+            name: name.into(),
+            version: version.into(),
+            depends: depends.iter().map(|x| x.to_string()).collect(),
+            provides: provides.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alpm_helper::test_utils::db_pkg;
+    use crate::alpm_helper::*;
+
+    #[test]
+    fn test_db_package_from_desc() {
+        let desc = concat!(
+            "%FILENAME%\nlinux-cachyos-headers-6.10.5-1-x86_64_v3.pkg.tar.zst\n\n",
+            "%NAME%\nlinux-cachyos-headers\n\n",
+            "%BASE%\nlinux-cachyos\n\n",
+            "%VERSION%\n6.10.5-1\n\n",
+            "%CSIZE%\n36015523\n\n",
+            "%ISIZE%\n171839488\n\n",
+            "%ARCH%\nx86_64_v3\n\n",
+            "%BUILDDATE%\n1723812345\n\n",
+            "%PROVIDES%\nlinux-headers=6.10.5\n\n",
+            "%DEPENDS%\npahole\nglibc\n\n",
+        );
+        let db_pkg = DbPackage::from_desc(desc).unwrap();
+        assert_eq!(db_pkg, DbPackage {
+            name: "linux-cachyos-headers".into(),
+            base: "linux-cachyos".into(),
+            version: "6.10.5-1".into(),
+            filename: "linux-cachyos-headers-6.10.5-1-x86_64_v3.pkg.tar.zst".into(),
+            arch: "x86_64_v3".into(),
+            builddate: 1723812345,
+            csize: 36015523,
+            isize: 171839488,
+            provides: vec!["linux-headers=6.10.5".into()],
+            depends: vec!["pahole".into(), "glibc".into()],
+            ..Default::default()
+        });
+        assert_eq!(db_pkg.db_pair(), "linux-cachyos-headers-6.10.5-1");
+
+        assert!(DbPackage::from_desc("%NAME%\ndash\n\n%VERSION%\n0.5.12-1\n\n").is_err());
+    }
+
+    #[test]
+    fn test_stale_and_brand_new() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let repo_dir = Path::new(&temp_dir);
+        std::fs::write(repo_dir.join("dash-0.5.12-1.1-x86_64.pkg.tar.zst"), "").unwrap();
+
+        let db_pkgs = vec![
+            DbPackage {
+                name: "dash".into(),
+                version: "0.5.12-1.1".into(),
+                filename: "dash-0.5.12-1.1-x86_64.pkg.tar.zst".into(),
+                ..Default::default()
+            },
+            DbPackage {
+                name: "st".into(),
+                version: "0.8.4-2".into(),
+                filename: "st-0.8.4-2-x86_64.pkg.tar.zst".into(),
+                ..Default::default()
+            },
+        ];
+
+        let stale_pkgs = get_stale_packages(&db_pkgs, repo_dir, |path| Path::new(path).exists());
+        assert_eq!(stale_pkgs, vec![db_pkgs[1].clone()]);
+
+        let pkg_list: Vec<String> = vec![
+            format!("{temp_dir}/dash-0.5.12-1.1-x86_64.pkg.tar.zst"),
+            format!("{temp_dir}/dwm-6.2-4-x86_64.pkg.tar.zst"),
+        ];
+        let pkginfos = crate::pkginfo::load_pkginfos(&pkg_list);
+        assert_eq!(get_brand_new_packages(&db_pkgs, &pkginfos), vec![format!(
+            "{temp_dir}/dwm-6.2-4-x86_64.pkg.tar.zst"
+        )]);
+
+        std::fs::remove_dir_all(temp_dir).unwrap();
+    }
 
-    let alpm_root_dir = alpm_handle.root();
-    if !alpm_root_dir.starts_with(tmp_dir.to_str().unwrap()) {
-        log::error!("alpm handle root at '{}' wasn't removed", alpm_root_dir);
-        return Ok(());
+    #[test]
+    fn test_pkgbase_grouping() {
+        let kernel_pkg = |name: &str, version: &str| DbPackage {
+            base: "linux-cachyos".into(),
+            ..db_pkg(name, version, &[], &[])
+        };
+        let pkginfo = |name: &str, version: &str| {
+            (format!("/repo/{name}-{version}-x86_64.pkg.tar.zst"), PackageInfo {
+                pkgname: name.into(),
+                pkgbase: "linux-cachyos".into(),
+                pkgver: version.into(),
+                ..Default::default()
+            })
+        };
+        let db_pkgs = vec![
+            kernel_pkg("linux-cachyos", "6.10.5-1"),
+            kernel_pkg("linux-cachyos-headers", "6.10.5-1"),
+        ];
+        assert!(get_pkgbase_version_mismatches(&db_pkgs).is_empty());
+
+        // only the kernel is uploaded
+        let incoming_pkgs = vec![pkginfo("linux-cachyos", "6.10.6-1")];
+        let partial_pkgbases = get_partial_pkgbases(&db_pkgs, &incoming_pkgs, &incoming_pkgs);
+        assert_eq!(partial_pkgbases.len(), 1);
+        assert!(partial_pkgbases[0].1.contains("\"linux-cachyos-headers\""));
+
+        // the headers are uploaded already
+        let available_pkgs = vec![
+            pkginfo("linux-cachyos", "6.10.6-1"),
+            pkginfo("linux-cachyos-headers", "6.10.6-1"),
+        ];
+        assert!(get_partial_pkgbases(&db_pkgs, &incoming_pkgs, &available_pkgs).is_empty());
+
+        let incoming_pkgs = vec![
+            pkginfo("linux-cachyos", "6.10.6-1"),
+            pkginfo("linux-cachyos-headers", "6.10.6-2"),
+        ];
+        let partial_pkgbases = get_partial_pkgbases(&db_pkgs, &incoming_pkgs, &incoming_pkgs);
+        assert!(partial_pkgbases[0].1.contains("different versions"));
+
+        let db_pkgs = vec![
+            kernel_pkg("linux-cachyos", "6.10.6-1"),
+            kernel_pkg("linux-cachyos-headers", "6.10.5-1"),
+            DbPackage { base: "dash".into(), ..db_pkg("dash", "0.5.12-1", &[], &[]) },
+        ];
+        let mismatches = get_pkgbase_version_mismatches(&db_pkgs);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].0, "linux-cachyos");
+        assert_eq!(mismatches[0].1.len(), 2);
     }
 
-    // remove our temp repo dir
-    fs::remove_dir_all(alpm_root_dir)?;
+    #[test]
+    fn test_broken_dependencies() {
+        assert_eq!(parse_dependency("glibc>=2.40"), ("glibc", Some((">=", "2.40"))));
+        assert_eq!(parse_dependency("libalpm.so=15-64"), ("libalpm.so", Some(("=", "15-64"))));
+        assert_eq!(parse_dependency("sh"), ("sh", None));
+
+        let repo_pkgs = vec![
+            db_pkg("pacman", "7.0.0-1", &["glibc>=2.40", "libalpm.so=15-64", "sh"], &[]),
+            db_pkg("paru", "2.0.4-1", &["pacman>=7.0", "git"], &[]),
+            db_pkg("yay", "12.4.2-1", &["pacman<7"], &[]),
+            db_pkg("libalpm", "15.0.0-1", &[], &["libalpm.so=15-64"]),
+            db_pkg("dash", "0.5.12-1", &[], &["sh"]),
+        ];
+        let upstream_pkgs = vec![db_pkg("glibc", "2.40+r16-1", &[], &[])];
+        let all_pkgs = repo_pkgs.iter().chain(&upstream_pkgs).collect::<Vec<_>>();
+
+        let broken_pkgs = get_broken_dependencies(&repo_pkgs, &all_pkgs)
+            .into_iter()
+            .map(|(db_pkg, missing_deps)| (db_pkg.name.as_str(), missing_deps))
+            .collect::<Vec<_>>();
+        assert_eq!(broken_pkgs, vec![
+            ("paru", vec!["git".to_owned()]),
+            ("yay", vec!["pacman<7".to_owned()]),
+        ]);
+
+        // unversioned provide doesn't satisfy the constraint
+        let repo_pkgs = vec![db_pkg("bash-completion", "2.14-1", &["sh>=1"], &[])];
+        let dash_pkg = db_pkg("dash", "0.5.12-1", &[], &["sh"]);
+        assert_eq!(get_broken_dependencies(&repo_pkgs, &[&repo_pkgs[0], &dash_pkg]).len(), 1);
+    }
 
-    Ok(())
+    #[test]
+    fn test_soname_breakages() {
+        let db_pkgs = vec![
+            db_pkg("libalpm", "1.0-1", &[], &["libalpm.so=15-64", "alpm"]),
+            db_pkg("pacman", "1.0-1", &["libalpm.so=15-64"], &[]),
+            db_pkg("yay", "1.0-1", &["libalpm.so>=14"], &[]),
+        ];
+        let sibling_dbs = vec![vec![db_pkg("paru", "1.0-1", &["libalpm.so"], &[])]];
+        let incoming_pkg = |name: &str, depends: &[&str], provides: &[&str]| {
+            let db_pkg = db_pkg(name, "2.0-1", depends, provides);
+            (format!("/repo/{name}-2.0-1-x86_64.pkg.tar.zst"), PackageInfo {
+                pkgname: db_pkg.name,
+                pkgver: "2.0-1".into(),
+                depends: db_pkg.depends,
+                provides: db_pkg.provides,
+                ..Default::default()
+            })
+        };
+
+        // yay and paru are still satisfied by the new soname
+        let incoming_pkgs = vec![incoming_pkg("libalpm", &[], &["libalpm.so=16-64"])];
+        let soname_breakages = get_soname_breakages(&db_pkgs, &incoming_pkgs, &sibling_dbs);
+        assert_eq!(soname_breakages, vec![SonameBreakage {
+            pkg_filepath: "/repo/libalpm-2.0-1-x86_64.pkg.tar.zst".into(),
+            pkgname: "libalpm".into(),
+            soname: "libalpm.so=15-64".into(),
+            dependents: vec!["pacman-1.0-1".into()],
+        }]);
+
+        // the dependents are rebuilt together with the library
+        let incoming_pkgs = vec![
+            incoming_pkg("libalpm", &[], &["libalpm.so=16-64"]),
+            incoming_pkg("pacman", &["libalpm.so=16-64"], &[]),
+            incoming_pkg("yay", &["libalpm.so>=16"], &[]),
+        ];
+        let soname_breakages = get_soname_breakages(&db_pkgs, &incoming_pkgs, &[]);
+        assert!(soname_breakages.is_empty());
+
+        // the sibling repo still ships its own copy of the library with the old soname
+        let sibling_dbs = vec![vec![
+            db_pkg("libalpm", "1.0-1", &[], &["libalpm.so=15-64"]),
+            db_pkg("paru", "1.0-1", &["libalpm.so=15-64"], &[]),
+        ]];
+        let incoming_pkgs = vec![incoming_pkg("libalpm", &[], &["libalpm.so=16-64"])];
+        let soname_breakages = get_soname_breakages(&db_pkgs, &incoming_pkgs, &sibling_dbs);
+        assert_eq!(soname_breakages.len(), 1);
+        assert_eq!(soname_breakages[0].dependents, vec!["pacman-1.0-1".to_owned()]);
+
+        // the old soname of the sibling repo doesn't satisfy the dependents of the repo,
+        // while the sibling dependent relying on the repo copy is broken
+        let sibling_dbs =
+            vec![vec![db_pkg("libalpm-git", "1.0-1", &[], &["libalpm.so=15-64"])], vec![db_pkg(
+                "paru",
+                "1.0-1",
+                &["libalpm.so=15-64"],
+                &[],
+            )]];
+        let soname_breakages = get_soname_breakages(&db_pkgs, &incoming_pkgs, &sibling_dbs);
+        assert_eq!(soname_breakages.len(), 1);
+        assert_eq!(soname_breakages[0].dependents, vec![
+            "pacman-1.0-1".to_owned(),
+            "paru-1.0-1".to_owned()
+        ]);
+    }
+
+    #[test]
+    fn test_file_conflicts() {
+        let pkg = |name: &str, conflicts: &[&str], provides: &[&str], files: &[&str]| {
+            let db_pkg = DbPackage {
+                conflicts: conflicts.iter().map(|x| x.to_string()).collect(),
+                ..db_pkg(name, "1.0-1", &[], provides)
+            };
+            (db_pkg, files.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+        };
+        let pkgs = vec![
+            pkg("vim", &[], &[], &["usr/", "usr/bin/", "usr/bin/vim", "usr/bin/xxd"]),
+            pkg("xxd", &[], &[], &["usr/", "usr/bin/", "usr/bin/xxd"]),
+            pkg("gvim", &["vim-minimal"], &[], &["usr/bin/", "usr/bin/vim", "usr/bin/xxd"]),
+            pkg("vim-minimal", &[], &[], &["usr/bin/vim"]),
+            pkg("neovim", &[], &["vi"], &["usr/bin/vi"]),
+            pkg("vi", &["vi"], &[], &["usr/bin/vi"]),
+        ];
+
+        let file_conflicts = get_file_conflicts(&pkgs)
+            .into_iter()
+            .map(|(x, y, paths)| (x.name.as_str(), y.name.as_str(), paths))
+            .collect::<Vec<_>>();
+        assert_eq!(file_conflicts, vec![
+            ("vim", "xxd", vec!["usr/bin/xxd".to_owned()]),
+            ("vim", "gvim", vec!["usr/bin/vim".to_owned(), "usr/bin/xxd".to_owned()]),
+            ("vim", "vim-minimal", vec!["usr/bin/vim".to_owned()]),
+            ("xxd", "gvim", vec!["usr/bin/xxd".to_owned()]),
+        ]);
+    }
+
+    #[test]
+    fn test_missing_repo_db() {
+        assert_eq!(read_repo_db("/nonexistent/repof.db.tar.zst").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_read_repo_files_db() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let repo_db_path = format!("{temp_dir}/repof.db.tar.zst");
+        assert!(read_repo_files_db(&repo_db_path).unwrap().is_empty());
+
+        let pkg_filepath = format!("{temp_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        crate::utils::create_test_pkg(&pkg_filepath, &[
+            (".PKGINFO", "pkgname = dash\npkgver = 0.5.12-1\narch = x86_64\n"),
+            ("usr/bin/dash", ""),
+        ]);
+        let opts = repo_db::WriterOptions::default();
+        let mut repo_db = repo_db::RepoDb::open(&repo_db_path).unwrap();
+        repo_db.add_package(&pkg_filepath, &opts).unwrap();
+        repo_db.write(&opts).unwrap();
+
+        let filelists = read_repo_files_db(&repo_db_path).unwrap();
+        assert_eq!(
+            filelists.get("dash-0.5.12-1-x86_64.pkg.tar.zst"),
+            Some(&vec!["usr/bin/dash".to_owned()])
+        );
+
+        std::fs::remove_dir_all(temp_dir).unwrap();
+    }
 }
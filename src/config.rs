@@ -9,17 +9,82 @@ use serde::Deserialize;
 #[serde(default)]
 pub struct Config {
     pub profiles: HashMap<String, Profile>,
+    /// Named lists of the profiles, which are handled together
+    pub groups: HashMap<String, Vec<String>>,
 }
 
-#[derive(Debug, PartialEq, Default, Deserialize)]
+/// Tool which is used to modify the repo DB
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// Calls repo-add/repo-remove
+    #[default]
+    External,
+    /// Rewrites the repo DB in-process
+    Native,
+}
+
+/// What to do with the incoming package which drops a soname other packages still depend on
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SonameCheck {
+    /// Logs the breakage and adds the package anyway
+    #[default]
+    Warn,
+    /// Rejects the package together with its split siblings
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
 pub struct Profile {
     pub repo: String,
+    /// Architecture of the packages in the repo, e.g `x86_64_v3`
+    pub arch: Option<String>,
     #[serde(default = "default_add_params")]
     pub add_params: Vec<String>,
     #[serde(default = "default_rm_params")]
     pub rm_params: Vec<String>,
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// Glob patterns of package file names in addition to the extensions makepkg produces
+    #[serde(default)]
+    pub extra_pkg_patterns: Vec<String>,
     #[serde(default = "default_require_signature")]
     pub require_signature: bool,
+    /// Keyring file, or directory of key files, with the trusted packager keys which the
+    /// package signatures are verified against
+    pub keyring: Option<String>,
+    /// Accepts the existing signature files without verifying them when no `keyring` is set
+    #[serde(default)]
+    pub allow_unverified_signatures: bool,
+    /// `packager` values (or glob patterns) of `.PKGINFO` which may publish into the repo,
+    /// anyone if empty
+    #[serde(default)]
+    pub allowed_packagers: Vec<String>,
+    /// Fingerprints of the keys which may sign packages published into the repo, anyone if empty
+    #[serde(default)]
+    pub allowed_signers: Vec<String>,
+    /// Directory where the rejected packages are moved instead of being added
+    pub quarantine_dir: Option<String>,
+    /// Names (or glob patterns) of packages which may be replaced by older versions
+    #[serde(default)]
+    pub allow_downgrade: Vec<String>,
+    /// Repo DBs which the dependencies are resolved against in addition to the repo itself,
+    /// e.g mirrored core/extra
+    #[serde(default)]
+    pub dep_repos: Vec<String>,
+    /// Refuses the update which would leave packages with unsatisfied dependencies
+    #[serde(default)]
+    pub check_deps: bool,
+    #[serde(default)]
+    pub soname_check: SonameCheck,
+    /// Refuses the update which would leave packages owning the same files, unless they declare
+    /// conflicts/replaces on each other
+    #[serde(default)]
+    pub check_file_conflicts: bool,
+    /// Repo DBs of the profiles sharing a group with this one, filled in from the config
+    #[serde(skip)]
+    pub sibling_repos: Vec<String>,
     #[serde(default = "default_backup")]
     pub backup: bool,
     pub backup_dir: Option<String>,
@@ -28,8 +93,111 @@ pub struct Profile {
     pub debug_dir: Option<String>,
     #[serde(default = "default_interactive")]
     pub interactive: bool,
-    // Add the reference_repo field
+    /// Seconds to wait for the lock of the repo directories held by another process
+    #[serde(default = "default_lock_timeout")]
+    pub lock_timeout: u64,
+    /// Repo DB which newer package versions are synced from
     pub reference_repo: Option<String>,
+    /// Names (or glob patterns) of packages which are synced from the reference repo,
+    /// all packages are synced if empty
+    #[serde(default)]
+    pub reference_allow: Vec<String>,
+    /// Names (or glob patterns) of packages which are never synced from the reference repo
+    #[serde(default)]
+    pub reference_deny: Vec<String>,
+}
+
+impl Config {
+    /// Gets the repo DBs of the other profiles which are in the same groups as the profile
+    pub fn get_sibling_repos(&self, profile_name: &str) -> Vec<String> {
+        let mut sibling_repos = vec![];
+        for profile_names in self.groups.values().filter(|x| x.iter().any(|y| y == profile_name)) {
+            for sibling_name in profile_names.iter().filter(|x| *x != profile_name) {
+                let sibling_repo = &self.profiles[sibling_name].repo;
+                if !sibling_repos.contains(sibling_repo) {
+                    sibling_repos.push(sibling_repo.clone());
+                }
+            }
+        }
+        sibling_repos.sort();
+        sibling_repos
+    }
+}
+
+impl Profile {
+    /// Derives the profile of the companion `<repo>-debug` DB located in the `debug_dir`,
+    /// if the separate debug repo is enabled
+    pub fn debug_profile(&self) -> Option<Profile> {
+        let debug_dir = self.debug_dir.as_ref()?;
+        let repo_path = Path::new(&self.repo);
+        if *debug_dir == self.repo || repo_path.parent() == Some(Path::new(debug_dir)) {
+            return None;
+        }
+
+        let repo_filename = repo_path.file_name()?.to_str()?;
+        let (db_prefix, db_suffix) = repo_filename.split_once(".db")?;
+        Some(Profile {
+            repo: format!("{debug_dir}/{db_prefix}-debug.db{db_suffix}"),
+            debug_dir: None,
+            reference_repo: None,
+            reference_allow: vec![],
+            reference_deny: vec![],
+            ..self.clone()
+        })
+    }
+
+    /// Checks the package against the allow/deny lists of the reference repo sync
+    pub fn is_reference_pkg_allowed(&self, pkgname: &str) -> bool {
+        if matches_any_pattern(&self.reference_deny, pkgname) {
+            return false;
+        }
+        self.reference_allow.is_empty() || matches_any_pattern(&self.reference_allow, pkgname)
+    }
+
+    /// Checks whether the package may be replaced by older version
+    pub fn is_downgrade_allowed(&self, pkgname: &str) -> bool {
+        matches_any_pattern(&self.allow_downgrade, pkgname)
+    }
+
+    /// Checks the `packager` of the package against the allowlist
+    pub fn is_packager_allowed(&self, packager: &str) -> bool {
+        self.allowed_packagers.is_empty() || matches_any_pattern(&self.allowed_packagers, packager)
+    }
+
+    /// Checks fingerprint of the signing key against the allowlist, the package without verified
+    /// signature is allowed only if the list is empty
+    /// Refuses to require the signatures which can't be verified for the lack of the keyring,
+    /// unless the unverified signatures are explicitly allowed
+    pub fn check_signature_settings(&self) -> Result<()> {
+        if self.require_signature && self.keyring.is_none() && !self.allow_unverified_signatures {
+            anyhow::bail!(
+                "Profile of {} requires signatures but has no keyring to verify them against, set \
+                 keyring or allow_unverified_signatures",
+                self.repo
+            );
+        }
+        Ok(())
+    }
+
+    pub fn is_signer_allowed(&self, fingerprint: Option<&str>) -> bool {
+        if self.allowed_signers.is_empty() {
+            return true;
+        }
+        let Some(fingerprint) = fingerprint else {
+            return false;
+        };
+        self.allowed_signers.iter().any(|allowed_signer| {
+            allowed_signer.replace(' ', "").eq_ignore_ascii_case(&fingerprint.replace(' ', ""))
+        })
+    }
+}
+
+// Matches the value against the glob patterns, invalid patterns are compared literally
+fn matches_any_pattern(patterns: &[String], value: &str) -> bool {
+    patterns.iter().any(|pattern| match glob::Pattern::new(pattern) {
+        Ok(glob_pattern) => glob_pattern.matches(value),
+        Err(_) => pattern == value,
+    })
 }
 
 pub fn parse_config_file(filepath: &str) -> Result<Config> {
@@ -60,6 +228,12 @@ fn parse_config_content(file_content: &str) -> Result<Config> {
         anyhow::bail!("The config file is empty!")
     }
     let config: Config = toml::from_str(file_content)?;
+    for (group, profile_names) in &config.groups {
+        if let Some(profile_name) = profile_names.iter().find(|x| !config.profiles.contains_key(*x))
+        {
+            anyhow::bail!("Group {group} refers to unknown profile {profile_name}");
+        }
+    }
     Ok(config)
 }
 
@@ -83,6 +257,10 @@ fn default_interactive() -> bool {
     false
 }
 
+fn default_lock_timeout() -> u64 {
+    60
+}
+
 #[cfg(test)]
 mod tests {
     use crate::config::*;
@@ -97,30 +275,68 @@ mod tests {
             profiles: HashMap::from([
                 ("repof".to_string(), Profile {
                     repo: "/home/testuser/repos/x86_64/os/repof/repof.db.tar.zst".to_string(),
+                    arch: Some("x86_64".to_string()),
                     add_params: vec!["--sign".to_string(), "--include-sigs".to_string()],
                     rm_params: vec!["--sign".to_string()],
+                    backend: BackendKind::External,
+                    extra_pkg_patterns: vec![],
                     require_signature: true,
+                    keyring: Some("/etc/repo-manage/keys".to_string()),
+                    allow_unverified_signatures: false,
+                    allowed_packagers: vec![],
+                    allowed_signers: vec![],
+                    quarantine_dir: None,
+                    allow_downgrade: vec![],
+                    dep_repos: vec![],
+                    check_deps: false,
+                    soname_check: SonameCheck::Warn,
+                    check_file_conflicts: false,
+                    sibling_repos: vec![],
                     backup: true,
                     backup_num: None,
                     backup_dir: Some("/home/testuser/backup_repos/repof".to_string()),
                     debug_dir: Some("/home/testuser/debug_repos/repof".to_string()),
                     interactive: false,
+                    lock_timeout: 60,
                     reference_repo: None,
+                    reference_allow: vec![],
+                    reference_deny: vec![],
                 }),
                 ("reposecond".to_string(), Profile {
                     repo: "/home/testuser/repos/x86_64/os/reposecond/reposecond.db.tar.zst"
                         .to_string(),
+                    arch: Some("x86_64".to_string()),
                     add_params: vec!["--sign".to_string(), "--include-sigs".to_string()],
                     rm_params: vec!["--sign".to_string()],
+                    backend: BackendKind::External,
+                    extra_pkg_patterns: vec![],
                     require_signature: true,
+                    keyring: Some("/etc/repo-manage/keys".to_string()),
+                    allow_unverified_signatures: false,
+                    allowed_packagers: vec![],
+                    allowed_signers: vec![],
+                    quarantine_dir: None,
+                    allow_downgrade: vec![],
+                    dep_repos: vec![],
+                    check_deps: false,
+                    soname_check: SonameCheck::Warn,
+                    check_file_conflicts: false,
+                    sibling_repos: vec![],
                     backup: true,
                     backup_num: None,
                     backup_dir: Some("/home/testuser/backup_repos/reposecond".to_string()),
                     debug_dir: Some("/home/testuser/debug_repos/reposecond".to_string()),
                     interactive: false,
+                    lock_timeout: 60,
                     reference_repo: None,
+                    reference_allow: vec![],
+                    reference_deny: vec![],
                 }),
             ]),
+            groups: HashMap::from([("all-repos".to_string(), vec![
+                "repof".to_string(),
+                "reposecond".to_string(),
+            ])]),
         };
 
         assert_eq!(parsed_config.unwrap(), expected_config);
@@ -138,6 +354,141 @@ debug_package_dir = "/home/testuser/debug_repos/repof"
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_backend_kind() {
+        let config_str = r#"
+[profiles.repof]
+repo = "/home/testuser/repos/x86_64/os/repof/repof.db.tar.zst"
+backend = "native"
+"#;
+
+        let config = parse_config_content(config_str).unwrap();
+        assert_eq!(config.profiles["repof"].backend, BackendKind::Native);
+
+        let config_str = r#"
+[profiles.repof]
+repo = "/home/testuser/repos/x86_64/os/repof/repof.db.tar.zst"
+backend = "repoctl"
+"#;
+        assert!(parse_config_content(config_str).is_err());
+    }
+
+    #[test]
+    fn test_debug_profile() {
+        let profile = Profile {
+            repo: "/home/testuser/repos/x86_64/os/repof/repof.db.tar.zst".into(),
+            debug_dir: Some("/home/testuser/repos/x86_64/os/repof-debug".into()),
+            backup: true,
+            ..Default::default()
+        };
+        let debug_profile = profile.debug_profile().unwrap();
+        assert_eq!(
+            debug_profile.repo,
+            "/home/testuser/repos/x86_64/os/repof-debug/repof-debug.db.tar.zst"
+        );
+        assert_eq!(debug_profile.debug_dir, None);
+        assert!(debug_profile.backup);
+        assert_eq!(debug_profile.debug_profile(), None);
+
+        // debug packages stay in the repo itself
+        let profile =
+            Profile { debug_dir: Some("/home/testuser/repos/x86_64/os/repof".into()), ..profile };
+        assert_eq!(profile.debug_profile(), None);
+        let profile = Profile { debug_dir: None, ..profile };
+        assert_eq!(profile.debug_profile(), None);
+    }
+
+    #[test]
+    fn test_reference_allow_deny() {
+        let config_str = r#"
+[profiles.repof]
+repo = "/home/testuser/repos/x86_64/os/repof/repof.db.tar.zst"
+reference_repo = "/home/testuser/repos/x86_64/os/repof-super/repof-super.db.tar.zst"
+reference_allow = ["linux-cachyos*", "dash"]
+reference_deny = ["linux-cachyos-lts*"]
+"#;
+        let config = parse_config_content(config_str).unwrap();
+        let profile = &config.profiles["repof"];
+        assert!(profile.is_reference_pkg_allowed("dash"));
+        assert!(profile.is_reference_pkg_allowed("linux-cachyos-headers"));
+        assert!(!profile.is_reference_pkg_allowed("linux-cachyos-lts"));
+        assert!(!profile.is_reference_pkg_allowed("dwm"));
+
+        // everything is allowed without allow list
+        let profile = Profile { reference_allow: vec![], ..profile.clone() };
+        assert!(profile.is_reference_pkg_allowed("dwm"));
+        assert!(!profile.is_reference_pkg_allowed("linux-cachyos-lts-headers"));
+    }
+
+    #[test]
+    fn test_packager_allowlist() {
+        let config_str = r#"
+[profiles.repof]
+repo = "/home/testuser/repos/x86_64/os/repof/repof.db.tar.zst"
+allowed_packagers = ["CachyOS <admin@cachyos.org>", "* <*@builders.cachyos.org>"]
+allowed_signers = ["6C75 6E41 E595 6D02 0705  98A5 E8F3 FF41 CF5B 4A37"]
+"#;
+        let config = parse_config_content(config_str).unwrap();
+        let profile = &config.profiles["repof"];
+        assert!(profile.is_packager_allowed("CachyOS <admin@cachyos.org>"));
+        assert!(profile.is_packager_allowed("Builder One <one@builders.cachyos.org>"));
+        assert!(!profile.is_packager_allowed("Unknown Packager"));
+        assert!(profile.is_signer_allowed(Some("6c756e41e5956d02070598a5e8f3ff41cf5b4a37")));
+        assert!(!profile.is_signer_allowed(Some("32B2A52725FFA9F07BA3088D0FAC1C0CAA54B85B")));
+        assert!(!profile.is_signer_allowed(None));
+
+        // anyone is allowed without allowlists
+        let profile =
+            Profile { allowed_packagers: vec![], allowed_signers: vec![], ..profile.clone() };
+        assert!(profile.is_packager_allowed("Unknown Packager"));
+        assert!(profile.is_signer_allowed(None));
+    }
+
+    #[test]
+    fn test_signature_settings() {
+        let config_str = r#"
+[profiles.repof]
+repo = "/home/testuser/repos/x86_64/os/repof/repof.db.tar.zst"
+"#;
+        let config = parse_config_content(config_str).unwrap();
+        let profile = &config.profiles["repof"];
+        // the signatures can't be verified without the keyring
+        assert!(profile.check_signature_settings().is_err());
+
+        let profile = Profile { allow_unverified_signatures: true, ..profile.clone() };
+        assert!(profile.check_signature_settings().is_ok());
+        let profile = Profile {
+            keyring: Some("/etc/repo-manage/keys".into()),
+            allow_unverified_signatures: false,
+            ..profile
+        };
+        assert!(profile.check_signature_settings().is_ok());
+        let profile = Profile { keyring: None, require_signature: false, ..profile };
+        assert!(profile.check_signature_settings().is_ok());
+    }
+
+    #[test]
+    fn test_profile_groups() {
+        let config_str = r#"
+[profiles.core-v3]
+repo = "/home/testuser/repos/x86_64_v3/core-v3/core-v3.db.tar.zst"
+
+[profiles.core-v4]
+repo = "/home/testuser/repos/x86_64_v4/core-v4/core-v4.db.tar.zst"
+
+[groups]
+core = ["core-v3", "core-v4"]
+"#;
+        let config = parse_config_content(config_str).unwrap();
+        assert_eq!(config.groups["core"], vec!["core-v3".to_string(), "core-v4".to_string()]);
+        assert_eq!(config.get_sibling_repos("core-v3"), vec!["/home/testuser/repos/x86_64_v4/\
+                                                              core-v4/core-v4.db.tar.zst"
+            .to_string()]);
+
+        let config_str = format!("{config_str}extra = [\"core-v3\", \"extra-v3\"]\n");
+        assert!(parse_config_content(&config_str).is_err());
+    }
+
     #[test]
     fn test_empty_config() {
         let config_str = "";
@@ -0,0 +1,370 @@
+use crate::plan::PlanStep;
+use crate::repo_db;
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+const JOURNAL_DIRNAME: &str = ".repo-manage-journal";
+const JOURNAL_FILENAME: &str = "journal.json";
+
+/// State of the journaled step
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StepState {
+    /// The step was started, but might not be finished
+    Started,
+    Done,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub step: PlanStep,
+    pub state: StepState,
+    /// Repo DB which is modified by the step
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub db_path: Option<String>,
+    /// Where the removed file is kept until the journal is committed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash_path: Option<String>,
+}
+
+/// Copy of the repo DB files taken before the first modification of the DB
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DbBackup {
+    pub db_path: String,
+    /// Pairs of the DB file and its copy
+    pub files: Vec<(String, String)>,
+}
+
+/// Operation journal of the command, kept in the repo directory until the command finishes.
+///
+/// Every step is recorded before it is performed, removed files are kept in the journal and the
+/// repo DB files are saved before they are rewritten, so the interrupted command can be rolled
+/// forward or back by the `Recover` command.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Journal {
+    #[serde(skip)]
+    dir: String,
+    pub command: String,
+    pub entries: Vec<JournalEntry>,
+    pub db_backups: Vec<DbBackup>,
+}
+
+impl Journal {
+    /// Opens the journal left in the repo directory
+    pub fn open(repo_dir: &Path) -> Result<Option<Self>> {
+        let journal_dir = get_journal_dir(repo_dir);
+        let journal_path = format!("{journal_dir}/{JOURNAL_FILENAME}");
+        if !Path::new(&journal_path).exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&journal_path)?;
+        let mut journal: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse journal '{journal_path}'"))?;
+        journal.dir = journal_dir;
+        Ok(Some(journal))
+    }
+
+    /// Starts the new journal, refusing to do so if the interrupted one exists
+    pub fn create(repo_dir: &Path, command: &str) -> Result<Self> {
+        if let Some(journal) = Self::open(repo_dir)? {
+            anyhow::bail!(
+                "Found journal of interrupted '{}' command in '{}', run recover first",
+                journal.command,
+                repo_dir.display()
+            );
+        }
+
+        let journal = Self {
+            dir: get_journal_dir(repo_dir),
+            command: command.to_owned(),
+            entries: vec![],
+            db_backups: vec![],
+        };
+        // leftovers of the journal without the journal file are useless
+        if Path::new(&journal.dir).exists() {
+            fs::remove_dir_all(&journal.dir)?;
+        }
+        fs::create_dir_all(format!("{}/trash", journal.dir))
+            .with_context(|| format!("Failed to create journal dir '{}'", journal.dir))?;
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Records the step before it is performed, returns its index
+    pub fn begin(&mut self, step: &PlanStep, db_path: Option<&str>) -> Result<usize> {
+        let trash_path = match step {
+            PlanStep::RemoveFile(path) => Some(format!(
+                "{}/trash/{}-{}",
+                self.dir,
+                self.entries.len(),
+                Path::new(path).file_name().unwrap().to_str().unwrap()
+            )),
+            _ => None,
+        };
+        if let Some(db_path) = db_path {
+            self.backup_db_files(db_path)?;
+        }
+
+        self.entries.push(JournalEntry {
+            step: step.clone(),
+            state: StepState::Started,
+            db_path: db_path.map(str::to_owned),
+            trash_path,
+        });
+        self.save()?;
+        Ok(self.entries.len() - 1)
+    }
+
+    pub fn finish(&mut self, entry_index: usize) -> Result<()> {
+        self.entries[entry_index].state = StepState::Done;
+        self.save()
+    }
+
+    pub fn entry(&self, entry_index: usize) -> &JournalEntry {
+        &self.entries[entry_index]
+    }
+
+    pub fn has_unfinished_steps(&self) -> bool {
+        self.entries.iter().any(|entry| entry.state == StepState::Started)
+    }
+
+    /// Drops the journal together with the removed files
+    pub fn commit(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("Failed to remove journal dir '{}'", self.dir))
+    }
+
+    /// Finishes the interrupted step, or undoes all recorded steps with `rollback`.
+    ///
+    /// The interrupted repo DB step is performed again from the saved DB with `redo_db_step`.
+    pub fn recover(
+        mut self,
+        rollback: bool,
+        mut redo_db_step: impl FnMut(&str, &PlanStep) -> Result<()>,
+    ) -> Result<()> {
+        if rollback {
+            for entry in self.entries.iter().rev() {
+                log::info!("Rolling back '{}'..", entry.step);
+                undo_step(entry)?;
+            }
+            for db_backup in &self.db_backups {
+                log::info!("Restoring repo DB '{}'..", db_backup.db_path);
+                restore_db_files(db_backup)?;
+            }
+            return self.commit();
+        }
+
+        for entry_index in 0..self.entries.len() {
+            let entry = self.entries[entry_index].clone();
+            if entry.state == StepState::Done {
+                continue;
+            }
+
+            log::info!("Finishing '{}'..", entry.step);
+            if let Some(db_path) = &entry.db_path {
+                // the DB might be swapped partially, start over from the saved one and replay
+                // all steps of the DB
+                if let Some(db_backup) = self.db_backups.iter().find(|x| x.db_path == *db_path) {
+                    restore_db_files(db_backup)?;
+                }
+                for db_entry in &self.entries[..=entry_index] {
+                    if db_entry.db_path.as_ref() == Some(db_path) {
+                        redo_db_step(db_path, &db_entry.step)?;
+                    }
+                }
+            } else {
+                redo_file_step(&entry)?;
+            }
+            self.finish(entry_index)?;
+        }
+        log::info!("The interrupted '{}' command can be run again now", self.command);
+        self.commit()
+    }
+
+    fn backup_db_files(&mut self, db_path: &str) -> Result<()> {
+        if self.db_backups.iter().any(|db_backup| db_backup.db_path == db_path) {
+            return Ok(());
+        }
+
+        let backup_dir = format!("{}/db-backup/{}", self.dir, self.db_backups.len());
+        fs::create_dir_all(&backup_dir)?;
+        let mut db_backup = DbBackup { db_path: db_path.to_owned(), files: vec![] };
+        for db_file in repo_db::list_db_files(db_path)? {
+            let db_filename = Path::new(&db_file).file_name().unwrap().to_str().unwrap();
+            let backup_path = format!("{backup_dir}/{db_filename}");
+            fs::copy(&db_file, &backup_path)
+                .with_context(|| format!("Failed to save DB file '{db_file}'"))?;
+            db_backup.files.push((db_file, backup_path));
+        }
+        self.db_backups.push(db_backup);
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let journal_path = format!("{}/{JOURNAL_FILENAME}", self.dir);
+        let tmp_journal_path = format!("{journal_path}.tmp");
+        fs::write(&tmp_journal_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_journal_path, &journal_path)
+            .with_context(|| format!("Failed to write journal '{journal_path}'"))
+    }
+}
+
+/// Moves the file, falling back to copying in case the destination is on another filesystem
+pub fn move_path(src: &str, dest: &str) -> Result<()> {
+    if fs::rename(src, dest).is_err() {
+        fs::copy(src, dest).with_context(|| format!("Failed to move '{src}' -> '{dest}'"))?;
+        fs::remove_file(src)?;
+    }
+    Ok(())
+}
+
+fn get_journal_dir(repo_dir: &Path) -> String {
+    format!("{}/{JOURNAL_DIRNAME}", repo_dir.to_str().unwrap())
+}
+
+fn redo_file_step(entry: &JournalEntry) -> Result<()> {
+    match &entry.step {
+        // otherwise the file is moved already
+        PlanStep::MoveFile { src, dest } if Path::new(src).exists() => move_path(src, dest),
+        // the copy might be partial
+        PlanStep::CopyFile { src, dest } => Ok(fs::copy(src, dest).map(|_| ())?),
+        PlanStep::RemoveFile(path) if Path::new(path).exists() => {
+            move_path(path, entry.trash_path.as_ref().unwrap())
+        },
+        PlanStep::WriteFile { path, content } => Ok(fs::write(path, content)?),
+        _ => Ok(()),
+    }
+}
+
+fn undo_step(entry: &JournalEntry) -> Result<()> {
+    match &entry.step {
+        PlanStep::MoveFile { src, dest }
+            if Path::new(dest).exists() && !Path::new(src).exists() =>
+        {
+            move_path(dest, src)
+        },
+        PlanStep::CopyFile { dest, .. } if Path::new(dest).exists() => Ok(fs::remove_file(dest)?),
+        PlanStep::WriteFile { path, .. } if Path::new(path).exists() => Ok(fs::remove_file(path)?),
+        PlanStep::RemoveFile(path) if Path::new(entry.trash_path.as_ref().unwrap()).exists() => {
+            move_path(entry.trash_path.as_ref().unwrap(), path)
+        },
+        // the repo DB is restored from the saved files
+        _ => Ok(()),
+    }
+}
+
+fn restore_db_files(db_backup: &DbBackup) -> Result<()> {
+    for db_file in repo_db::list_db_files(&db_backup.db_path)? {
+        repo_db::remove_db_file(&db_file)?;
+    }
+    for (db_file, backup_path) in &db_backup.files {
+        fs::copy(backup_path, db_file)
+            .with_context(|| format!("Failed to restore DB file '{db_file}'"))?;
+        repo_db::create_db_symlink(db_file)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::journal::*;
+
+    #[test]
+    fn test_journal_rollback() {
+        let repo_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let repo_db_path = format!("{repo_dir}/repof.db.tar.zst");
+        let pkgfile = format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        let moved_pkgfile = format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst.moved");
+        let removed_pkgfile = format!("{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.zst");
+        fs::write(&repo_db_path, "old db").unwrap();
+        fs::write(&pkgfile, "").unwrap();
+        fs::write(&removed_pkgfile, "").unwrap();
+
+        let mut journal = Journal::create(Path::new(&repo_dir), "Update").unwrap();
+        let move_step = PlanStep::MoveFile { src: pkgfile.clone(), dest: moved_pkgfile.clone() };
+        let entry_index = journal.begin(&move_step, None).unwrap();
+        move_path(&pkgfile, &moved_pkgfile).unwrap();
+        journal.finish(entry_index).unwrap();
+
+        let remove_step = PlanStep::RemoveFile(removed_pkgfile.clone());
+        let entry_index = journal.begin(&remove_step, None).unwrap();
+        move_path(&removed_pkgfile, journal.entry(entry_index).trash_path.as_ref().unwrap())
+            .unwrap();
+        journal.finish(entry_index).unwrap();
+
+        // interrupted in the middle of the DB rewrite
+        let add_step = PlanStep::RepoAdd(vec![pkgfile.clone()]);
+        journal.begin(&add_step, Some(&repo_db_path)).unwrap();
+        fs::write(&repo_db_path, "new db").unwrap();
+        assert!(journal.has_unfinished_steps());
+
+        // another command refuses to run
+        assert!(Journal::create(Path::new(&repo_dir), "Reset").is_err());
+
+        let journal = Journal::open(Path::new(&repo_dir)).unwrap().unwrap();
+        assert_eq!(journal.command, "Update");
+        assert_eq!(journal.entries.len(), 3);
+        journal.recover(true, |_, _| unreachable!()).unwrap();
+
+        assert!(Path::new(&pkgfile).exists());
+        assert!(Path::new(&removed_pkgfile).exists());
+        assert!(!Path::new(&moved_pkgfile).exists());
+        assert_eq!(fs::read_to_string(&repo_db_path).unwrap(), "old db");
+        assert_eq!(Journal::open(Path::new(&repo_dir)).unwrap(), None);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_journal_roll_forward() {
+        let repo_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let repo_db_path = format!("{repo_dir}/repof.db.tar.zst");
+        let pkgfile = format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        let moved_pkgfile = format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst.moved");
+        fs::write(&repo_db_path, "old db").unwrap();
+        fs::write(&pkgfile, "").unwrap();
+
+        // interrupted in the middle of the file move
+        let mut journal = Journal::create(Path::new(&repo_dir), "Update").unwrap();
+        let move_step = PlanStep::MoveFile { src: pkgfile.clone(), dest: moved_pkgfile.clone() };
+        journal.begin(&move_step, None).unwrap();
+        let journal = Journal::open(Path::new(&repo_dir)).unwrap().unwrap();
+        journal.recover(false, |_, _| unreachable!()).unwrap();
+        assert!(!Path::new(&pkgfile).exists());
+        assert!(Path::new(&moved_pkgfile).exists());
+
+        // interrupted in the middle of the second DB rewrite
+        let mut journal = Journal::create(Path::new(&repo_dir), "Update").unwrap();
+        let add_step = PlanStep::RepoAdd(vec![moved_pkgfile.clone()]);
+        let entry_index = journal.begin(&add_step, Some(&repo_db_path)).unwrap();
+        fs::write(&repo_db_path, "db with dash").unwrap();
+        journal.finish(entry_index).unwrap();
+        let remove_step = PlanStep::RepoRemove(vec!["dwm".to_owned()]);
+        journal.begin(&remove_step, Some(&repo_db_path)).unwrap();
+        fs::write(&repo_db_path, "half written db").unwrap();
+
+        let mut redone_steps = vec![];
+        journal
+            .recover(false, |db_path, step| {
+                // the DB steps start over from the saved DB
+                if redone_steps.is_empty() {
+                    assert_eq!(fs::read_to_string(db_path).unwrap(), "old db");
+                }
+                redone_steps.push(step.clone());
+                fs::write(db_path, format!("db after {} steps", redone_steps.len()))?;
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(redone_steps, vec![add_step, remove_step]);
+        assert_eq!(fs::read_to_string(&repo_db_path).unwrap(), "db after 2 steps");
+        assert_eq!(Journal::open(Path::new(&repo_dir)).unwrap(), None);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+}
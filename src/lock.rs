@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use std::{fs, thread};
+
+use anyhow::{Context, Result};
+
+const EXCLUSIVE_LOCK_FILENAME: &str = ".repo-manage.lck";
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// distinguishes shared locks and stale lock takeovers of the same process
+static SHARED_LOCK_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// Access to the directory which is requested by the command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockMode {
+    /// Read-only access, may be held by multiple processes at once
+    Shared,
+    /// Read-write access
+    Exclusive,
+}
+
+/// Advisory lock of the repo directories, released on drop.
+///
+/// Each lock is a file in the directory containing PID of the holder, locks of the processes
+/// which don't exist anymore are considered stale and taken over atomically.
+#[derive(Debug)]
+pub struct RepoLock {
+    lock_files: Vec<String>,
+}
+
+impl RepoLock {
+    /// Locks the directories, waiting up to `timeout` for each of them.
+    ///
+    /// The directories are locked in a sorted order, the strongest requested mode wins for the
+    /// duplicated ones.
+    pub fn acquire(dirs: &[(String, LockMode)], timeout: Duration) -> Result<Self> {
+        let mut dir_modes: BTreeMap<&str, LockMode> = BTreeMap::new();
+        for (dir, mode) in dirs {
+            let dir_mode = dir_modes.entry(dir.trim_end_matches('/')).or_insert(*mode);
+            *dir_mode = (*dir_mode).max(*mode);
+        }
+
+        // lock files are released on drop in case of the error
+        let mut repo_lock = Self { lock_files: vec![] };
+        for (dir, mode) in dir_modes {
+            let lock_file = acquire_dir_lock(dir, mode, timeout)?;
+            repo_lock.lock_files.push(lock_file);
+        }
+        Ok(repo_lock)
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        for lock_file in self.lock_files.iter().rev() {
+            if let Err(rm_err) = fs::remove_file(lock_file) {
+                log::error!("Failed to release the lock '{lock_file}': {rm_err}");
+            }
+        }
+    }
+}
+
+fn acquire_dir_lock(dir: &str, mode: LockMode, timeout: Duration) -> Result<String> {
+    let exclusive_lock_file = format!("{dir}/{EXCLUSIVE_LOCK_FILENAME}");
+    let deadline = Instant::now() + timeout;
+    loop {
+        let holder_pid = match mode {
+            LockMode::Exclusive => {
+                if let Some(holder_pid) = try_create_lock_file(&exclusive_lock_file)? {
+                    Some(holder_pid)
+                } else if let Some(reader_pid) = get_shared_lock_holder(dir)? {
+                    // writer doesn't wait with the lock held, to not block the readers
+                    fs::remove_file(&exclusive_lock_file)?;
+                    Some(reader_pid)
+                } else {
+                    log::debug!("Acquired exclusive lock of '{dir}'");
+                    return Ok(exclusive_lock_file);
+                }
+            },
+            LockMode::Shared => {
+                let seq = SHARED_LOCK_SEQ.fetch_add(1, Ordering::Relaxed);
+                let shared_lock_file =
+                    format!("{exclusive_lock_file}.{}.{seq}", std::process::id());
+                if let Some(holder_pid) = get_lock_holder(&exclusive_lock_file)? {
+                    Some(holder_pid)
+                } else {
+                    // the name is unique, so there is nobody to wait for
+                    try_create_lock_file(&shared_lock_file)?;
+                    if let Some(holder_pid) = get_lock_holder(&exclusive_lock_file)? {
+                        // writer came in between
+                        fs::remove_file(&shared_lock_file)?;
+                        Some(holder_pid)
+                    } else {
+                        log::debug!("Acquired shared lock of '{dir}'");
+                        return Ok(shared_lock_file);
+                    }
+                }
+            },
+        };
+
+        let holder_pid = holder_pid.unwrap();
+        if Instant::now() >= deadline {
+            anyhow::bail!(
+                "'{dir}' is locked by process {holder_pid}, gave up after {}s",
+                timeout.as_secs()
+            );
+        }
+        log::debug!("'{dir}' is locked by process {holder_pid}, waiting..");
+        thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+// Creates the lock file with our PID, returns PID of the live holder if it exists already
+fn try_create_lock_file(lock_file: &str) -> Result<Option<u32>> {
+    loop {
+        match fs::OpenOptions::new().write(true).create_new(true).open(lock_file) {
+            Ok(mut file) => {
+                write!(file, "{}", std::process::id())
+                    .with_context(|| format!("Failed to write lock file '{lock_file}'"))?;
+                return Ok(None);
+            },
+            Err(open_err) if open_err.kind() == ErrorKind::AlreadyExists => {
+                if let Some(holder_pid) = get_lock_holder(lock_file)? {
+                    return Ok(Some(holder_pid));
+                }
+                // stale lock is removed, try again
+            },
+            Err(open_err) => {
+                return Err(open_err)
+                    .with_context(|| format!("Failed to create lock file '{lock_file}'"));
+            },
+        }
+    }
+}
+
+// Gets PID of the live lock holder, removing the stale lock file
+fn get_lock_holder(lock_file: &str) -> Result<Option<u32>> {
+    let content = match fs::read_to_string(lock_file) {
+        Ok(content) => content,
+        Err(read_err) if read_err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(read_err) => {
+            return Err(read_err).with_context(|| format!("Failed to read lock '{lock_file}'"));
+        },
+    };
+
+    let holder_pid = content.trim().parse::<u32>().ok();
+    match holder_pid {
+        Some(holder_pid) if is_process_alive(holder_pid) => return Ok(Some(holder_pid)),
+        // the file might be just created and not written yet
+        None if !is_lock_file_abandoned(lock_file) => return Ok(Some(0)),
+        _ => {},
+    }
+    let holder_pid = holder_pid.unwrap_or_default();
+
+    log::warn!("Removing stale lock '{lock_file}' of process {holder_pid}");
+    take_over_stale_lock(lock_file, &content)
+        .with_context(|| format!("Failed to remove stale lock '{lock_file}'"))?;
+    Ok(None)
+}
+
+// Removes the stale lock file, unless another process has replaced it with its own lock since
+// it was read. The file is moved away first, so only one process can take it over, and put back
+// if it turns out to be the fresh lock. Returns whether the stale lock was removed
+fn take_over_stale_lock(lock_file: &str, stale_content: &str) -> Result<bool> {
+    let lock_dir = Path::new(lock_file).parent().unwrap().to_str().unwrap();
+    let seq = SHARED_LOCK_SEQ.fetch_add(1, Ordering::Relaxed);
+    let takeover_file = format!("{lock_dir}/.repo-manage-stale.{}.{seq}", std::process::id());
+    match fs::rename(lock_file, &takeover_file) {
+        Ok(()) => {},
+        // another process took it over already
+        Err(rename_err) if rename_err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(rename_err) => return Err(rename_err.into()),
+    }
+
+    let is_stale = fs::read_to_string(&takeover_file)? == stale_content;
+    if !is_stale {
+        // fails if yet another lock is created in between, it is the holder then
+        if let Err(link_err) = fs::hard_link(&takeover_file, lock_file) {
+            log::error!("Failed to put back the lock '{lock_file}': {link_err}");
+        }
+    }
+    fs::remove_file(&takeover_file)?;
+    Ok(is_stale)
+}
+
+fn get_shared_lock_holder(dir: &str) -> Result<Option<u32>> {
+    let pattern =
+        format!("{}.*", glob::Pattern::escape(&format!("{dir}/{EXCLUSIVE_LOCK_FILENAME}")));
+    for entry in glob::glob(&pattern)? {
+        if let Some(holder_pid) = get_lock_holder(entry?.to_str().unwrap())? {
+            return Ok(Some(holder_pid));
+        }
+    }
+    Ok(None)
+}
+
+// Lock file without PID which is not written for a while
+fn is_lock_file_abandoned(lock_file: &str) -> bool {
+    fs::metadata(lock_file)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > Duration::from_secs(10))
+}
+
+fn is_process_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::*;
+
+    #[test]
+    fn test_exclusive_and_shared_lock() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let exclusive = [(temp_dir.clone(), LockMode::Exclusive)];
+        let shared = [(temp_dir.clone(), LockMode::Shared)];
+
+        let repo_lock = RepoLock::acquire(&exclusive, Duration::ZERO).unwrap();
+        assert!(RepoLock::acquire(&exclusive, Duration::ZERO).is_err());
+        assert!(RepoLock::acquire(&shared, Duration::ZERO).is_err());
+        drop(repo_lock);
+
+        // readers don't block each other, but block the writer
+        let shared_lock = RepoLock::acquire(&shared, Duration::ZERO).unwrap();
+        let another_shared_lock = RepoLock::acquire(&shared, Duration::ZERO).unwrap();
+        assert!(RepoLock::acquire(&exclusive, Duration::from_millis(200)).is_err());
+        drop(shared_lock);
+        drop(another_shared_lock);
+
+        // the strongest mode is taken for the same directory
+        let repo_lock =
+            RepoLock::acquire(&[shared[0].clone(), exclusive[0].clone()], Duration::ZERO).unwrap();
+        assert!(RepoLock::acquire(&shared, Duration::ZERO).is_err());
+        drop(repo_lock);
+
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_lock() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let lock_file = format!("{temp_dir}/{EXCLUSIVE_LOCK_FILENAME}");
+        // PID above the kernel limit doesn't exist
+        fs::write(&lock_file, format!("{}", u32::MAX)).unwrap();
+
+        let repo_lock =
+            RepoLock::acquire(&[(temp_dir.clone(), LockMode::Exclusive)], Duration::ZERO).unwrap();
+        assert_eq!(fs::read_to_string(&lock_file).unwrap(), std::process::id().to_string());
+        drop(repo_lock);
+
+        // the lock was replaced by the live one after it was found stale
+        fs::write(&lock_file, std::process::id().to_string()).unwrap();
+        assert!(!take_over_stale_lock(&lock_file, &u32::MAX.to_string()).unwrap());
+        assert_eq!(fs::read_to_string(&lock_file).unwrap(), std::process::id().to_string());
+        assert!(take_over_stale_lock(&lock_file, &std::process::id().to_string()).unwrap());
+        assert!(!take_over_stale_lock(&lock_file, "").unwrap());
+
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use log::{Metadata, Record};
 
@@ -6,6 +7,9 @@ struct SimpleLogger;
 
 static LOGGER: SimpleLogger = SimpleLogger;
 
+// keeps stdout clean for machine-readable output
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
 impl log::Log for SimpleLogger {
     fn enabled(&self, _: &Metadata) -> bool {
         true
@@ -13,7 +17,11 @@ impl log::Log for SimpleLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
+            if LOG_TO_STDERR.load(Ordering::Relaxed) {
+                eprintln!("{} - {}", record.level(), record.args());
+            } else {
+                println!("{} - {}", record.level(), record.args());
+            }
         }
     }
 
@@ -40,3 +48,8 @@ pub fn init_logger() -> Result<(), log::SetLoggerError> {
 
     log::set_logger(&LOGGER).map(|()| log::set_max_level(max_log_level))
 }
+
+/// Writes log messages into stderr instead of stdout
+pub fn log_to_stderr() {
+    LOG_TO_STDERR.store(true, Ordering::Relaxed);
+}
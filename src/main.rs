@@ -1,15 +1,30 @@
 mod alpm_helper;
 mod config;
+mod journal;
+mod lock;
 mod logger;
 mod pkg_utils;
+mod pkginfo;
+mod plan;
+mod prompt;
+mod repo_db;
 mod repo_utils;
+mod report;
+mod signature;
 mod utils;
 
-use std::fs;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use plan::{Executor, PlanStep};
+use report::{
+    print_report, CheckupReport, ConsistencyReport, DepsReport, OutputFormat, RepoPackages,
+    ReportEntry,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -17,8 +32,23 @@ struct Cli {
     #[command(subcommand)]
     command: Commands,
     /// Profile to use from the configuration file
+    #[arg(short, long, conflicts_with_all = ["all_profiles", "group"])]
+    profile: Option<String>,
+    /// Run the command for each profile from the configuration file
+    #[arg(long, conflicts_with = "group")]
+    all_profiles: bool,
+    /// Run the command for each profile of the group from the configuration file
     #[arg(short, long)]
-    profile: String,
+    group: Option<String>,
+    /// Print what would be done without touching the repository
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Don't ask for confirmation even if the profile is interactive
+    #[arg(short, long, global = true)]
+    yes: bool,
+    /// Allow adding packages older than the ones in the repository DB
+    #[arg(long, global = true)]
+    allow_downgrade: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -29,11 +59,68 @@ enum Commands {
     Update,
     /// Moves packages from current directory into the repository
     MovePkgsToRepo,
-    /// Check if the packages are up-to-date
-    IsPkgsUpToDate,
+    /// Check if the packages are up-to-date,
+    /// exits with non-zero code if the repository is not clean
+    IsPkgsUpToDate {
+        /// Format of the report
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Checks that the sibling repositories of the profile group (`--group`) have the same
+    /// packages, exits with non-zero code if they don't
+    CheckConsistency {
+        /// Format of the report
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Checks that the runtime dependencies of the packages are satisfied by the repository and
+    /// the configured dependency repositories, exits with non-zero code if they aren't
+    CheckDeps {
+        /// Format of the report
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Copies newer packages from the reference repository into the repository
+    SyncFromReference,
     /// Cleans up the backup directory,
     /// removing the N amount of packages if configured to do so
     CleanupBackupDir,
+    /// Restores the package version from the backup directory
+    Rollback {
+        /// Name of the package to roll back
+        pkgname: String,
+        /// Version to restore, the latest older one by default
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Moves packages from one profile's repository into another one's,
+    /// e.g from testing into stable
+    Promote {
+        /// Profile of the source repository
+        #[arg(long)]
+        from: String,
+        /// Profile of the destination repository
+        #[arg(long)]
+        to: String,
+        /// Packages to promote together with the rest of their split packages, all by default
+        pkgnames: Vec<String>,
+    },
+    /// Removes the packages from the repository, the package files are backed up or removed
+    /// according to the profile
+    Remove {
+        /// Names of the packages to remove
+        #[arg(required = true)]
+        pkgnames: Vec<String>,
+        /// Remove even if other packages of the repository depend on them
+        #[arg(long)]
+        force: bool,
+    },
+    /// Finishes the interrupted command from its journal
+    Recover {
+        /// Undo the steps of the interrupted command instead
+        #[arg(long)]
+        rollback: bool,
+    },
     // Check if we have only certain amount of debug packages in the debug repository
     // IsDebugPkgsOk, // ok maybe not implemented
 }
@@ -43,101 +130,429 @@ fn main() -> Result<()> {
 
     // initialize the logger
     logger::init_logger().expect("Failed to initialize logger");
+    if matches!(
+        args.command,
+        Commands::IsPkgsUpToDate { format: OutputFormat::Json }
+            | Commands::CheckConsistency { format: OutputFormat::Json }
+            | Commands::CheckDeps { format: OutputFormat::Json }
+    ) {
+        logger::log_to_stderr();
+    }
 
     // load config
     let config_path = config::get_config_path()?;
     let config = config::parse_config_file(&config_path)?;
 
-    // get profile from config
-    let profile = config
-        .profiles
-        .get(&args.profile)
-        .ok_or(anyhow::anyhow!("Profile {} not found", args.profile))?;
+    // the group is checked as a whole
+    if let Commands::CheckConsistency { format } = &args.command {
+        let Some(group) = &args.group else {
+            anyhow::bail!("--group is required for the command");
+        };
+        let report = do_consistency_check(&config, group)?;
+        let exit_code = print_report(&report, *format)?;
+        if exit_code != 0 {
+            std::process::exit(exit_code);
+        }
+        return Ok(());
+    }
+
+    let profile_names = get_profile_names(&args, &config)?;
+    if let [profile_name] = profile_names.as_slice() {
+        let exit_code = run_profile(&args, &config, profile_name)?;
+        if exit_code != 0 {
+            std::process::exit(exit_code);
+        }
+        return Ok(());
+    }
+
+    // keep going with the rest of the profiles, and report all of them at the end
+    let mut results = vec![];
+    for profile_name in &profile_names {
+        log::info!("Running for profile '{profile_name}'..");
+        results.push((profile_name, run_profile(&args, &config, profile_name)));
+    }
+
+    let mut exit_code = 0;
+    log::info!("Summary:");
+    for (profile_name, result) in results {
+        match result {
+            Ok(0) => log::info!("  {profile_name}: ok"),
+            Ok(profile_exit_code) => {
+                log::warn!("  {profile_name}: exited with code {profile_exit_code}");
+                exit_code = exit_code.max(profile_exit_code);
+            },
+            Err(profile_err) => {
+                log::error!("  {profile_name}: failed: {profile_err:#}");
+                exit_code = exit_code.max(1);
+            },
+        }
+    }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+
+    Ok(())
+}
+
+// Gets names of the profiles to run the command for
+fn get_profile_names(args: &Cli, config: &config::Config) -> Result<Vec<String>> {
+    // promote works with the destination profile
+    if let Commands::Promote { to, .. } = &args.command {
+        if args.all_profiles || args.group.is_some() {
+            anyhow::bail!("promote cannot be used with multiple profiles");
+        }
+        return Ok(vec![to.clone()]);
+    }
+
+    if args.all_profiles {
+        let mut profile_names = config.profiles.keys().cloned().collect::<Vec<_>>();
+        profile_names.sort();
+        return Ok(profile_names);
+    }
+    if let Some(group) = &args.group {
+        let profile_names =
+            config.groups.get(group).ok_or(anyhow::anyhow!("Group {group} not found"))?;
+        return Ok(profile_names.clone());
+    }
+    match &args.profile {
+        Some(profile_name) => Ok(vec![profile_name.clone()]),
+        None => anyhow::bail!("--profile, --group or --all-profiles is required for the command"),
+    }
+}
+
+// Runs the command for the profile, returns the exit code
+fn run_profile(args: &Cli, config: &config::Config, profile_name: &str) -> Result<i32> {
+    let mut profile = get_profile(config, profile_name)?.clone();
+    if args.allow_downgrade {
+        profile.allow_downgrade = vec!["*".to_owned()];
+    }
+    profile.sibling_repos = config.get_sibling_repos(profile_name);
+    let profile = &profile;
+    let source_profile = match &args.command {
+        Commands::Promote { from, .. } => Some(get_profile(config, from)?),
+        _ => None,
+    };
 
     let repo_path = Path::new(&profile.repo);
     let repo_dir = repo_path.parent().unwrap();
 
-    let repo_db_prefix = pkg_utils::get_repo_db_prefix(&profile.repo);
-    let repo_db_pattern = format!("{}/{repo_db_prefix}.*", repo_dir.to_str().unwrap());
+    log::debug!("repo db path := {}", profile.repo);
+
+    // companion repo DB of the debug packages
+    let debug_profile = profile.debug_profile();
+
+    // don't let another process touch the repo while we are working with it
+    let mut lock_dirs = get_lock_dirs(profile, &args.command, args.dry_run);
+    if let Some(source_profile) = source_profile {
+        lock_dirs.extend(get_lock_dirs(source_profile, &args.command, args.dry_run));
+    }
+    let repo_lock = lock::RepoLock::acquire(&lock_dirs, Duration::from_secs(profile.lock_timeout))?;
+    let mut debug_backend = debug_profile.as_ref().map(repo_utils::make_backend);
+    let mut source_backend = source_profile.map(repo_utils::make_backend);
+
+    let mut backend = repo_utils::make_backend(profile);
+    let prompter = prompt::Prompter::new(profile.interactive, args.yes);
+    let mut executor = Executor::new(backend.as_mut(), args.dry_run)
+        .with_prompter(prompter)
+        .with_extra_pkg_patterns(&profile.extra_pkg_patterns);
+    profile.check_signature_settings()?;
+    if let Some(keyring) = &profile.keyring {
+        executor = executor.with_signature_verifier(signature::SignatureVerifier::new(keyring)?);
+    }
+
+    // journal of the steps, so the interrupted command can be recovered
+    let is_mutating = !matches!(
+        args.command,
+        Commands::IsPkgsUpToDate { .. } | Commands::CheckDeps { .. } | Commands::Recover { .. }
+    );
+    if is_mutating && !args.dry_run {
+        let command_name = format!("{:?}", args.command);
+        executor = executor.with_journal(journal::Journal::create(repo_dir, &command_name)?);
+    }
 
-    log::debug!("repo db path := {repo_db_pattern}");
+    let result = run_command(
+        &args.command,
+        config,
+        profile,
+        &mut executor,
+        RepoBackends {
+            debug: debug_profile.as_ref().zip(debug_backend.as_mut().map(|x| x.as_mut() as _)),
+            source: source_profile.zip(source_backend.as_mut().map(|x| x.as_mut() as _)),
+        },
+        repo_dir,
+    );
+    executor.finish_journal(result.is_ok())?;
+    let exit_code = result?;
+
+    if args.dry_run {
+        executor.print_plan();
+    }
 
-    match &args.command {
+    drop(repo_lock);
+    Ok(exit_code)
+}
+
+fn get_profile<'a>(config: &'a config::Config, profile_name: &str) -> Result<&'a config::Profile> {
+    config.profiles.get(profile_name).ok_or(anyhow::anyhow!("Profile {profile_name} not found"))
+}
+
+// Repo DBs which are modified by the command in addition to the profile's one
+struct RepoBackends<'a, 'b> {
+    debug: Option<(&'b config::Profile, &'a mut dyn repo_utils::RepoBackend)>,
+    source: Option<(&'b config::Profile, &'a mut dyn repo_utils::RepoBackend)>,
+}
+
+// Runs the command, returns the exit code
+fn run_command<'a>(
+    command: &Commands,
+    config: &config::Config,
+    profile: &config::Profile,
+    executor: &mut Executor<'a>,
+    backends: RepoBackends<'a, '_>,
+    repo_dir: &Path,
+) -> Result<i32> {
+    let debug_repo = backends.debug;
+    match command {
         Commands::Reset => {
-            do_repo_reset(profile, &repo_db_pattern, repo_dir)?;
-            // TODO(vnepogodin): handle debug packages
-            // move them to debug folder if is set
+            do_repo_reset(profile, executor, repo_dir)?;
+            if let Some((debug_profile, debug_backend)) = debug_repo {
+                do_debug_repo_update(debug_profile, executor, debug_backend, true)?;
+            }
         },
         Commands::Update => {
-            do_repo_update(profile, repo_dir)?;
-            // TODO(vnepogodin): handle debug packages
-            // move them to debug folder if is set
+            let rejected_pkgs = do_repo_update(profile, executor, repo_dir)?;
+            if let Some((debug_profile, debug_backend)) = debug_repo {
+                do_debug_repo_update(debug_profile, executor, debug_backend, false)?;
+            }
+            if !rejected_pkgs.is_empty() {
+                log::error!("Rejected {} package(s): {rejected_pkgs:?}", rejected_pkgs.len());
+                return Ok(1);
+            }
         },
         Commands::MovePkgsToRepo => {
-            do_repo_move_pkgs(profile, repo_dir)?;
+            let current_dir =
+                std::env::current_dir().context("Failed to get current working dir")?;
+            let rejected_pkgs = do_repo_move_pkgs(profile, executor, &current_dir, repo_dir)?;
+            if let Some((debug_profile, debug_backend)) = debug_repo {
+                do_debug_repo_update(debug_profile, executor, debug_backend, false)?;
+            }
+            if !rejected_pkgs.is_empty() {
+                log::error!("Rejected {} package(s): {rejected_pkgs:?}", rejected_pkgs.len());
+                return Ok(1);
+            }
+        },
+        Commands::SyncFromReference => {
+            let rejected_pkgs = do_reference_sync(profile, executor, repo_dir)?;
+            if !rejected_pkgs.is_empty() {
+                log::error!("Rejected {} package(s): {rejected_pkgs:?}", rejected_pkgs.len());
+                return Ok(1);
+            }
+        },
+        Commands::CheckConsistency { .. } => unreachable!("the group is checked as a whole"),
+        Commands::IsPkgsUpToDate { format } => {
+            let report = do_repo_checkup(profile, executor, repo_dir)?;
+            return print_report(&report, *format);
         },
-        Commands::IsPkgsUpToDate => {
-            do_repo_checkup(profile, repo_dir)?;
+        Commands::CheckDeps { format } => {
+            let report = do_deps_check(profile, executor)?;
+            return print_report(&report, *format);
         },
         Commands::CleanupBackupDir => {
-            do_backup_repo_cleanup(profile)?;
+            do_backup_repo_cleanup(profile, executor)?;
         },
+        Commands::Rollback { pkgname, to } => {
+            do_repo_rollback(profile, executor, repo_dir, pkgname, to.as_deref())?;
+        },
+        Commands::Remove { pkgnames, force } => {
+            do_repo_remove(profile, executor, repo_dir, pkgnames, *force)?;
+        },
+        Commands::Promote { pkgnames, .. } => {
+            let (source_profile, source_backend) = backends.source.unwrap();
+            do_repo_promote(source_profile, profile, executor, source_backend, pkgnames)?;
+        },
+        Commands::Recover { rollback } => {
+            do_journal_recover(config, executor, repo_dir, *rollback)?;
+        },
+    }
+    Ok(0)
+}
+
+// Gets the directories which are touched by the command, with the required access
+fn get_lock_dirs(
+    profile: &config::Profile,
+    command: &Commands,
+    dry_run: bool,
+) -> Vec<(String, lock::LockMode)> {
+    let read_only =
+        dry_run || matches!(command, Commands::IsPkgsUpToDate { .. } | Commands::CheckDeps { .. });
+    let mode = if read_only { lock::LockMode::Shared } else { lock::LockMode::Exclusive };
+
+    let repo_dir = Path::new(&profile.repo).parent().unwrap().to_str().unwrap().to_owned();
+    let mut lock_dirs = vec![(repo_dir, mode)];
+    if let Some(backup_dir) = profile.backup_dir.as_ref().filter(|_| profile.backup) {
+        lock_dirs.push((backup_dir.clone(), mode));
+    }
+    if let Some(quarantine_dir) = &profile.quarantine_dir {
+        lock_dirs.push((quarantine_dir.clone(), mode));
+    }
+    if let Some(debug_profile) = profile.debug_profile() {
+        let debug_dir = Path::new(&debug_profile.repo).parent().unwrap();
+        lock_dirs.push((debug_dir.to_str().unwrap().to_owned(), mode));
+    }
+    if let Some(reference_repo) = &profile.reference_repo {
+        let reference_dir = Path::new(reference_repo).parent().unwrap();
+        lock_dirs.push((reference_dir.to_str().unwrap().to_owned(), lock::LockMode::Shared));
     }
 
-    Ok(())
+    // nothing to lock in the directories which don't exist yet
+    lock_dirs.retain(|(dir, _)| Path::new(dir).is_dir());
+    lock_dirs
 }
 
-fn do_repo_reset(profile: &config::Profile, repo_db_pattern: &str, repo_dir: &Path) -> Result<()> {
-    // Remove db and files
-    for pattern in [repo_db_pattern] {
-        log::debug!("removing db file '{pattern}'..");
-        for entry in glob::glob(pattern)? {
-            fs::remove_file(entry?)?
+fn do_journal_recover(
+    config: &config::Config,
+    executor: &mut Executor,
+    repo_dir: &Path,
+    rollback: bool,
+) -> Result<()> {
+    let Some(journal) = journal::Journal::open(repo_dir)? else {
+        log::info!("Nothing to recover in '{}'", repo_dir.display());
+        return Ok(());
+    };
+
+    log::info!("Found journal of interrupted '{}' command", journal.command);
+    if executor.is_dry_run() {
+        for entry in &journal.entries {
+            log::info!("[dry-run] {:?}: {}", entry.state, entry.step);
         }
+        return Ok(());
     }
 
-    let mut pkgs_list = glob::glob(&format!("{}/*.pkg.tar.zst", repo_dir.to_str().unwrap()))?
-        .map(|x| x.unwrap().to_str().unwrap().to_owned())
-        .collect::<Vec<_>>();
-    let outdated_pkgs = pkg_utils::get_outdated_pkgs(&pkgs_list);
-    pkgs_list.retain(|pkg| !outdated_pkgs.contains(pkg));
+    // the command might have modified the repo DBs of other profiles as well
+    let mut repo_profiles = vec![];
+    for profile in config.profiles.values() {
+        repo_profiles.extend(profile.debug_profile());
+        repo_profiles.push(profile.clone());
+    }
+
+    journal.recover(rollback, |db_path, step| {
+        // the step is performed again by the backend of its repo DB
+        let Some(repo_profile) = repo_profiles.iter().find(|x| x.repo == db_path) else {
+            anyhow::bail!("Repo DB '{db_path}' doesn't belong to any profile");
+        };
+        redo_repo_db_step(repo_utils::make_backend(repo_profile).as_mut(), step)
+    })?;
+    log::info!("Recovery is done!");
+    Ok(())
+}
 
-    // don't insert packages without signature
-    if profile.require_signature {
-        pkg_utils::remove_pkgs_without_sig(&mut pkgs_list);
+fn redo_repo_db_step(backend: &mut dyn repo_utils::RepoBackend, step: &PlanStep) -> Result<()> {
+    match step {
+        PlanStep::RepoAdd(pkgfiles) => backend.add(pkgfiles),
+        PlanStep::RepoRemove(pkgname_list) => backend.remove(pkgname_list),
+        PlanStep::RepoRebuild(pkgfiles) => backend.rebuild(pkgfiles),
+        _ => Ok(()),
     }
+}
 
-    // run repo-add
-    repo_utils::handle_repo_add(profile, &pkgs_list)?;
+fn do_repo_reset(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    repo_dir: &Path,
+) -> Result<()> {
+    // debug packages go into the separate debug repo
+    do_debug_packages_check(profile, executor, repo_dir)?;
+
+    let mut pkgs_list = executor.list_pkgfiles(repo_dir.to_str().unwrap())?;
+    check_compression_conflicts(&pkgs_list)?;
+    let outdated_pkgs = pkg_utils::get_outdated_pkgs(&executor.load_pkginfos(&pkgs_list));
+    pkgs_list.retain(|pkg| !outdated_pkgs.contains(pkg));
+
+    // don't insert packages without valid signature
+    pkg_utils::remove_pkgs_with_invalid_sig(&mut pkgs_list, |pkg| {
+        executor.check_signature(pkg, profile.require_signature).map(|_| ())
+    });
+
+    // recreate the DB with packages
+    executor.repo_rebuild(&pkgs_list)?;
 
     // handle removal/backup here
-    handle_outdated_pkgs(profile, &outdated_pkgs)?;
+    handle_outdated_pkgs(profile, executor, &outdated_pkgs)?;
 
     log::info!("Repo reset is done!");
 
     Ok(())
 }
 
-fn do_repo_update(profile: &config::Profile, repo_dir: &Path) -> Result<()> {
-    let pkgs_list = glob::glob(&format!("{}/*.pkg.tar.zst", repo_dir.to_str().unwrap()))?
-        .map(|x| x.unwrap().to_str().unwrap().to_owned())
-        .collect::<Vec<_>>();
-    let outdated_pkgs = pkg_utils::get_outdated_pkgs(&pkgs_list);
-    let mut new_pkgs = pkg_utils::get_new_pkgs(&pkgs_list);
+// Adds the new packages of the repo dir into the DB and removes the stale entries, returns the
+// packages which were rejected
+fn do_repo_update(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    repo_dir: &Path,
+) -> Result<Vec<String>> {
+    // debug packages go into the separate debug repo
+    do_debug_packages_check(profile, executor, repo_dir)?;
+
+    let pkgs_list = executor.list_pkgfiles(repo_dir.to_str().unwrap())?;
+    check_compression_conflicts(&pkgs_list)?;
+    let pkginfos = executor.load_pkginfos(&pkgs_list);
+    let outdated_pkgs = pkg_utils::get_outdated_pkgs(&pkginfos);
+    let mut new_pkgs = pkg_utils::get_new_pkgs(&pkginfos);
 
     // 1. handle new packages
 
     // TODO(vnepogodin): handle ref repo updates here
 
     // handle new packages which are not present in the DB
-    let mut brand_new_pkgs = alpm_helper::get_brand_new_packages(&profile.repo)
-        .context("Failed to get brand new pkgs")?;
+    let db_pkgs = executor.list_entries().context("Failed to read repo DB")?;
+    let mut brand_new_pkgs = alpm_helper::get_brand_new_packages(&db_pkgs, &pkginfos);
+    // older versions of brand new packages are handled as outdated ones
+    brand_new_pkgs.retain(|pkg| !outdated_pkgs.contains(pkg) && !new_pkgs.contains(pkg));
     // after append the brand_new_pkgs becomes invalidated (e.g empty Vec)
     new_pkgs.append(&mut brand_new_pkgs);
 
-    // don't insert packages without signature
-    if profile.require_signature {
-        pkg_utils::remove_pkgs_without_sig(&mut new_pkgs);
+    // don't insert packages without valid signature or from the packagers which aren't allowed
+    let mut rejected_pkgs = vec![];
+    new_pkgs.retain(|pkg| match check_incoming_pkg(profile, executor, &db_pkgs, pkg) {
+        Ok(()) => true,
+        Err(reject_err) => {
+            rejected_pkgs.push((pkg.clone(), reject_err));
+            false
+        },
+    });
+    // split packages of the pkgbase are added together
+    let new_pkginfos =
+        pkginfos.iter().filter(|(x, _)| new_pkgs.contains(x)).cloned().collect::<Vec<_>>();
+    reject_partial_pkgbases(&db_pkgs, &new_pkginfos, &pkginfos, &mut rejected_pkgs);
+    new_pkgs.retain(|pkg| !rejected_pkgs.iter().any(|(x, _)| x == pkg));
+    // the sonames which other packages still depend on must not disappear
+    let new_pkginfos =
+        pkginfos.iter().filter(|(x, _)| new_pkgs.contains(x)).cloned().collect::<Vec<_>>();
+    check_soname_breakages(profile, &db_pkgs, &new_pkginfos, &mut rejected_pkgs)?;
+    new_pkgs.retain(|pkg| !rejected_pkgs.iter().any(|(x, _)| x == pkg));
+
+    for (rejected_pkg, reject_err) in &rejected_pkgs {
+        quarantine_pkg(profile, executor, rejected_pkg, &format!("{reject_err:#}"))?;
+    }
+    // the versions which the rejected packages would replace are kept
+    let accepted_pkginfos = pkginfos
+        .iter()
+        .filter(|(pkg, _)| !rejected_pkgs.iter().any(|(x, _)| x == pkg))
+        .cloned()
+        .collect::<Vec<_>>();
+    let outdated_pkgs = pkg_utils::get_outdated_pkgs(&accepted_pkginfos);
+
+    // the packages which the update would break must be satisfied by the repo in the end
+    if profile.check_deps && !new_pkgs.is_empty() {
+        let added_pkginfos =
+            pkginfos.iter().filter(|(x, _)| new_pkgs.contains(x)).cloned().collect::<Vec<_>>();
+        check_update_deps(profile, &db_pkgs, &added_pkginfos)?;
+    }
+
+    // the added packages must not own the same files as other packages
+    if profile.check_file_conflicts && !new_pkgs.is_empty() {
+        check_update_file_conflicts(profile, executor, repo_dir, &db_pkgs, &new_pkgs)?;
     }
 
     // if update available then update the DB accordingly
@@ -146,194 +561,642 @@ fn do_repo_update(profile: &config::Profile, repo_dir: &Path) -> Result<()> {
         // TODO(vnepogodin): print which new packages we add
         // e.g adding new package 'pacman'..
 
-        repo_utils::handle_repo_add(profile, &new_pkgs)?;
+        executor.repo_add(&new_pkgs)?;
 
         // 1.1 handle removal/backup of old packages here
         // NOTE: we are likely to handle it equally for update and reset. lets hope so?
-        handle_outdated_pkgs(profile, &outdated_pkgs)?;
+        handle_outdated_pkgs(profile, executor, &outdated_pkgs)?;
     }
 
     // 2. handle stale packages
+    // the DB was read before adding, entries of the packages we just added are replaced already
+    let added_pkgnames = pkginfos
+        .iter()
+        .filter(|(pkg, _)| new_pkgs.contains(pkg))
+        .map(|(_, pkginfo)| pkginfo.pkgname.clone())
+        .collect::<Vec<_>>();
     let stale_pkgs =
-        alpm_helper::get_stale_packages(&profile.repo).context("Failed to get stale pkgs")?;
+        alpm_helper::get_stale_packages(&db_pkgs, repo_dir, |path| executor.exists(path))
+            .into_iter()
+            .filter(|db_pkg| !added_pkgnames.contains(&db_pkg.name))
+            .map(|db_pkg| {
+                log::info!("Found stale package in repo DB: '{}'", db_pkg.db_pair());
+                db_pkg.name
+            })
+            .collect::<Vec<_>>();
 
     // if we found stale packages then remove them from DB
     // overwise silently skip and finish update command
     if !stale_pkgs.is_empty() {
-        repo_utils::handle_repo_remove(profile, &stale_pkgs)?;
+        executor.repo_remove(&stale_pkgs)?;
     }
 
     log::info!("Repo update is done!");
 
-    Ok(())
+    Ok(rejected_pkgs.into_iter().map(|(x, _)| x).collect())
 }
 
-fn do_repo_move_pkgs(profile: &config::Profile, repo_dir: &Path) -> Result<()> {
+fn do_repo_move_pkgs(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    src_dir: &Path,
+    repo_dir: &Path,
+) -> Result<Vec<String>> {
     // 1. moving packages from current dir
-    let current_dir = std::env::current_dir().context("Failed to get current working dir")?;
 
-    // here we get only packages without signature
-    let pkg_to_move_list = glob::glob(&format!("{}/*.pkg.tar.zst", current_dir.to_str().unwrap()))?
-        .map(|x| x.unwrap().to_str().unwrap().to_owned())
-        .collect::<Vec<_>>();
+    let mut pkg_to_move_list = executor.list_pkgfiles(src_dir.to_str().unwrap())?;
 
     // NOTE: probably we would rather want here to see filenames instead of full paths
     log::info!("Found packages to move in current dir: {pkg_to_move_list:?}");
 
-    // lets invalidate packages if they are without signatures
-    let mut invalid_pkgs: Vec<String> = vec![];
+    // invalid packages are quarantined, the valid ones proceed
+    let db_pkgs = executor.list_entries().context("Failed to read repo DB")?;
+    let mut rejected_pkgs = vec![];
     for pkg_to_move in &pkg_to_move_list {
-        // check for signature if we require it
-        if profile.require_signature && !Path::new(&format!("{pkg_to_move}.sig")).exists() {
-            let pkg_db_entry = pkg_utils::get_pkg_db_pair_from_path(pkg_to_move);
-            log::error!("Found package without required signature: '{pkg_db_entry}'");
-            invalid_pkgs.push(pkg_to_move.clone());
+        if let Err(reject_err) = check_pkg_to_move(profile, executor, &db_pkgs, pkg_to_move) {
+            rejected_pkgs.push((pkg_to_move.clone(), reject_err));
         }
     }
-    if !invalid_pkgs.is_empty() {
-        log::error!("Aborting due to found 'invalid' packages. Cannot proceed further");
-        return Ok(());
-    }
+    // split packages of the pkgbase are moved together, unless the rest of them is in the repo
+    let move_pkginfos = executor
+        .load_pkginfos(&pkg_to_move_list)
+        .into_iter()
+        .filter(|(x, _)| !rejected_pkgs.iter().any(|(y, _)| x == y))
+        .collect::<Vec<_>>();
+    let mut available_pkginfos =
+        executor.load_pkginfos(&executor.list_pkgfiles(repo_dir.to_str().unwrap())?);
+    available_pkginfos.extend(move_pkginfos.iter().cloned());
+    reject_partial_pkgbases(&db_pkgs, &move_pkginfos, &available_pkginfos, &mut rejected_pkgs);
 
-    if let Err(pkg_move_err) = handle_pkgfiles_move(&pkg_to_move_list, repo_dir.to_str().unwrap()) {
-        log::error!("Error occured while moving package files: {pkg_move_err}");
-        return Ok(());
+    for (rejected_pkg, reject_err) in &rejected_pkgs {
+        quarantine_pkg(profile, executor, rejected_pkg, &format!("{reject_err:#}"))?;
+    }
+    pkg_to_move_list.retain(|pkg| !rejected_pkgs.iter().any(|(x, _)| x == pkg));
+    let mut rejected_pkgs = rejected_pkgs.into_iter().map(|(x, _)| x).collect::<Vec<_>>();
+    if pkg_to_move_list.is_empty() {
+        log::info!("No valid packages to move");
+    } else {
+        // the moved packages must not clash with the ones in the repo
+        let repo_dir_str = repo_dir.to_str().unwrap();
+        let mut dest_pkgs_list = executor.list_pkgfiles(repo_dir_str)?;
+        dest_pkgs_list.extend(pkg_to_move_list.iter().map(|pkg_to_move| {
+            let pkg_filename = Path::new(pkg_to_move).file_name().unwrap().to_str().unwrap();
+            format!("{repo_dir_str}/{pkg_filename}")
+        }));
+        dest_pkgs_list.sort();
+        check_compression_conflicts(&dest_pkgs_list)?;
+
+        handle_pkgfiles_move(executor, &pkg_to_move_list, repo_dir_str)
+            .context("Error occured while moving package files")?;
     }
 
     // 2. doing regular repo update
     // TODO(vnepogodin): don't parse all packages in the repo,
     // we need to touch only packages which we move into
-    do_repo_update(profile, repo_dir)?;
+    // the packages rejected by the update count as well
+    rejected_pkgs.extend(do_repo_update(profile, executor, repo_dir)?);
 
     log::info!("Repo MovePkgsToRepo is done!");
 
-    Ok(())
+    Ok(rejected_pkgs)
 }
 
-fn do_repo_checkup(profile: &config::Profile, repo_dir: &Path) -> Result<()> {
-    let pkgs_list = glob::glob(&format!("{}/*.pkg.tar.zst", repo_dir.to_str().unwrap()))?
-        .map(|x| x.unwrap().to_str().unwrap().to_owned())
-        .collect::<Vec<_>>();
-    let outdated_pkgs = pkg_utils::get_outdated_pkgs(&pkgs_list);
-    let new_pkgs = pkg_utils::get_new_pkgs(&pkgs_list);
+fn do_repo_checkup(
+    profile: &config::Profile,
+    executor: &Executor,
+    repo_dir: &Path,
+) -> Result<CheckupReport> {
+    let pkgs_list =
+        pkg_utils::list_pkgfiles(repo_dir.to_str().unwrap(), &profile.extra_pkg_patterns)?;
+    let pkginfos = executor.load_pkginfos(&pkgs_list);
+    let outdated_pkgs = pkg_utils::get_outdated_pkgs(&pkginfos);
+    let new_pkgs = pkg_utils::get_new_pkgs(&pkginfos);
 
     let repo_db_prefix = pkg_utils::get_repo_db_prefix(&profile.repo);
+    let mut report = CheckupReport::new(&repo_db_prefix);
 
     // 1. handle new packages
 
     // handle new packages which are not present in the DB
-    let brand_new_pkgs = alpm_helper::get_brand_new_packages(&profile.repo)
-        .context("Failed to get brand new pkgs")?;
+    let db_pkgs = executor.list_entries().context("Failed to read repo DB")?;
+    let brand_new_pkgs = alpm_helper::get_brand_new_packages(&db_pkgs, &pkginfos);
+
+    for brand_new_pkg in &brand_new_pkgs {
+        report.brand_new.push(ReportEntry::from_pkgfile(
+            brand_new_pkg,
+            "package is not present in the repo DB",
+        ));
+    }
 
-    for brand_new_pkg in brand_new_pkgs {
-        let pkg_pair = pkg_utils::get_pkg_db_pair_from_path(&brand_new_pkg);
-        log::info!("Found brand new package in repo '{repo_db_prefix}': '{pkg_pair}'");
+    for new_pkg in &new_pkgs {
+        report.new.push(ReportEntry::from_pkgfile(
+            new_pkg,
+            "newer version of the package file in the repo",
+        ));
     }
 
-    for new_pkg in new_pkgs {
-        let pkg_pair = pkg_utils::get_pkg_db_pair_from_path(&new_pkg);
-        log::info!("Found new package in repo '{repo_db_prefix}': '{pkg_pair}'");
+    // 1.1 check signatures of the packages which are going to be added
+    for entry in report.brand_new.iter_mut().chain(report.new.iter_mut()) {
+        let pkg_filepath = format!("{}/{}", repo_dir.to_str().unwrap(), entry.filename);
+        match executor.check_signature(&pkg_filepath, profile.require_signature) {
+            Ok(signer) => entry.signer = signer,
+            Err(sig_err) => report
+                .invalid_signature
+                .push(ReportEntry::from_pkgfile(&pkg_filepath, &sig_err.to_string())),
+        }
     }
 
-    // 1.1 handle removal/backup of old packages here
-    for outdated_pkg in outdated_pkgs {
-        let pkg_pair = pkg_utils::get_pkg_db_pair_from_path(&outdated_pkg);
-        log::info!("Found outdated package in repo '{repo_db_prefix}': '{pkg_pair}'");
+    // 1.2 handle removal/backup of old packages here
+    for outdated_pkg in &outdated_pkgs {
+        report.outdated.push(ReportEntry::from_pkgfile(
+            outdated_pkg,
+            "package file is superseded by a newer version in the repo",
+        ));
     }
 
     // 2. handle stale packages
 
-    // we want to get here filenames of stale packages
-    let stale_filenames = alpm_helper::get_stale_filenames(&profile.repo)
-        .context("Failed to get stale pkgs with filename")?;
+    for stale_pkg in
+        alpm_helper::get_stale_packages(&db_pkgs, repo_dir, |path| Path::new(path).exists())
+    {
+        report.stale.push(ReportEntry::from_db_pkg(
+            &stale_pkg,
+            "package file is missing in the repo directory",
+        ));
+    }
+
+    // 3. handle split packages which are at different versions in the DB
+    for (pkgbase, pkgbase_pkgs) in alpm_helper::get_pkgbase_version_mismatches(&db_pkgs) {
+        for db_pkg in &pkgbase_pkgs {
+            report.pkgbase_mismatch.push(ReportEntry::from_db_pkg(
+                db_pkg,
+                &format!("packages of pkgbase '{pkgbase}' are at different versions"),
+            ));
+        }
+    }
+
+    // 4. handle package files which are the same version with different compression
+    for conflicting_pkgs in pkg_utils::get_compression_conflicts(&pkgs_list) {
+        for conflicting_pkg in &conflicting_pkgs {
+            report.compression_conflicts.push(ReportEntry::from_pkgfile(
+                conflicting_pkg,
+                "the same package version exists with different compression",
+            ));
+        }
+    }
 
-    for stale_filename in stale_filenames {
-        let pkg_pair = pkg_utils::get_pkg_db_pair_from_path(&stale_filename);
-        log::info!("Found stale package in repo '{repo_db_prefix}': '{pkg_pair}'");
+    // 5. handle packages which own the same files
+    let repo_filelists = alpm_helper::read_repo_files_db(&profile.repo)?;
+    let latest_pkgs =
+        pkgs_list.iter().filter(|x| !outdated_pkgs.contains(x)).cloned().collect::<Vec<_>>();
+    let pkg_filelists = load_pkg_filelists(executor, &repo_filelists, &db_pkgs, &latest_pkgs);
+    for (first_pkg, second_pkg, paths) in alpm_helper::get_file_conflicts(&pkg_filelists) {
+        for (db_pkg, other_pkg) in [(first_pkg, second_pkg), (second_pkg, first_pkg)] {
+            report.file_conflicts.push(ReportEntry::from_db_pkg(
+                db_pkg,
+                &format!(
+                    "owns {} file(s) of '{}', e.g '{}'",
+                    paths.len(),
+                    other_pkg.db_pair(),
+                    paths[0]
+                ),
+            ));
+        }
     }
 
-    // 3. handle ref repository
+    // 6. handle ref repository
     // Check for newer packages in the reference repository
     if let Some(reference_repo_path) = &profile.reference_repo {
-        let packages_to_copy = alpm_helper::get_newer_packages_from_reference(
-            &profile.repo,
-            reference_repo_path,
-        )
-        .context("Failed to get newer packages from reference repo")?;
-
-        if !packages_to_copy.is_empty() {
-            let new_pkgname_list = packages_to_copy.iter().map(|x| pkg_utils::get_pkg_db_pair_from_path(&x)).collect::<Vec<_>>();
-            log::info!("Found new pkgs from ref repo '{repo_db_prefix}': {new_pkgname_list:?}");
-        }
-
-        /*
-        // Copy the packages to the profile repository directory
-        let repo_dir = Path::new(&profile.repo).parent().unwrap();
-        for package_path in packages_to_copy {
-            let package_filename =
-                Path::new(&package_path).file_name().unwrap().to_str().unwrap();
-            let destination_path = repo_dir.join(package_filename);
-
-            log::info!("Copying package from reference repository: {}", package_filename);
-            fs::copy(&package_path, &destination_path)?;
-
-            // Copy the signature file as well
-            let signature_path = format!("{}.sig", package_path);
-            if Path::new(&signature_path).exists() {
-                let destination_signature_path =
-                    format!("{}.sig", destination_path.to_str().unwrap());
-                fs::copy(&signature_path, destination_signature_path)?;
-            }
-        }*/
+        let packages_to_copy =
+            alpm_helper::get_newer_packages_from_reference(&db_pkgs, reference_repo_path)
+                .context("Failed to get newer packages from reference repo")?;
+
+        for (_, reference_pkg) in &packages_to_copy {
+            report.newer_in_reference.push(ReportEntry::from_db_pkg(
+                reference_pkg,
+                &format!("reference repo '{reference_repo_path}' has newer version"),
+            ));
+        }
     }
 
+    report.finish();
     log::info!("Repo checkup is done!");
 
+    Ok(report)
+}
+
+// Compares the repo DBs of the profiles of the group
+fn do_consistency_check(config: &config::Config, group: &str) -> Result<ConsistencyReport> {
+    let profile_names =
+        config.groups.get(group).ok_or(anyhow::anyhow!("Group {group} not found"))?;
+
+    let mut repos = vec![];
+    let mut repo_locks = vec![];
+    for profile_name in profile_names {
+        let profile = get_profile(config, profile_name)?;
+        // the DBs are only read
+        let repo_dir = Path::new(&profile.repo).parent().unwrap().to_str().unwrap().to_owned();
+        repo_locks.push(lock::RepoLock::acquire(
+            &[(repo_dir, lock::LockMode::Shared)],
+            Duration::from_secs(profile.lock_timeout),
+        )?);
+
+        let packages = alpm_helper::read_repo_db(&profile.repo)
+            .with_context(|| format!("Failed to read repo DB of profile '{profile_name}'"))?;
+        repos.push(RepoPackages {
+            profile: profile_name.clone(),
+            arch: profile.arch.clone(),
+            packages,
+        });
+    }
+
+    Ok(ConsistencyReport::check(group, &repos))
+}
+
+// Resolves the dependencies of the repo packages against the repo and the dependency repos
+fn do_deps_check(profile: &config::Profile, executor: &Executor) -> Result<DepsReport> {
+    let db_pkgs = executor.list_entries().context("Failed to read repo DB")?;
+    let upstream_pkgs = read_dep_repos(profile)?;
+
+    let repo_db_prefix = pkg_utils::get_repo_db_prefix(&profile.repo);
+    let report = DepsReport::check(&repo_db_prefix, &db_pkgs, &upstream_pkgs);
+    log::info!("Repo dependency check is done!");
+
+    Ok(report)
+}
+
+// Reads the packages of the dependency repos of the profile
+fn read_dep_repos(profile: &config::Profile) -> Result<Vec<alpm_helper::DbPackage>> {
+    let mut upstream_pkgs = vec![];
+    for dep_repo in &profile.dep_repos {
+        if !Path::new(dep_repo).exists() {
+            anyhow::bail!("Dependency repo DB '{dep_repo}' doesn't exist");
+        }
+        upstream_pkgs.extend(alpm_helper::read_repo_db(dep_repo)?);
+    }
+    Ok(upstream_pkgs)
+}
+
+// Refuses the update which would leave the repo packages with unsatisfied dependencies, the
+// packages which are broken already don't count
+fn check_update_deps(
+    profile: &config::Profile,
+    db_pkgs: &[alpm_helper::DbPackage],
+    added_pkgs: &[(String, pkginfo::PackageInfo)],
+) -> Result<()> {
+    let upstream_pkgs = read_dep_repos(profile)?;
+
+    let mut updated_pkgs = db_pkgs
+        .iter()
+        .filter(|db_pkg| !added_pkgs.iter().any(|(_, x)| x.pkgname == db_pkg.name))
+        .cloned()
+        .collect::<Vec<_>>();
+    for (pkg_filepath, pkginfo) in added_pkgs {
+        let pkg_filename = Path::new(pkg_filepath).file_name().unwrap().to_str().unwrap();
+        updated_pkgs.push(alpm_helper::DbPackage::from_pkginfo(pkginfo, pkg_filename));
+    }
+
+    let newly_broken_pkgs = get_newly_broken_pkgs(db_pkgs, &updated_pkgs, &upstream_pkgs);
+    if !newly_broken_pkgs.is_empty() {
+        anyhow::bail!("The update would leave unsatisfied dependencies: {newly_broken_pkgs:?}");
+    }
     Ok(())
 }
 
-fn do_debug_packages_check(profile: &config::Profile, repo_dir: &Path) -> Result<()> {
-    // 1. check if we have debug repo assigned
-    if profile.debug_dir.is_none() || profile.debug_dir == Some(profile.repo.clone()) {
-        log::info!("Separate debug repo is disabled for this profile");
+// Gets the packages with unsatisfied dependencies after the repo DB changes, along with the
+// missing dependencies. The packages which are broken already don't count
+fn get_newly_broken_pkgs(
+    db_pkgs: &[alpm_helper::DbPackage],
+    updated_pkgs: &[alpm_helper::DbPackage],
+    upstream_pkgs: &[alpm_helper::DbPackage],
+) -> Vec<String> {
+    let all_pkgs = db_pkgs.iter().chain(upstream_pkgs).collect::<Vec<_>>();
+    let broken_pkgs = alpm_helper::get_broken_dependencies(db_pkgs, &all_pkgs);
+    let all_pkgs = updated_pkgs.iter().chain(upstream_pkgs).collect::<Vec<_>>();
+    alpm_helper::get_broken_dependencies(updated_pkgs, &all_pkgs)
+        .into_iter()
+        .filter(|(db_pkg, missing_deps)| {
+            !broken_pkgs.iter().any(|(x, y)| x.name == db_pkg.name && y == missing_deps)
+        })
+        .map(|(db_pkg, missing_deps)| format!("{} {missing_deps:?}", db_pkg.db_pair()))
+        .collect()
+}
+
+// Copies newer versions of the repo packages from the reference repository, and updates the repo
+// with them as usual, e.g replaced versions are handled as outdated ones. Returns the packages
+// which failed the verification or were rejected by the update
+fn do_reference_sync(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    repo_dir: &Path,
+) -> Result<Vec<String>> {
+    let Some(reference_repo_path) = &profile.reference_repo else {
+        anyhow::bail!("Reference repo is not configured for this profile");
+    };
+
+    let db_pkgs = executor.list_entries().context("Failed to read repo DB")?;
+    let packages_to_copy =
+        alpm_helper::get_newer_packages_from_reference(&db_pkgs, reference_repo_path)
+            .context("Failed to get newer packages from reference repo")?;
+
+    let mut synced_pkgs = vec![];
+    let mut rejected_pkgs = vec![];
+    for (package_path, reference_pkg) in &packages_to_copy {
+        let pkg_pair = reference_pkg.db_pair();
+        if !profile.is_reference_pkg_allowed(&reference_pkg.name) {
+            log::info!("Skipping package from reference repo '{pkg_pair}': not allowed");
+            continue;
+        }
+
+        // verify the package against the reference DB before copying it
+        if let Err(verify_err) =
+            verify_reference_pkgfile(profile, executor, package_path, reference_pkg)
+        {
+            log::error!("Skipping package from reference repo '{pkg_pair}': {verify_err}");
+            rejected_pkgs.push(package_path.clone());
+            continue;
+        }
+        if !executor.confirm_item(&format!("Sync '{pkg_pair}' from reference repo?"))? {
+            continue;
+        }
+
+        let dest_path = repo_dir.join(&reference_pkg.filename);
+        let dest_path = dest_path.to_str().unwrap();
+        log::info!("Copying package from reference repository: '{pkg_pair}'");
+        executor.copy_file(package_path, dest_path)?;
+
+        // copy the signature file as well
+        let signature_path = format!("{package_path}.sig");
+        if executor.exists(&signature_path) {
+            executor.copy_file(&signature_path, &format!("{dest_path}.sig"))?;
+        }
+        synced_pkgs.push(pkg_pair);
+    }
+
+    if synced_pkgs.is_empty() {
+        log::info!("No packages to sync from reference repo");
+        return Ok(rejected_pkgs);
+    }
+    log::info!("Synced pkgs from ref repo: {synced_pkgs:?}");
+
+    // add the packages and handle the replaced versions, the rejected ones are quarantined
+    rejected_pkgs.extend(do_repo_update(profile, executor, repo_dir)?);
+    Ok(rejected_pkgs)
+}
+
+// Refuses the update which would leave packages owning the same files, the conflicts between the
+// packages which are in the repo already don't count
+fn check_update_file_conflicts(
+    profile: &config::Profile,
+    executor: &Executor,
+    repo_dir: &Path,
+    db_pkgs: &[alpm_helper::DbPackage],
+    added_pkgs: &[String],
+) -> Result<()> {
+    let added_pkgnames = executor
+        .load_pkginfos(added_pkgs)
+        .into_iter()
+        .map(|(_, pkginfo)| pkginfo.pkgname)
+        .collect::<Vec<_>>();
+    let mut pkg_list = db_pkgs
+        .iter()
+        .filter(|db_pkg| !added_pkgnames.contains(&db_pkg.name))
+        .map(|db_pkg| format!("{}/{}", repo_dir.to_str().unwrap(), db_pkg.filename))
+        .filter(|pkg_filepath| executor.exists(pkg_filepath))
+        .collect::<Vec<_>>();
+    pkg_list.extend(added_pkgs.iter().cloned());
+
+    let repo_filelists = alpm_helper::read_repo_files_db(&profile.repo)?;
+    let pkg_filelists = load_pkg_filelists(executor, &repo_filelists, db_pkgs, &pkg_list);
+    let file_conflicts = alpm_helper::get_file_conflicts(&pkg_filelists)
+        .into_iter()
+        .filter(|(first_pkg, second_pkg, _)| {
+            added_pkgnames.contains(&first_pkg.name) || added_pkgnames.contains(&second_pkg.name)
+        })
+        .map(|(first_pkg, second_pkg, paths)| {
+            format!("'{}' and '{}' {paths:?}", first_pkg.db_pair(), second_pkg.db_pair())
+        })
+        .collect::<Vec<_>>();
+    if !file_conflicts.is_empty() {
+        anyhow::bail!("The update would leave packages owning the same files: {file_conflicts:?}");
+    }
+    Ok(())
+}
+
+// Gets the file lists of the package files, from the files DB of the repo if the package is in
+// there, or from the package archive otherwise
+fn load_pkg_filelists(
+    executor: &Executor,
+    repo_filelists: &HashMap<String, Vec<String>>,
+    db_pkgs: &[alpm_helper::DbPackage],
+    pkg_list: &[String],
+) -> Vec<(alpm_helper::DbPackage, Vec<String>)> {
+    let mut pkg_filelists = vec![];
+    for pkg_filepath in pkg_list {
+        let pkg_filename = Path::new(pkg_filepath).file_name().unwrap().to_str().unwrap();
+        let db_pkg = db_pkgs.iter().find(|db_pkg| db_pkg.filename == pkg_filename);
+        if let Some((db_pkg, filelist)) = db_pkg.zip(repo_filelists.get(pkg_filename)) {
+            pkg_filelists.push((db_pkg.clone(), filelist.clone()));
+            continue;
+        }
+
+        let source_path = executor.source_path(pkg_filepath);
+        let pkg_filelist = executor.read_pkginfo(pkg_filepath).and_then(|pkginfo| {
+            let filelist = pkginfo::read_pkg_filelist(&source_path)?;
+            Ok((alpm_helper::DbPackage::from_pkginfo(&pkginfo, pkg_filename), filelist))
+        });
+        match pkg_filelist {
+            Ok(pkg_filelist) => pkg_filelists.push(pkg_filelist),
+            Err(read_err) => log::warn!("Failed to list files of '{pkg_filepath}': {read_err:#}"),
+        }
+    }
+    pkg_filelists
+}
+
+fn do_repo_rollback(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    repo_dir: &Path,
+    pkgname: &str,
+    target_version: Option<&str>,
+) -> Result<()> {
+    let backup_dir = match &profile.backup_dir {
+        Some(backup_dir) if profile.backup && *backup_dir != profile.repo => backup_dir,
+        _ => anyhow::bail!("Backup is not enabled, there is nothing to roll back to"),
+    };
+
+    // all versions of the package in the repo are replaced by the restored one
+    let pkgs_list = executor.list_pkgfiles(repo_dir.to_str().unwrap())?;
+    let current_pkgs = pkg_utils::get_pkg_versions(&executor.load_pkginfos(&pkgs_list), pkgname);
+    let current_version = current_pkgs.first().map(|(_, version)| version);
+
+    let backup_pkginfos = executor.load_pkginfos(&executor.list_pkgfiles(backup_dir)?);
+    let Some(rollback_pkg) =
+        pkg_utils::get_rollback_pkg(&backup_pkginfos, pkgname, current_version, target_version)
+    else {
+        match (target_version, current_version) {
+            (Some(target_version), _) => anyhow::bail!(
+                "Version '{target_version}' of package '{pkgname}' is not found in the backup dir"
+            ),
+            (None, Some(current_version)) => anyhow::bail!(
+                "No backup of package '{pkgname}' older than '{current_version}' is found"
+            ),
+            (None, None) => anyhow::bail!("No backup of package '{pkgname}' is found"),
+        }
+    };
+
+    // don't restore packages without valid signature
+    executor.check_signature(&rollback_pkg, profile.require_signature).with_context(|| {
+        format!(
+            "Backup '{}' doesn't have valid signature",
+            pkg_utils::get_pkg_db_pair_from_path(&rollback_pkg)
+        )
+    })?;
+
+    let rollback_pkg_entry = pkg_utils::get_pkg_db_pair_from_path(&rollback_pkg);
+    let question = match current_pkgs.first() {
+        Some((current_pkg, _)) => format!(
+            "Roll back '{}' to '{rollback_pkg_entry}'?",
+            pkg_utils::get_pkg_db_pair_from_path(current_pkg)
+        ),
+        None => format!("Restore '{rollback_pkg_entry}'?"),
+    };
+    if !executor.confirm_item(&question)? {
         return Ok(());
     }
 
-    // NOTE: lets just move debug packages into the directory of the repo
-    // don't touch the debug repo DB at all.
+    // the current version goes into the backup, so it can be restored back
+    for (current_pkg, _) in &current_pkgs {
+        log::info!("backup '{}'..", pkg_utils::get_pkg_db_pair_from_path(current_pkg));
+        handle_pkgfile_move(executor, current_pkg, backup_dir)?;
+    }
+
+    log::info!("restore '{rollback_pkg_entry}'..");
+    handle_pkgfile_move(executor, &rollback_pkg, repo_dir.to_str().unwrap())?;
+
+    let rollback_pkg_filename = Path::new(&rollback_pkg).file_name().unwrap().to_str().unwrap();
+    executor.repo_add(&[format!("{}/{rollback_pkg_filename}", repo_dir.to_str().unwrap())])?;
+
+    log::info!("Repo rollback is done!");
+
+    Ok(())
+}
+
+fn do_repo_remove(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    repo_dir: &Path,
+    pkgnames: &[String],
+    force: bool,
+) -> Result<()> {
+    let db_pkgs = executor.list_entries().context("Failed to read repo DB")?;
+    if let Some(pkgname) = pkgnames.iter().find(|x| !db_pkgs.iter().any(|y| y.name == **x)) {
+        anyhow::bail!("Package '{pkgname}' is not in the repo DB");
+    }
+
+    // the rest of the packages must not depend on what only the removed ones provide
+    let upstream_pkgs = read_dep_repos(profile)?;
+    let remaining_pkgs =
+        db_pkgs.iter().filter(|x| !pkgnames.contains(&x.name)).cloned().collect::<Vec<_>>();
+    let broken_pkgs = get_newly_broken_pkgs(&db_pkgs, &remaining_pkgs, &upstream_pkgs);
+    if !broken_pkgs.is_empty() {
+        if !force {
+            anyhow::bail!(
+                "Removing {pkgnames:?} would leave unsatisfied dependencies: {broken_pkgs:?}, use \
+                 --force to remove anyway"
+            );
+        }
+        log::warn!("Removing {pkgnames:?} leaves unsatisfied dependencies: {broken_pkgs:?}");
+    }
+
+    executor.repo_remove(pkgnames)?;
 
-    // 2. get all debug packages in the repo it self, to move them into the debug directory
-    let pkgs_list = glob::glob(&format!("{}/*-debug-*.pkg.tar.zst", repo_dir.to_str().unwrap()))?
-        .map(|x| x.unwrap().to_str().unwrap().to_owned())
+    // the package files are backed up or removed the same way as outdated ones
+    let pkginfos = executor.load_pkginfos(&executor.list_pkgfiles(repo_dir.to_str().unwrap())?);
+    let removed_pkgs = pkgnames
+        .iter()
+        .flat_map(|pkgname| pkg_utils::get_pkg_versions(&pkginfos, pkgname))
+        .map(|(pkg_filepath, _)| pkg_filepath)
         .collect::<Vec<_>>();
+    handle_outdated_pkgs(profile, executor, &removed_pkgs)?;
 
-    // // the debug_dir is the parent dir without the repo
-    // if let Some(debug_dir) = &profile.debug_dir {
-    //     let debug_pkgs_list =
-    //         glob::glob(&format!("{}/*-debug-*.pkg.tar.zst",
-    // profile.debug_dir.as_ref().unwrap()))?             .map(|x|
-    // x.unwrap().to_str().unwrap().to_owned())             .collect::<Vec<_>>();
+    log::info!("Repo remove is done!");
 
-    //     pkgs_list.append(&mut debug_pkgs_list);
-    // }
+    Ok(())
+}
 
-    // TODO(vnepogodin): make a prompt on every run here in case iteractive is on
-    for pkg_to_move in &pkgs_list
-    // .iter().map(|x| Path::new(x))
-    {
+fn verify_reference_pkgfile(
+    profile: &config::Profile,
+    executor: &Executor,
+    package_path: &str,
+    reference_pkg: &alpm_helper::DbPackage,
+) -> Result<()> {
+    executor.check_signature(package_path, profile.require_signature)?;
+    if !reference_pkg.sha256sum.is_empty() {
+        let sha256sum = repo_db::get_file_sha256(package_path)?;
+        if sha256sum != reference_pkg.sha256sum {
+            anyhow::bail!(
+                "checksum mismatch, expected '{}' got '{sha256sum}'",
+                reference_pkg.sha256sum
+            );
+        }
+    }
+    Ok(())
+}
+
+fn do_debug_packages_check(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    repo_dir: &Path,
+) -> Result<()> {
+    // 1. check if we have debug repo assigned
+    if profile.debug_profile().is_none() {
+        log::debug!("Separate debug repo is disabled for this profile");
+        return Ok(());
+    }
+    let debug_dir = profile.debug_dir.as_ref().unwrap();
+
+    // 2. get all debug packages in the repo it self, to move them into the debug directory.
+    // the debug repo DB is updated separately after the repo, so entries of the moved packages
+    // are removed from the repo DB as stale ones
+    let pkginfos = executor.load_pkginfos(&executor.list_pkgfiles(repo_dir.to_str().unwrap())?);
+    for pkg_to_move in pkg_utils::get_debug_packages(&pkginfos) {
         let pkg_pair = pkg_utils::get_pkg_db_pair_from_path(&pkg_to_move);
         log::debug!("Found debug package in repo: {pkg_pair}");
-        // log::debug!("Moving debug package into debug dir: {pkg_to_move}");
-        // if let Err(file_err) = fs::rename_file(filepath) {
-        //     log::error!("Failed to move the debug package '{filepath}': {file_err}");
-        // }
+        if !executor.confirm_item(&format!("Move debug package '{pkg_pair}' into debug dir?"))? {
+            continue;
+        }
+        handle_pkgfile_move(executor, &pkg_to_move, debug_dir)?;
     }
 
     Ok(())
 }
 
+// Updates the companion debug repo DB in the debug dir, the debug packages have their own
+// outdated and backup handling there
+fn do_debug_repo_update<'a>(
+    debug_profile: &config::Profile,
+    executor: &mut Executor<'a>,
+    debug_backend: &'a mut dyn repo_utils::RepoBackend,
+    reset: bool,
+) -> Result<()> {
+    let debug_dir = Path::new(&debug_profile.repo).parent().unwrap();
+    log::info!("Handling debug repo '{}'..", debug_profile.repo);
+
+    let repo_backend = executor.replace_backend(debug_backend);
+    let result = if reset {
+        do_repo_reset(debug_profile, executor, debug_dir)
+    } else {
+        // the rejected debug packages are quarantined, and don't fail the main repo update
+        do_repo_update(debug_profile, executor, debug_dir).map(|_| ())
+    };
+    executor.replace_backend(repo_backend);
+
+    result
+}
+
 // Runs through the backup folder, and removes the backup of versions which we don't want to keep
-fn do_backup_repo_cleanup(profile: &config::Profile) -> Result<()> {
+fn do_backup_repo_cleanup(profile: &config::Profile, executor: &mut Executor) -> Result<()> {
     if !profile.backup || profile.backup_dir == Some(profile.repo.clone()) {
         log::info!("Backup is disabled for this repo");
         return Ok(());
@@ -348,30 +1211,33 @@ fn do_backup_repo_cleanup(profile: &config::Profile) -> Result<()> {
     }
 
     // lets get all packages in the repo it self and the debug repo folder
-    let pkgs_list = glob::glob(&format!("{}/*.pkg.tar.zst", profile.backup_dir.as_ref().unwrap()))?
-        .map(|x| x.unwrap().to_str().unwrap().to_owned())
-        .collect::<Vec<_>>();
+    let pkgs_list = executor.list_pkgfiles(profile.backup_dir.as_ref().unwrap())?;
 
-    let mut pkg_map =
-        pkg_utils::get_stale_pkg_versions(&pkgs_list, *profile.backup_num.as_ref().unwrap());
+    let mut pkg_map = pkg_utils::get_stale_pkg_versions(
+        &executor.load_pkginfos(&pkgs_list),
+        *profile.backup_num.as_ref().unwrap(),
+    );
     for (name, versions) in pkg_map.iter_mut() {
         // Remove the packages with more than N versions
         let pkg_versions = versions.iter().map(|x| x.1.to_string()).collect::<Vec<_>>();
         log::info!("Found more backup versions of package({name}) than allowed: {pkg_versions:?}");
 
-        // TODO(vnepogodin): make a prompt on every run here in case iteractive is on
         for filepath in versions.iter().map(|x| &x.0) {
+            let pkg_pair = pkg_utils::get_pkg_db_pair_from_path(filepath);
+            if !executor.confirm_item(&format!("Remove backup of '{pkg_pair}'?"))? {
+                continue;
+            }
             log::debug!("Removing package version: {filepath}");
 
             // remove the actual package file
-            if let Err(file_err) = fs::remove_file(filepath) {
+            if let Err(file_err) = executor.remove_file(filepath) {
                 log::error!("Failed to remove the backup file '{filepath}': {file_err}");
             }
 
             // remove package signature
             let sig_filepath = format!("{filepath}.sig");
-            if Path::new(&sig_filepath).exists() {
-                if let Err(file_err) = fs::remove_file(&sig_filepath) {
+            if executor.exists(&sig_filepath) {
+                if let Err(file_err) = executor.remove_file(&sig_filepath) {
                     log::error!(
                         "Failed to remove the backup file sig '{sig_filepath}': {file_err}"
                     );
@@ -385,59 +1251,141 @@ fn do_backup_repo_cleanup(profile: &config::Profile) -> Result<()> {
     Ok(())
 }
 
-// Transfers packages from one repo to another repo
-// 1. moves package files in the src repo to the dest repo
-// 2. removes packages from the src repo DB
-// 3. adds packages to the dest repo DB
-fn move_packages_from_repo_to_repo(src_repo_path: &str, dest_repo_path: &str) -> Result<()> {
-    // 1. moving packages from src dir
-    let src_repo_dir = Path::new(src_repo_path).parent().expect("Failed to get parent dir");
-    let dest_repo_dir = Path::new(dest_repo_path).parent().expect("Failed to get parent dir");
+fn do_repo_promote<'a>(
+    source_profile: &config::Profile,
+    profile: &config::Profile,
+    executor: &mut Executor<'a>,
+    source_backend: &'a mut dyn repo_utils::RepoBackend,
+    pkgnames: &[String],
+) -> Result<()> {
+    if source_profile.repo == profile.repo {
+        anyhow::bail!("Cannot promote packages into the same repo '{}'", profile.repo);
+    }
+    let source_dir = Path::new(&source_profile.repo).parent().unwrap().to_str().unwrap();
+    let repo_dir = Path::new(&profile.repo).parent().unwrap().to_str().unwrap();
 
-    // here we get only packages without signature
-    let pkg_to_move_list =
-        glob::glob(&format!("{}/*.pkg.tar.zst", src_repo_dir.to_str().unwrap()))?
-            .map(|x| x.unwrap().to_str().unwrap().to_owned())
-            .collect::<Vec<_>>();
+    // only the latest versions are promoted
+    let mut source_pkginfos = executor.load_pkginfos(&executor.list_pkgfiles(source_dir)?);
+    let outdated_pkgs = pkg_utils::get_outdated_pkgs(&source_pkginfos);
+    source_pkginfos.retain(|(pkg, _)| !outdated_pkgs.contains(pkg));
 
-    // NOTE: probably we would rather want here to see filenames instead of full paths
-    log::info!("Found packages to move in src dir: {pkg_to_move_list:?}");
+    for pkgname in pkgnames {
+        if !source_pkginfos.iter().any(|(_, x)| x.pkgname == *pkgname || x.pkgbase == *pkgname) {
+            anyhow::bail!("Package '{pkgname}' is not found in the repo '{}'", source_profile.repo);
+        }
+    }
 
-    if let Err(pkg_move_err) =
-        handle_pkgfiles_move(&pkg_to_move_list, dest_repo_dir.to_str().unwrap())
-    {
-        log::error!("Error occured while moving package files: {pkg_move_err}");
+    // split packages are promoted together, so the whole pkgbase of the selected ones is taken
+    let mut pkgbases = source_pkginfos
+        .iter()
+        .filter(|(_, x)| {
+            pkgnames.is_empty() || pkgnames.contains(&x.pkgname) || pkgnames.contains(&x.pkgbase)
+        })
+        .map(|(_, x)| x.pkgbase.clone())
+        .collect::<Vec<_>>();
+    pkgbases.sort();
+    pkgbases.dedup();
+
+    let db_pkgs = executor.list_entries().context("Failed to read repo DB")?;
+    let mut promoted_pkgs = vec![];
+    for pkgbase in &pkgbases {
+        let pkgbase_pkgs =
+            source_pkginfos.iter().filter(|(_, x)| x.pkgbase == *pkgbase).collect::<Vec<_>>();
+        let pkg_pairs = pkgbase_pkgs.iter().map(|(_, x)| x.db_pair()).collect::<Vec<_>>();
+
+        // destination repo decides whether the signature is required, and must not be downgraded
+        for (pkg_filepath, pkginfo) in &pkgbase_pkgs {
+            executor.check_signature(pkg_filepath, profile.require_signature).with_context(
+                || format!("Package '{}' doesn't have valid signature", pkginfo.db_pair()),
+            )?;
+            check_pkg_version(profile, executor, &db_pkgs, pkg_filepath)
+                .with_context(|| format!("Cannot promote '{}'", pkginfo.db_pair()))?;
+        }
+        if !executor.confirm_item(&format!("Promote {pkg_pairs:?}?"))? {
+            continue;
+        }
+        promoted_pkgs.extend(pkgbase_pkgs);
+    }
+    if promoted_pkgs.is_empty() {
+        log::info!("Nothing to promote");
         return Ok(());
     }
 
-    // TODO(vnepogodin): modify source repo DB (e.g remove the moved packages from the db)
+    let promoted_pkgnames =
+        promoted_pkgs.iter().map(|(_, x)| x.pkgname.clone()).collect::<Vec<_>>();
+    let promoted_files = promoted_pkgs.iter().map(|(x, _)| x.clone()).collect::<Vec<_>>();
+    let moved_files = promoted_files
+        .iter()
+        .map(|x| format!("{repo_dir}/{}", Path::new(x).file_name().unwrap().to_str().unwrap()))
+        .collect::<Vec<_>>();
+    let replaced_pkgs = executor
+        .load_pkginfos(&executor.list_pkgfiles(repo_dir)?)
+        .into_iter()
+        .filter(|(x, y)| promoted_pkgnames.contains(&y.pkgname) && !moved_files.contains(x))
+        .map(|(pkg_filepath, _)| pkg_filepath)
+        .collect::<Vec<_>>();
+
+    handle_pkgfiles_move(executor, &promoted_files, repo_dir)?;
+    executor.repo_add(&moved_files)?;
 
-    log::info!("Repo MovePkgsFromRepo2Repo is done!");
+    // whatever the promoted packages replace is handled by the destination backup policy
+    handle_outdated_pkgs(profile, executor, &replaced_pkgs)?;
 
+    let repo_backend = executor.replace_backend(source_backend);
+    let result = executor.repo_remove(&promoted_pkgnames);
+    executor.replace_backend(repo_backend);
+    result?;
+
+    log::info!("Repo promote is done!");
+
+    Ok(())
+}
+
+// Refuses to proceed with the same package version in several compressions,
+// as it is not clear which one of them to keep
+fn check_compression_conflicts(pkgs_list: &[String]) -> Result<()> {
+    let conflicts = pkg_utils::get_compression_conflicts(pkgs_list);
+    for conflicting_pkgs in &conflicts {
+        log::error!(
+            "Found the same package version with different compression: {conflicting_pkgs:?}"
+        );
+    }
+    if !conflicts.is_empty() {
+        anyhow::bail!("Cannot proceed with conflicting package files, keep only one of each");
+    }
     Ok(())
 }
 
-fn handle_outdated_pkgs(profile: &config::Profile, outdated_pkgs: &[String]) -> Result<()> {
+fn handle_outdated_pkgs(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    outdated_pkgs: &[String],
+) -> Result<()> {
     // 1. handle removal/backup here
     log::debug!("outdated_pkgs := {outdated_pkgs:?}");
     for outdated_pkg in outdated_pkgs {
         let outdated_pkg_entry = pkg_utils::get_pkg_db_pair_from_path(outdated_pkg);
 
-        // TODO(vnepogodin): make a prompt on every run here in case iteractive is on
         if profile.backup && profile.backup_dir != Some(profile.repo.clone()) {
+            if !executor.confirm_item(&format!("Backup outdated '{outdated_pkg_entry}'?"))? {
+                continue;
+            }
             log::info!("backup '{outdated_pkg_entry}'..");
-            handle_pkgfile_move(outdated_pkg, profile.backup_dir.as_ref().unwrap())?;
+            handle_pkgfile_move(executor, outdated_pkg, profile.backup_dir.as_ref().unwrap())?;
         } else {
+            if !executor.confirm_item(&format!("Remove outdated '{outdated_pkg_entry}'?"))? {
+                continue;
+            }
             log::info!("rm '{outdated_pkg_entry}'..");
             // we would rather be fail safe here and just report without *panicing*
-            if let Err(rm_err) = fs::remove_file(outdated_pkg) {
+            if let Err(rm_err) = executor.remove_file(outdated_pkg) {
                 log::error!("Failed to remove outdated package '{outdated_pkg}': {rm_err}");
             }
 
             // remove package signature
             let sig_filepath = format!("{outdated_pkg}.sig");
-            if Path::new(&sig_filepath).exists() {
-                if let Err(file_err) = fs::remove_file(&sig_filepath) {
+            if executor.exists(&sig_filepath) {
+                if let Err(file_err) = executor.remove_file(&sig_filepath) {
                     log::error!(
                         "Failed to remove outdated package sig '{sig_filepath}': {file_err}"
                     );
@@ -450,13 +1398,179 @@ fn handle_outdated_pkgs(profile: &config::Profile, outdated_pkgs: &[String]) ->
     // to not spam the log with needless run
     if profile.backup {
         // lets run just regular backup cleanup
-        do_backup_repo_cleanup(profile)?;
+        do_backup_repo_cleanup(profile, executor)?;
+    }
+
+    Ok(())
+}
+
+// Checks the package which is going to be added into the repo: its signature, whether the
+// packager is allowed to publish into the repo, and its version against the repo DB
+fn check_incoming_pkg(
+    profile: &config::Profile,
+    executor: &Executor,
+    db_pkgs: &[alpm_helper::DbPackage],
+    pkg_filepath: &str,
+) -> Result<()> {
+    // the signature is needed to check the signer
+    let require_signature = profile.require_signature || !profile.allowed_signers.is_empty();
+    let signer = executor.check_signature(pkg_filepath, require_signature)?;
+    check_pkg_packager(profile, executor, pkg_filepath, signer.as_deref())?;
+    check_pkg_version(profile, executor, db_pkgs, pkg_filepath)
+}
+
+// Compares the package with the version in the repo DB: downgrades are refused unless allowed for
+// the package, and the same version must have the same content
+fn check_pkg_version(
+    profile: &config::Profile,
+    executor: &Executor,
+    db_pkgs: &[alpm_helper::DbPackage],
+    pkg_filepath: &str,
+) -> Result<()> {
+    let source_path = executor.source_path(pkg_filepath);
+    let pkginfo = executor
+        .load_pkginfo(pkg_filepath)
+        .ok_or(anyhow::anyhow!("Failed to get package metadata"))?;
+    let Some(db_pkg) = db_pkgs.iter().find(|x| x.name == pkginfo.pkgname) else {
+        return Ok(());
+    };
+
+    match pkginfo.version().vercmp(db_pkg.version()) {
+        Ordering::Less if !profile.is_downgrade_allowed(&pkginfo.pkgname) => anyhow::bail!(
+            "downgrade from '{}' to '{}' is not allowed",
+            db_pkg.version,
+            pkginfo.pkgver
+        ),
+        Ordering::Equal if !db_pkg.sha256sum.is_empty() => {
+            let sha256sum = repo_db::get_file_sha256(&source_path)?;
+            if sha256sum != db_pkg.sha256sum {
+                anyhow::bail!(
+                    "version '{}' is in the repo DB already with different content",
+                    pkginfo.pkgver
+                );
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+// Checks the packager of the package and the verified signing key against the allowlists of the
+// profile
+fn check_pkg_packager(
+    profile: &config::Profile,
+    executor: &Executor,
+    pkg_filepath: &str,
+    signer: Option<&str>,
+) -> Result<()> {
+    if !profile.allowed_packagers.is_empty() {
+        let pkginfo = executor.read_pkginfo(pkg_filepath)?;
+        if !profile.is_packager_allowed(&pkginfo.packager) {
+            anyhow::bail!("packager '{}' is not allowed", pkginfo.packager);
+        }
+    }
+    if !profile.is_signer_allowed(signer) {
+        match signer {
+            Some(signer) => anyhow::bail!("signing key {signer} is not allowed"),
+            None => anyhow::bail!("signature is not verified, keyring is not configured"),
+        }
+    }
+    Ok(())
+}
+
+// Checks the package which is going to be moved into the repo, in addition to the checks of the
+// incoming packages: the metadata must be readable, and the arch must match the repo
+fn check_pkg_to_move(
+    profile: &config::Profile,
+    executor: &Executor,
+    db_pkgs: &[alpm_helper::DbPackage],
+    pkg_filepath: &str,
+) -> Result<()> {
+    let pkginfo = executor.read_pkginfo(pkg_filepath)?;
+    if let Some(arch) =
+        profile.arch.as_ref().filter(|x| pkginfo.arch != "any" && pkginfo.arch != **x)
+    {
+        anyhow::bail!("arch '{}' doesn't match the repo arch '{arch}'", pkginfo.arch);
+    }
+    check_incoming_pkg(profile, executor, db_pkgs, pkg_filepath)
+}
+
+// Rejects all incoming packages of the pkgbases which would be left at different versions
+fn reject_partial_pkgbases(
+    db_pkgs: &[alpm_helper::DbPackage],
+    incoming_pkgs: &[(String, pkginfo::PackageInfo)],
+    available_pkgs: &[(String, pkginfo::PackageInfo)],
+    rejected_pkgs: &mut Vec<(String, anyhow::Error)>,
+) {
+    for (pkgbase, reason) in
+        alpm_helper::get_partial_pkgbases(db_pkgs, incoming_pkgs, available_pkgs)
+    {
+        for (pkg_filepath, _) in incoming_pkgs.iter().filter(|(_, x)| x.pkgbase == pkgbase) {
+            rejected_pkgs.push((pkg_filepath.clone(), anyhow::anyhow!("{reason}")));
+        }
+    }
+}
+
+// Looks for the incoming packages which drop sonames still needed by the packages of the repo
+// or of the sibling profiles. Depending on the profile they are either logged or rejected
+// together with the rest of their pkgbase
+fn check_soname_breakages(
+    profile: &config::Profile,
+    db_pkgs: &[alpm_helper::DbPackage],
+    incoming_pkgs: &[(String, pkginfo::PackageInfo)],
+    rejected_pkgs: &mut Vec<(String, anyhow::Error)>,
+) -> Result<()> {
+    if incoming_pkgs.is_empty() {
+        return Ok(());
+    }
+    let mut sibling_dbs = vec![];
+    for sibling_repo in profile.sibling_repos.iter().filter(|x| Path::new(x).exists()) {
+        sibling_dbs.push(alpm_helper::read_repo_db(sibling_repo)?);
     }
 
+    for soname_breakage in alpm_helper::get_soname_breakages(db_pkgs, incoming_pkgs, &sibling_dbs) {
+        let reason = format!(
+            "'{}' drops soname '{}' which is still required by {:?}",
+            soname_breakage.pkgname, soname_breakage.soname, soname_breakage.dependents
+        );
+        if profile.soname_check == config::SonameCheck::Warn {
+            log::warn!("{reason}");
+            continue;
+        }
+        let pkgbase = incoming_pkgs
+            .iter()
+            .find(|(x, _)| *x == soname_breakage.pkg_filepath)
+            .map(|(_, pkginfo)| pkginfo.pkgbase.as_str())
+            .unwrap_or_default();
+        for (pkg_filepath, _) in incoming_pkgs.iter().filter(|(_, x)| x.pkgbase == pkgbase) {
+            if !rejected_pkgs.iter().any(|(x, _)| x == pkg_filepath) {
+                rejected_pkgs.push((pkg_filepath.clone(), anyhow::anyhow!("{reason}")));
+            }
+        }
+    }
     Ok(())
 }
 
-fn handle_pkgfile_move(pkg_to_move: &str, dest_dir: &str) -> Result<()> {
+// Moves the rejected package with its signature into the quarantine dir, the reason is written
+// beside it. The package is left in place if there is no quarantine dir
+fn quarantine_pkg(
+    profile: &config::Profile,
+    executor: &mut Executor,
+    pkg_filepath: &str,
+    reason: &str,
+) -> Result<()> {
+    let pkg_db_entry = pkg_utils::get_pkg_db_pair_from_path(pkg_filepath);
+    log::error!("Rejected package '{pkg_db_entry}': {reason}");
+    let Some(quarantine_dir) = &profile.quarantine_dir else {
+        return Ok(());
+    };
+    handle_pkgfile_move(executor, pkg_filepath, quarantine_dir)?;
+
+    let pkg_filename = Path::new(pkg_filepath).file_name().unwrap().to_str().unwrap();
+    executor.write_file(&format!("{quarantine_dir}/{pkg_filename}.reason"), &format!("{reason}\n"))
+}
+
+fn handle_pkgfile_move(executor: &mut Executor, pkg_to_move: &str, dest_dir: &str) -> Result<()> {
     let pkg_filename = Path::new(&pkg_to_move).file_name().unwrap().to_str().unwrap();
     let dest_path = format!("{}/{pkg_filename}", dest_dir);
 
@@ -467,14 +1581,14 @@ fn handle_pkgfile_move(pkg_to_move: &str, dest_dir: &str) -> Result<()> {
     // NOTE: maybe we should handle move part better?
 
     // moving package
-    if let Err(move_err) = fs::rename(pkg_to_move, &dest_path) {
+    if let Err(move_err) = executor.move_file(pkg_to_move, &dest_path) {
         anyhow::bail!("Failed to move pkg: {move_err}");
     }
     // moving package signature
     let pkg_sig_to_move = format!("{pkg_to_move}.sig");
     let sig_dest_path = format!("{dest_path}.sig");
-    if Path::new(&pkg_sig_to_move).exists() {
-        if let Err(move_err) = fs::rename(pkg_sig_to_move, &sig_dest_path) {
+    if executor.exists(&pkg_sig_to_move) {
+        if let Err(move_err) = executor.move_file(&pkg_sig_to_move, &sig_dest_path) {
             log::error!("Failed to move pkg signature: {move_err}");
         }
     }
@@ -482,11 +1596,1012 @@ fn handle_pkgfile_move(pkg_to_move: &str, dest_dir: &str) -> Result<()> {
     Ok(())
 }
 
-fn handle_pkgfiles_move(pkg_to_move_list: &[String], dest_dir: &str) -> Result<()> {
+fn handle_pkgfiles_move(
+    executor: &mut Executor,
+    pkg_to_move_list: &[String],
+    dest_dir: &str,
+) -> Result<()> {
     // now lets move
     for pkg_to_move in pkg_to_move_list {
-        handle_pkgfile_move(pkg_to_move, dest_dir)?;
+        handle_pkgfile_move(executor, pkg_to_move, dest_dir)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use crate::repo_utils::FakeBackend;
+    use crate::*;
+
+    fn create_pkgfiles(dir: &str, pkgfiles: &[&str], with_sig: bool) {
+        for pkgfile in pkgfiles {
+            let pkginfo = pkginfo::get_pkginfo_from_filename(pkgfile).unwrap();
+            let pkginfo_content = format!(
+                "pkgname = {}\npkgver = {}\narch = {}\n",
+                pkginfo.pkgname, pkginfo.pkgver, pkginfo.arch
+            );
+            utils::create_test_pkg(&format!("{dir}/{pkgfile}"), &[(".PKGINFO", &pkginfo_content)]);
+            if with_sig {
+                fs::write(format!("{dir}/{pkgfile}.sig"), "").unwrap();
+            }
+        }
+    }
+
+    // creates the signed package file with additional .PKGINFO lines, e.g dependencies
+    fn create_pkgfile_with_metadata(dir: &str, pkgfile: &str, metadata: &[&str]) -> String {
+        create_pkgfile_with_files(dir, pkgfile, metadata, &[])
+    }
+
+    fn create_pkgfile_with_files(
+        dir: &str,
+        pkgfile: &str,
+        metadata: &[&str],
+        files: &[&str],
+    ) -> String {
+        let pkginfo = pkginfo::get_pkginfo_from_filename(pkgfile).unwrap();
+        let pkginfo_content = format!(
+            "pkgname = {}\npkgver = {}\narch = {}\n{}",
+            pkginfo.pkgname,
+            pkginfo.pkgver,
+            pkginfo.arch,
+            metadata.iter().map(|x| format!("{x}\n")).collect::<String>()
+        );
+        let pkg_filepath = format!("{dir}/{pkgfile}");
+        let mut entries = vec![(".PKGINFO", pkginfo_content.as_str())];
+        entries.extend(files.iter().map(|file| (*file, "")));
+        utils::create_test_pkg(&pkg_filepath, &entries);
+        fs::write(format!("{pkg_filepath}.sig"), "").unwrap();
+        pkg_filepath
+    }
+
+    fn create_test_profile(repo_dir: &str) -> config::Profile {
+        config::Profile {
+            repo: format!("{repo_dir}/repof.db.tar.zst"),
+            require_signature: true,
+            // the test signatures are empty files
+            allow_unverified_signatures: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_repo_update() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let backup_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(
+            &repo_dir,
+            &["dash-0.5.12-1-x86_64.pkg.tar.zst", "dash-0.5.12-2-x86_64.pkg.tar.zst"],
+            true,
+        );
+        // brand new package without signature must not be added
+        create_pkgfiles(&repo_dir, &["st-0.8.4-2-x86_64.pkg.tar.zst"], false);
+
+        let profile = config::Profile {
+            backup: true,
+            backup_dir: Some(backup_dir.clone()),
+            ..create_test_profile(&repo_dir)
+        };
+        let mut backend = FakeBackend::with_entries(&[
+            "dash-0.5.12-1-x86_64.pkg.tar.zst",
+            "dwm-6.2-4-x86_64.pkg.tar.zst",
+        ]);
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+
+        assert_eq!(backend.added, vec![format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst")]);
+        assert_eq!(backend.removed, vec!["dwm".to_owned()]);
+        assert_eq!(backend.entries.keys().collect::<Vec<_>>(), vec!["dash"]);
+
+        // the outdated package is moved with its signature into the backup dir
+        assert!(!Path::new(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")).exists());
+        assert!(Path::new(&format!("{backup_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")).exists());
+        assert!(Path::new(&format!("{backup_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst.sig")).exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(backup_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_update_compressions() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(
+            &repo_dir,
+            &["dash-0.5.12-1-x86_64.pkg.tar.xz", "dwm-6.2-4-x86_64.pkg.tar.gz"],
+            true,
+        );
+
+        let profile = create_test_profile(&repo_dir);
+        let mut backend = FakeBackend::default();
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+        assert_eq!(backend.added, vec![
+            format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.xz"),
+            format!("{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.gz"),
+        ]);
+
+        // the same version with another compression is refused
+        create_pkgfiles(&repo_dir, &["dash-0.5.12-1-x86_64.pkg.tar.zst"], true);
+        let mut backend = FakeBackend::default();
+        let mut executor = Executor::new(&mut backend, false);
+        assert!(do_repo_update(&profile, &mut executor, Path::new(&repo_dir)).is_err());
+        assert!(do_repo_reset(&profile, &mut executor, Path::new(&repo_dir)).is_err());
+        assert!(executor.plan().is_empty());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_update_downgrade() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(
+            &repo_dir,
+            &[
+                "dash-0.5.12-1-x86_64.pkg.tar.zst",
+                "dash-0.5.12-2-x86_64.pkg.tar.zst",
+                "dwm-6.2-3-x86_64.pkg.tar.zst",
+                "dwm-6.2-4-x86_64.pkg.tar.zst",
+            ],
+            true,
+        );
+
+        // the file of the newer DB version is gone, and the one of the same version is rebuilt
+        let mut backend = FakeBackend::with_entries(&[
+            &format!("{repo_dir}/dash-0.5.12-3-x86_64.pkg.tar.zst"),
+            &format!("{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.zst"),
+        ]);
+        backend.entries.get_mut("dwm").unwrap().sha256sum = "0".repeat(64);
+        let profile = create_test_profile(&repo_dir);
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+        assert!(backend.added.is_empty());
+
+        // unless the downgrade is allowed
+        let profile = config::Profile { allow_downgrade: vec!["dash".to_owned()], ..profile };
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+        assert_eq!(backend.added, vec![format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst")]);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_update_split_pkgs() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let create_split_pkg = |pkgname: &str, pkgver: &str| {
+            let pkg_filepath = format!("{repo_dir}/{pkgname}-{pkgver}-x86_64.pkg.tar.zst");
+            let pkginfo_content = format!(
+                "pkgname = {pkgname}\npkgbase = linux-cachyos\npkgver = {pkgver}\narch = x86_64\n"
+            );
+            utils::create_test_pkg(&pkg_filepath, &[(".PKGINFO", &pkginfo_content)]);
+            fs::write(format!("{pkg_filepath}.sig"), "").unwrap();
+            pkg_filepath
+        };
+        let mut backend = FakeBackend::with_entries(&[
+            &create_split_pkg("linux-cachyos", "6.10.5-1"),
+            &create_split_pkg("linux-cachyos-headers", "6.10.5-1"),
+        ]);
+
+        // the headers are not uploaded yet
+        let kernel_pkg = create_split_pkg("linux-cachyos", "6.10.6-1");
+        let profile = create_test_profile(&repo_dir);
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+        assert!(backend.added.is_empty());
+
+        let headers_pkg = create_split_pkg("linux-cachyos-headers", "6.10.6-1");
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+        assert_eq!(backend.added, vec![kernel_pkg, headers_pkg]);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_update_check_deps() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let mut backend = FakeBackend::with_entries(&[
+            &create_pkgfile_with_metadata(&repo_dir, "pacman-7.0.0-1-x86_64.pkg.tar.zst", &[
+                "depend = libalpm.so=15-64",
+                "depend = glibc",
+            ]),
+            &create_pkgfile_with_metadata(&repo_dir, "libalpm-15.0.0-1-x86_64.pkg.tar.zst", &[
+                "provides = libalpm.so=15-64",
+            ]),
+        ]);
+        let profile = config::Profile { check_deps: true, ..create_test_profile(&repo_dir) };
+
+        // glibc comes from upstream, which isn't configured
+        let report = do_deps_check(&profile, &Executor::new(&mut backend, false)).unwrap();
+        assert!(!report.clean);
+        assert_eq!(report.broken[0].name, "pacman");
+        assert_eq!(report.broken[0].missing, vec!["glibc".to_owned()]);
+
+        // the new soname breaks pacman
+        create_pkgfile_with_metadata(&repo_dir, "libalpm-16.0.0-1-x86_64.pkg.tar.zst", &[
+            "provides = libalpm.so=16-64",
+        ]);
+        let update_result =
+            do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir));
+        assert!(update_result.unwrap_err().to_string().contains("pacman-7.0.0-1"));
+        assert!(backend.added.is_empty());
+
+        // unless pacman is rebuilt as well
+        let pacman_pkg =
+            create_pkgfile_with_metadata(&repo_dir, "pacman-7.0.0-2-x86_64.pkg.tar.zst", &[
+                "depend = libalpm.so=16-64",
+                "depend = glibc",
+            ]);
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+        assert_eq!(backend.added, vec![
+            format!("{repo_dir}/libalpm-16.0.0-1-x86_64.pkg.tar.zst"),
+            pacman_pkg
+        ]);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_update_soname_check() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let quarantine_dir = utils::create_temporary_directory(None).unwrap();
+        let mut backend = FakeBackend::with_entries(&[
+            &create_pkgfile_with_metadata(&repo_dir, "pacman-7.0.0-1-x86_64.pkg.tar.zst", &[
+                "depend = libalpm.so=15-64",
+            ]),
+            &create_pkgfile_with_metadata(&repo_dir, "libalpm-15.0.0-1-x86_64.pkg.tar.zst", &[
+                "provides = libalpm.so=15-64",
+            ]),
+        ]);
+        create_pkgfile_with_metadata(&repo_dir, "libalpm-16.0.0-1-x86_64.pkg.tar.zst", &[
+            "provides = libalpm.so=16-64",
+        ]);
+
+        // the package is left in place without the quarantine dir
+        let profile = config::Profile {
+            soname_check: config::SonameCheck::Block,
+            ..create_test_profile(&repo_dir)
+        };
+        let rejected_pkgs =
+            do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+                .unwrap();
+        assert_eq!(rejected_pkgs, vec![format!("{repo_dir}/libalpm-16.0.0-1-x86_64.pkg.tar.zst")]);
+        assert!(backend.added.is_empty());
+
+        // the breakage is only logged by default
+        let warn_profile =
+            config::Profile { soname_check: config::SonameCheck::Warn, ..profile.clone() };
+        let mut executor = Executor::new(&mut backend, true);
+        do_repo_update(&warn_profile, &mut executor, Path::new(&repo_dir)).unwrap();
+        assert!(executor.plan().contains(&PlanStep::RepoAdd(vec![format!(
+            "{repo_dir}/libalpm-16.0.0-1-x86_64.pkg.tar.zst"
+        )])));
+
+        let profile = config::Profile { quarantine_dir: Some(quarantine_dir.clone()), ..profile };
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+        assert!(backend.added.is_empty());
+        let reason = fs::read_to_string(format!(
+            "{quarantine_dir}/libalpm-16.0.0-1-x86_64.pkg.tar.zst.reason"
+        ))
+        .unwrap();
+        assert!(reason.contains("libalpm.so=15-64"));
+        assert!(reason.contains("pacman-7.0.0-1"));
+
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(quarantine_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_remove() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let backup_dir = utils::create_temporary_directory(None).unwrap();
+        let libalpm_pkg =
+            create_pkgfile_with_metadata(&repo_dir, "libalpm-15.0.0-1-x86_64.pkg.tar.zst", &[
+                "provides = libalpm.so=15-64",
+            ]);
+        let mut backend = FakeBackend::with_entries(&[
+            &create_pkgfile_with_metadata(&repo_dir, "pacman-7.0.0-1-x86_64.pkg.tar.zst", &[
+                "depend = libalpm.so=15-64",
+            ]),
+            &libalpm_pkg,
+        ]);
+        let profile = config::Profile {
+            backup: true,
+            backup_dir: Some(backup_dir.clone()),
+            ..create_test_profile(&repo_dir)
+        };
+        let repo_path = Path::new(&repo_dir);
+        let remove_pkgs = |backend: &mut FakeBackend, pkgnames: &[&str], force: bool| {
+            let pkgnames = pkgnames.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            let mut executor = Executor::new(backend, false);
+            do_repo_remove(&profile, &mut executor, repo_path, &pkgnames, force)
+        };
+
+        assert!(remove_pkgs(&mut backend, &["dash"], false).is_err());
+        // pacman needs the soname only libalpm provides
+        let remove_err = remove_pkgs(&mut backend, &["libalpm"], false).unwrap_err();
+        assert!(remove_err.to_string().contains("pacman-7.0.0-1"));
+        assert!(backend.removed.is_empty());
+        assert!(Path::new(&libalpm_pkg).exists());
+
+        // together with its dependents, or forcibly
+        remove_pkgs(&mut backend, &["libalpm", "pacman"], false).unwrap();
+        assert_eq!(backend.removed, vec!["libalpm".to_owned(), "pacman".to_owned()]);
+        assert!(!Path::new(&libalpm_pkg).exists());
+        assert!(
+            Path::new(&format!("{backup_dir}/libalpm-15.0.0-1-x86_64.pkg.tar.zst.sig")).exists()
+        );
+        assert!(Path::new(&format!("{backup_dir}/pacman-7.0.0-1-x86_64.pkg.tar.zst")).exists());
+
+        let mut backend = FakeBackend::with_entries(&[
+            &create_pkgfile_with_metadata(&repo_dir, "pacman-7.0.0-1-x86_64.pkg.tar.zst", &[
+                "depend = libalpm.so=15-64",
+            ]),
+            &create_pkgfile_with_metadata(&repo_dir, "libalpm-15.0.0-1-x86_64.pkg.tar.zst", &[
+                "provides = libalpm.so=15-64",
+            ]),
+        ]);
+        remove_pkgs(&mut backend, &["libalpm"], true).unwrap();
+        assert_eq!(backend.removed, vec!["libalpm".to_owned()]);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(backup_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_file_conflicts() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let mut backend = FakeBackend::with_entries(&[&create_pkgfile_with_files(
+            &repo_dir,
+            "vim-9.1-1-x86_64.pkg.tar.zst",
+            &[],
+            &["usr/bin/vim", "usr/bin/xxd"],
+        )]);
+        let xxd_pkg = create_pkgfile_with_files(&repo_dir, "xxd-9.1-1-x86_64.pkg.tar.zst", &[], &[
+            "usr/bin/xxd",
+        ]);
+        let profile =
+            config::Profile { check_file_conflicts: true, ..create_test_profile(&repo_dir) };
+
+        let report =
+            do_repo_checkup(&profile, &Executor::new(&mut backend, false), Path::new(&repo_dir))
+                .unwrap();
+        let file_conflicts =
+            report.file_conflicts.iter().map(|x| (x.db_pair(), &x.reason)).collect::<Vec<_>>();
+        assert_eq!(file_conflicts, vec![
+            (
+                "vim-9.1-1".to_owned(),
+                &"owns 1 file(s) of 'xxd-9.1-1', e.g 'usr/bin/xxd'".to_owned()
+            ),
+            (
+                "xxd-9.1-1".to_owned(),
+                &"owns 1 file(s) of 'vim-9.1-1', e.g 'usr/bin/xxd'".to_owned()
+            ),
+        ]);
+
+        let update_result =
+            do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir));
+        assert!(update_result.unwrap_err().to_string().contains("usr/bin/xxd"));
+        assert!(backend.added.is_empty());
+
+        // unless xxd declares the conflict
+        fs::remove_file(&xxd_pkg).unwrap();
+        create_pkgfile_with_files(
+            &repo_dir,
+            "xxd-9.1-1-x86_64.pkg.tar.zst",
+            &["conflict = vim"],
+            &["usr/bin/xxd"],
+        );
+        do_repo_update(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+        assert_eq!(backend.added, vec![xxd_pkg]);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_rollback() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let backup_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(&repo_dir, &["dash-0.5.12-2-x86_64.pkg.tar.zst"], true);
+        create_pkgfiles(
+            &backup_dir,
+            &["dash-0.5.11-1-x86_64.pkg.tar.zst", "dash-0.5.12-1-x86_64.pkg.tar.zst"],
+            true,
+        );
+        // backup without signature must not be restored
+        create_pkgfiles(&backup_dir, &["dash-0.5.10-1-x86_64.pkg.tar.zst"], false);
+
+        let profile = config::Profile {
+            backup: true,
+            backup_dir: Some(backup_dir.clone()),
+            ..create_test_profile(&repo_dir)
+        };
+        let mut backend = FakeBackend::with_entries(&["dash-0.5.12-2-x86_64.pkg.tar.zst"]);
+        let mut executor = Executor::new(&mut backend, false);
+        let repo_path = Path::new(&repo_dir);
+        assert!(
+            do_repo_rollback(&profile, &mut executor, repo_path, "dash", Some("0.5.10")).is_err()
+        );
+        assert!(do_repo_rollback(&profile, &mut executor, repo_path, "st", None).is_err());
+        do_repo_rollback(&profile, &mut executor, repo_path, "dash", None).unwrap();
+
+        // the current version is swapped with the latest older one
+        assert_eq!(backend.added, vec![format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")]);
+        assert!(Path::new(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst.sig")).exists());
+        assert!(!Path::new(&format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst")).exists());
+        assert!(Path::new(&format!("{backup_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst")).exists());
+        assert!(Path::new(&format!("{backup_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst.sig")).exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(backup_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_reset() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(
+            &repo_dir,
+            &[
+                "dash-0.5.12-1-x86_64.pkg.tar.zst",
+                "dash-0.5.12-2-x86_64.pkg.tar.zst",
+                "dwm-6.2-4-x86_64.pkg.tar.zst",
+            ],
+            true,
+        );
+
+        let profile = create_test_profile(&repo_dir);
+        let mut backend = FakeBackend::with_entries(&["st-0.8.4-2-x86_64.pkg.tar.zst"]);
+        do_repo_reset(&profile, &mut Executor::new(&mut backend, false), Path::new(&repo_dir))
+            .unwrap();
+
+        assert_eq!(backend.entries.keys().collect::<Vec<_>>(), vec!["dash", "dwm"]);
+        assert_eq!(backend.entries["dash"].version, "0.5.12-2");
+
+        // without backup the outdated package is removed
+        assert!(!Path::new(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")).exists());
+        assert!(!Path::new(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst.sig")).exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_move_pkgs() {
+        let src_dir = utils::create_temporary_directory(None).unwrap();
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(&src_dir, &["dwm-6.2-4-x86_64.pkg.tar.zst"], true);
+
+        let profile = create_test_profile(&repo_dir);
+        let mut backend = FakeBackend::default();
+        do_repo_move_pkgs(
+            &profile,
+            &mut Executor::new(&mut backend, false),
+            Path::new(&src_dir),
+            Path::new(&repo_dir),
+        )
+        .unwrap();
+
+        assert_eq!(backend.added, vec![format!("{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.zst")]);
+        assert!(Path::new(&format!("{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.zst.sig")).exists());
+        assert!(!Path::new(&format!("{src_dir}/dwm-6.2-4-x86_64.pkg.tar.zst")).exists());
+
+        // packages without signature are not moved at all
+        create_pkgfiles(&src_dir, &["st-0.8.4-2-x86_64.pkg.tar.zst"], false);
+        do_repo_move_pkgs(
+            &profile,
+            &mut Executor::new(&mut backend, false),
+            Path::new(&src_dir),
+            Path::new(&repo_dir),
+        )
+        .unwrap();
+        assert!(Path::new(&format!("{src_dir}/st-0.8.4-2-x86_64.pkg.tar.zst")).exists());
+        assert_eq!(backend.added.len(), 1);
+
+        // the repo is updated even without valid packages to move
+        create_pkgfiles(&repo_dir, &["nano-8.0-1-any.pkg.tar.zst"], true);
+        let rejected_pkgs = do_repo_move_pkgs(
+            &profile,
+            &mut Executor::new(&mut backend, false),
+            Path::new(&src_dir),
+            Path::new(&repo_dir),
+        )
+        .unwrap();
+        assert_eq!(rejected_pkgs, vec![format!("{src_dir}/st-0.8.4-2-x86_64.pkg.tar.zst")]);
+        assert_eq!(backend.added[1..], [format!("{repo_dir}/nano-8.0-1-any.pkg.tar.zst")]);
+
+        fs::remove_dir_all(src_dir).unwrap();
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_move_pkgs_quarantine() {
+        let src_dir = utils::create_temporary_directory(None).unwrap();
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let quarantine_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(&repo_dir, &["dash-0.5.12-2-x86_64.pkg.tar.zst"], true);
+        create_pkgfiles(
+            &src_dir,
+            &[
+                "dash-0.5.12-1-x86_64.pkg.tar.zst",
+                "dwm-6.2-4-aarch64.pkg.tar.zst",
+                "htop-3.3.0-1-x86_64.pkg.tar.zst",
+                "nano-8.0-1-any.pkg.tar.zst",
+            ],
+            true,
+        );
+        create_pkgfiles(&src_dir, &["st-0.8.4-2-x86_64.pkg.tar.zst"], false);
+        fs::write(format!("{src_dir}/vim-9.1-1-x86_64.pkg.tar.zst"), "").unwrap();
+        fs::write(format!("{src_dir}/vim-9.1-1-x86_64.pkg.tar.zst.sig"), "").unwrap();
+
+        let profile = config::Profile {
+            arch: Some("x86_64".to_owned()),
+            quarantine_dir: Some(quarantine_dir.clone()),
+            ..create_test_profile(&repo_dir)
+        };
+        let mut backend =
+            FakeBackend::with_entries(&[&format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst")]);
+        let rejected_pkgs = do_repo_move_pkgs(
+            &profile,
+            &mut Executor::new(&mut backend, false),
+            Path::new(&src_dir),
+            Path::new(&repo_dir),
+        )
+        .unwrap();
+
+        // the valid packages proceed
+        assert_eq!(backend.added, vec![
+            format!("{repo_dir}/htop-3.3.0-1-x86_64.pkg.tar.zst"),
+            format!("{repo_dir}/nano-8.0-1-any.pkg.tar.zst"),
+        ]);
+        assert_eq!(rejected_pkgs.len(), 4);
+        for (rejected_pkg, reason) in [
+            ("dash-0.5.12-1-x86_64.pkg.tar.zst", "downgrade from '0.5.12-2'"),
+            ("dwm-6.2-4-aarch64.pkg.tar.zst", "arch 'aarch64'"),
+            ("st-0.8.4-2-x86_64.pkg.tar.zst", "doesn't exist"),
+            ("vim-9.1-1-x86_64.pkg.tar.zst", "Failed to read .PKGINFO"),
+        ] {
+            assert!(Path::new(&format!("{quarantine_dir}/{rejected_pkg}")).exists());
+            let reason_content =
+                fs::read_to_string(format!("{quarantine_dir}/{rejected_pkg}.reason")).unwrap();
+            assert!(reason_content.contains(reason), "{reason_content}");
+        }
+
+        fs::remove_dir_all(src_dir).unwrap();
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(quarantine_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_packager_allowlist() {
+        let src_dir = utils::create_temporary_directory(None).unwrap();
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let quarantine_dir = utils::create_temporary_directory(None).unwrap();
+        let create_pkg = |pkg_filepath: &str, packager: &str| {
+            let pkg_filename = Path::new(pkg_filepath).file_name().unwrap().to_str().unwrap();
+            let pkginfo = pkginfo::get_pkginfo_from_filename(pkg_filename).unwrap();
+            let pkginfo_content = format!(
+                "pkgname = {}\npkgver = {}\npackager = {packager}\n",
+                pkginfo.pkgname, pkginfo.pkgver
+            );
+            utils::create_test_pkg(pkg_filepath, &[(".PKGINFO", &pkginfo_content)]);
+            fs::write(format!("{pkg_filepath}.sig"), "").unwrap();
+        };
+        let builder = "CachyOS <admin@cachyos.org>";
+        create_pkg(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst"), builder);
+        create_pkg(&format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst"), "Someone Else");
+        create_pkg(&format!("{src_dir}/dwm-6.2-4-x86_64.pkg.tar.zst"), builder);
+        create_pkg(&format!("{src_dir}/st-0.8.4-2-x86_64.pkg.tar.zst"), "Someone Else");
+
+        let profile = config::Profile {
+            allowed_packagers: vec![builder.to_owned()],
+            quarantine_dir: Some(quarantine_dir.clone()),
+            ..create_test_profile(&repo_dir)
+        };
+        let mut backend =
+            FakeBackend::with_entries(&[&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")]);
+        let rejected_pkgs = do_repo_move_pkgs(
+            &profile,
+            &mut Executor::new(&mut backend, false),
+            Path::new(&src_dir),
+            Path::new(&repo_dir),
+        )
+        .unwrap();
+        // including the one rejected by the repo update
+        assert_eq!(rejected_pkgs, vec![
+            format!("{src_dir}/st-0.8.4-2-x86_64.pkg.tar.zst"),
+            format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst"),
+        ]);
+
+        // the package which is replaced by the rejected one stays in the repo
+        assert_eq!(backend.added, vec![format!("{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.zst")]);
+        assert!(Path::new(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")).exists());
+        for rejected_pkg in ["dash-0.5.12-2-x86_64.pkg.tar.zst", "st-0.8.4-2-x86_64.pkg.tar.zst"] {
+            assert!(Path::new(&format!("{quarantine_dir}/{rejected_pkg}")).exists());
+            assert!(Path::new(&format!("{quarantine_dir}/{rejected_pkg}.sig")).exists());
+        }
+
+        fs::remove_dir_all(src_dir).unwrap();
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(quarantine_dir).unwrap();
+    }
+
+    #[test]
+    fn test_debug_repo_update() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let debug_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(
+            &repo_dir,
+            &["dwm-6.2-4-x86_64.pkg.tar.zst", "dwm-debug-6.2-4-x86_64.pkg.tar.zst"],
+            true,
+        );
+        create_pkgfiles(&debug_dir, &["dwm-debug-6.2-3-x86_64.pkg.tar.zst"], true);
+
+        let profile = config::Profile {
+            debug_dir: Some(debug_dir.clone()),
+            ..create_test_profile(&repo_dir)
+        };
+        let debug_profile = profile.debug_profile().unwrap();
+        assert_eq!(debug_profile.repo, format!("{debug_dir}/repof-debug.db.tar.zst"));
+
+        let mut backend = FakeBackend::with_entries(&["dwm-debug-6.2-3-x86_64.pkg.tar.zst"]);
+        let mut debug_backend = FakeBackend::with_entries(&["dwm-debug-6.2-3-x86_64.pkg.tar.zst"]);
+        let mut executor = Executor::new(&mut backend, false);
+        do_repo_update(&profile, &mut executor, Path::new(&repo_dir)).unwrap();
+        do_debug_repo_update(&debug_profile, &mut executor, &mut debug_backend, false).unwrap();
+
+        // the debug package is moved with its signature and removed from the repo DB
+        assert_eq!(backend.entries.keys().collect::<Vec<_>>(), vec!["dwm"]);
+        assert_eq!(backend.removed, vec!["dwm-debug".to_owned()]);
+        assert!(Path::new(&format!("{debug_dir}/dwm-debug-6.2-4-x86_64.pkg.tar.zst.sig")).exists());
+
+        // the debug repo has its own outdated handling
+        assert_eq!(debug_backend.added, vec![format!(
+            "{debug_dir}/dwm-debug-6.2-4-x86_64.pkg.tar.zst"
+        )]);
+        assert_eq!(debug_backend.entries["dwm-debug"].version, "6.2-4");
+        assert!(!Path::new(&format!("{debug_dir}/dwm-debug-6.2-3-x86_64.pkg.tar.zst")).exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(debug_dir).unwrap();
+    }
+
+    #[test]
+    fn test_reference_sync() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let reference_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(&repo_dir, &["dash-0.5.12-1-x86_64.pkg.tar.zst"], true);
+        create_pkgfiles(&repo_dir, &["dwm-6.2-3-x86_64.pkg.tar.zst"], true);
+        create_pkgfiles(&repo_dir, &["st-0.8.4-1-x86_64.pkg.tar.zst"], true);
+
+        // reference repo with newer versions of all packages
+        let reference_repo = format!("{reference_dir}/reference.db.tar.zst");
+        let mut reference_db = repo_db::RepoDb::new(&reference_repo).unwrap();
+        for (pkgname, pkgver) in [("dash", "0.5.12-2"), ("dwm", "6.2-4"), ("st", "0.8.4-2")] {
+            let pkgfile = format!("{reference_dir}/{pkgname}-{pkgver}-x86_64.pkg.tar.zst");
+            let pkginfo = format!("pkgname = {pkgname}\npkgver = {pkgver}\narch = x86_64\n");
+            utils::create_test_pkg(&pkgfile, &[(".PKGINFO", &pkginfo)]);
+            fs::write(format!("{pkgfile}.sig"), "").unwrap();
+            reference_db.add_package(&pkgfile, &Default::default()).unwrap();
+        }
+        reference_db.write(&Default::default()).unwrap();
+        // the package file is corrupted after it was added into the reference DB
+        fs::write(format!("{reference_dir}/st-0.8.4-2-x86_64.pkg.tar.zst"), "").unwrap();
+
+        let profile = config::Profile {
+            reference_repo: Some(reference_repo),
+            reference_deny: vec!["dwm".into()],
+            ..create_test_profile(&repo_dir)
+        };
+        let mut backend = FakeBackend::with_entries(&[
+            "dash-0.5.12-1-x86_64.pkg.tar.zst",
+            "dwm-6.2-3-x86_64.pkg.tar.zst",
+            "st-0.8.4-1-x86_64.pkg.tar.zst",
+        ]);
+        let rejected_pkgs = do_reference_sync(
+            &profile,
+            &mut Executor::new(&mut backend, false),
+            Path::new(&repo_dir),
+        )
+        .unwrap();
+        assert_eq!(rejected_pkgs, vec![format!("{reference_dir}/st-0.8.4-2-x86_64.pkg.tar.zst")]);
+
+        assert_eq!(backend.added, vec![format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst")]);
+        assert!(Path::new(&format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst.sig")).exists());
+        // the replaced version is handled as outdated one
+        assert!(!Path::new(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")).exists());
+        // denied and corrupted packages are not synced
+        assert!(!Path::new(&format!("{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.zst")).exists());
+        assert!(!Path::new(&format!("{repo_dir}/st-0.8.4-2-x86_64.pkg.tar.zst")).exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(reference_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_promote() {
+        let testing_dir = utils::create_temporary_directory(None).unwrap();
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        // split packages of the same pkgbase
+        for (pkgname, pkgbase) in [("gcc", "gcc"), ("gcc-libs", "gcc"), ("dash", "dash")] {
+            let pkgfile = format!("{testing_dir}/{pkgname}-14.1.1-1-x86_64.pkg.tar.zst");
+            let pkginfo = format!(
+                "pkgname = {pkgname}\npkgbase = {pkgbase}\npkgver = 14.1.1-1\narch = x86_64\n"
+            );
+            utils::create_test_pkg(&pkgfile, &[(".PKGINFO", &pkginfo)]);
+            fs::write(format!("{pkgfile}.sig"), "").unwrap();
+        }
+        create_pkgfiles(&repo_dir, &["gcc-libs-14.1.0-1-x86_64.pkg.tar.zst"], true);
+
+        let testing_profile = create_test_profile(&testing_dir);
+        let profile = config::Profile { backup: false, ..create_test_profile(&repo_dir) };
+        // unknown package
+        let mut empty_backend = FakeBackend::default();
+        assert!(do_repo_promote(
+            &testing_profile,
+            &profile,
+            &mut Executor::new(&mut FakeBackend::default(), false),
+            &mut empty_backend,
+            &["st".into()]
+        )
+        .is_err());
+
+        let mut testing_backend = FakeBackend::with_entries(&[
+            "gcc-14.1.1-1-x86_64.pkg.tar.zst",
+            "gcc-libs-14.1.1-1-x86_64.pkg.tar.zst",
+            "dash-14.1.1-1-x86_64.pkg.tar.zst",
+        ]);
+        let mut backend = FakeBackend::with_entries(&[
+            "gcc-libs-14.1.0-1-x86_64.pkg.tar.zst",
+            "dash-15.0.0-1-x86_64.pkg.tar.zst",
+        ]);
+        // the newer version in the destination must not be downgraded
+        let mut source_backend = FakeBackend::default();
+        let mut dry_run_source_backend = FakeBackend::default();
+        let mut executor = Executor::new(&mut backend, false);
+        let promote_err =
+            do_repo_promote(&testing_profile, &profile, &mut executor, &mut source_backend, &[
+                "dash".into(),
+            ])
+            .unwrap_err();
+        assert!(format!("{promote_err:#}").contains("downgrade"));
+        assert!(executor.plan().is_empty());
+
+        // the replaced version is handled only once the promoted one is in the DB
+        let mut executor = Executor::new(&mut backend, true);
+        do_repo_promote(&testing_profile, &profile, &mut executor, &mut dry_run_source_backend, &[
+            "gcc-libs".into(),
+        ])
+        .unwrap();
+        let replaced_pkg = format!("{repo_dir}/gcc-libs-14.1.0-1-x86_64.pkg.tar.zst");
+        let step_pos = |step: &PlanStep| executor.plan().iter().position(|x| x == step).unwrap();
+        assert!(
+            step_pos(&PlanStep::RepoAdd(vec![
+                format!("{repo_dir}/gcc-14.1.1-1-x86_64.pkg.tar.zst"),
+                format!("{repo_dir}/gcc-libs-14.1.1-1-x86_64.pkg.tar.zst"),
+            ])) < step_pos(&PlanStep::RemoveFile(replaced_pkg))
+        );
+
+        let mut executor = Executor::new(&mut backend, false);
+        do_repo_promote(&testing_profile, &profile, &mut executor, &mut testing_backend, &[
+            "gcc".into()
+        ])
+        .unwrap();
+
+        // the whole pkgbase is moved between the repos
+        assert_eq!(testing_backend.removed, vec!["gcc".to_owned(), "gcc-libs".to_owned()]);
+        assert_eq!(testing_backend.entries.keys().collect::<Vec<_>>(), vec!["dash"]);
+        assert_eq!(backend.added, vec![
+            format!("{repo_dir}/gcc-14.1.1-1-x86_64.pkg.tar.zst"),
+            format!("{repo_dir}/gcc-libs-14.1.1-1-x86_64.pkg.tar.zst"),
+        ]);
+        assert!(Path::new(&format!("{repo_dir}/gcc-libs-14.1.1-1-x86_64.pkg.tar.zst.sig")).exists());
+        assert!(Path::new(&format!("{testing_dir}/dash-14.1.1-1-x86_64.pkg.tar.zst")).exists());
+        // the replaced version is removed as the destination doesn't keep backups
+        assert!(!Path::new(&format!("{repo_dir}/gcc-libs-14.1.0-1-x86_64.pkg.tar.zst")).exists());
+
+        fs::remove_dir_all(testing_dir).unwrap();
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_profile_names() {
+        let config = config::Config {
+            profiles: HashMap::from([
+                ("core-v3".to_owned(), config::Profile::default()),
+                ("core-v4".to_owned(), config::Profile::default()),
+                ("extra-v3".to_owned(), config::Profile::default()),
+            ]),
+            groups: HashMap::from([("core".to_owned(), vec![
+                "core-v4".to_owned(),
+                "core-v3".to_owned(),
+            ])]),
+        };
+        let get_names = |cmdline: &str| {
+            let args = Cli::try_parse_from(cmdline.split_whitespace())?;
+            get_profile_names(&args, &config)
+        };
+
+        assert_eq!(get_names("rmu -p extra-v3 update").unwrap(), vec!["extra-v3"]);
+        assert_eq!(get_names("rmu --group core update").unwrap(), vec!["core-v4", "core-v3"]);
+        assert_eq!(get_names("rmu --all-profiles update").unwrap(), vec![
+            "core-v3", "core-v4", "extra-v3"
+        ]);
+        assert!(get_names("rmu --group extra update").is_err());
+        assert!(get_names("rmu update").is_err());
+        assert!(get_names("rmu -p core-v3 --all-profiles update").is_err());
+        assert!(get_names("rmu --all-profiles promote --from core-v3 --to core-v4").is_err());
+        assert_eq!(get_names("rmu promote --from core-v3 --to core-v4").unwrap(), vec!["core-v4"]);
+        // the group is named the same way for the consistency check
+        let args = Cli::try_parse_from("rmu --group core check-consistency".split_whitespace());
+        assert_eq!(args.unwrap().group.as_deref(), Some("core"));
+        assert!(Cli::try_parse_from("rmu check-consistency core".split_whitespace()).is_err());
+    }
+
+    #[test]
+    fn test_lock_dirs() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let backup_dir = utils::create_temporary_directory(None).unwrap();
+        let profile = config::Profile {
+            backup: true,
+            backup_dir: Some(backup_dir.clone()),
+            debug_dir: Some(format!("{repo_dir}/missing-debug")),
+            ..create_test_profile(&repo_dir)
+        };
+
+        assert_eq!(get_lock_dirs(&profile, &Commands::Update, false), vec![
+            (repo_dir.clone(), lock::LockMode::Exclusive),
+            (backup_dir.clone(), lock::LockMode::Exclusive),
+        ]);
+        let checkup = Commands::IsPkgsUpToDate { format: OutputFormat::Text };
+        assert_eq!(get_lock_dirs(&profile, &checkup, false)[0].1, lock::LockMode::Shared);
+        assert_eq!(get_lock_dirs(&profile, &Commands::Reset, true)[0].1, lock::LockMode::Shared);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(backup_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_checkup() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(
+            &repo_dir,
+            &["dash-0.5.12-1-x86_64.pkg.tar.zst", "dash-0.5.12-2-x86_64.pkg.tar.zst"],
+            true,
+        );
+
+        let profile = create_test_profile(&repo_dir);
+        let mut backend = FakeBackend::with_entries(&[
+            "dash-0.5.12-1-x86_64.pkg.tar.zst",
+            "dwm-6.2-4-x86_64.pkg.tar.zst",
+        ]);
+        let executor = Executor::new(&mut backend, false);
+        let report = do_repo_checkup(&profile, &executor, Path::new(&repo_dir)).unwrap();
+
+        assert!(!report.clean);
+        assert_eq!(report.repo, "repof");
+        assert!(report.brand_new.is_empty());
+        assert_eq!(report.new.iter().map(ReportEntry::db_pair).collect::<Vec<_>>(), vec![
+            "dash-0.5.12-2"
+        ]);
+        assert_eq!(report.outdated[0].filename, "dash-0.5.12-1-x86_64.pkg.tar.zst");
+        assert_eq!(report.stale.iter().map(ReportEntry::db_pair).collect::<Vec<_>>(), vec![
+            "dwm-6.2-4"
+        ]);
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_reset_interactive() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(
+            &repo_dir,
+            &[
+                "dash-0.5.12-1-x86_64.pkg.tar.zst",
+                "dash-0.5.12-2-x86_64.pkg.tar.zst",
+                "dwm-6.2-3-x86_64.pkg.tar.zst",
+                "dwm-6.2-4-x86_64.pkg.tar.zst",
+            ],
+            true,
+        );
+
+        let profile = create_test_profile(&repo_dir);
+        let mut backend = FakeBackend::default();
+
+        // declined summary aborts before touching anything
+        let mut executor =
+            Executor::new(&mut backend, false).with_prompter(prompt::Prompter::with_input("n\n"));
+        assert!(do_repo_reset(&profile, &mut executor, Path::new(&repo_dir)).is_err());
+        assert!(backend.added.is_empty());
+
+        // confirm the DB, keep the first outdated package and remove the second one
+        let mut executor = Executor::new(&mut backend, false)
+            .with_prompter(prompt::Prompter::with_input("y\nn\ny\n"));
+        do_repo_reset(&profile, &mut executor, Path::new(&repo_dir)).unwrap();
+        assert_eq!(backend.entries.keys().collect::<Vec<_>>(), vec!["dash", "dwm"]);
+        assert!(Path::new(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")).exists());
+        assert!(!Path::new(&format!("{repo_dir}/dwm-6.2-3-x86_64.pkg.tar.zst")).exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_update_dry_run() {
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        let backup_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(
+            &repo_dir,
+            &["dash-0.5.12-1-x86_64.pkg.tar.zst", "dash-0.5.12-2-x86_64.pkg.tar.zst"],
+            true,
+        );
+        create_pkgfiles(&backup_dir, &["dash-0.5.11-1-x86_64.pkg.tar.zst"], false);
+
+        let profile = config::Profile {
+            backup: true,
+            backup_dir: Some(backup_dir.clone()),
+            backup_num: Some(1),
+            ..create_test_profile(&repo_dir)
+        };
+        let mut backend = FakeBackend::with_entries(&[
+            "dash-0.5.12-1-x86_64.pkg.tar.zst",
+            "dwm-6.2-4-x86_64.pkg.tar.zst",
+        ]);
+        let mut executor = Executor::new(&mut backend, true);
+        do_repo_update(&profile, &mut executor, Path::new(&repo_dir)).unwrap();
+
+        // the backup cleanup sees the package which is moved into the backup dir by the plan
+        assert_eq!(executor.plan(), &[
+            plan::PlanStep::RepoAdd(vec![format!("{repo_dir}/dash-0.5.12-2-x86_64.pkg.tar.zst")]),
+            plan::PlanStep::MoveFile {
+                src: format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst"),
+                dest: format!("{backup_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst"),
+            },
+            plan::PlanStep::MoveFile {
+                src: format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst.sig"),
+                dest: format!("{backup_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst.sig"),
+            },
+            plan::PlanStep::RemoveFile(format!("{backup_dir}/dash-0.5.11-1-x86_64.pkg.tar.zst")),
+            plan::PlanStep::RepoRemove(vec!["dwm".to_owned()]),
+        ]);
+
+        // nothing is touched
+        assert!(backend.added.is_empty() && backend.removed.is_empty());
+        assert!(Path::new(&format!("{repo_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")).exists());
+        assert!(Path::new(&format!("{backup_dir}/dash-0.5.11-1-x86_64.pkg.tar.zst")).exists());
+        assert!(!Path::new(&format!("{backup_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst")).exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+        fs::remove_dir_all(backup_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_move_pkgs_dry_run() {
+        let src_dir = utils::create_temporary_directory(None).unwrap();
+        let repo_dir = utils::create_temporary_directory(None).unwrap();
+        create_pkgfiles(&src_dir, &["dwm-6.2-4-x86_64.pkg.tar.zst"], true);
+
+        let profile = create_test_profile(&repo_dir);
+        let mut backend = FakeBackend::default();
+        let mut executor = Executor::new(&mut backend, true);
+        do_repo_move_pkgs(&profile, &mut executor, Path::new(&src_dir), Path::new(&repo_dir))
+            .unwrap();
+
+        // the update part sees the packages at the planned destination
+        assert_eq!(
+            executor.plan().last(),
+            Some(&plan::PlanStep::RepoAdd(vec![format!(
+                "{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.zst"
+            )]))
+        );
+        assert!(Path::new(&format!("{src_dir}/dwm-6.2-4-x86_64.pkg.tar.zst")).exists());
+        assert!(!Path::new(&format!("{repo_dir}/dwm-6.2-4-x86_64.pkg.tar.zst")).exists());
+
+        fs::remove_dir_all(src_dir).unwrap();
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+}
@@ -1,17 +1,75 @@
+use crate::pkginfo::{self, PackageInfo};
 use crate::utils;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use std::path::Path;
 
+use anyhow::Result;
+
 type PackageMap = HashMap<String, Vec<(String, alpm::Version)>>;
 
-pub fn get_debug_packages(pkg_list: &[String]) -> Vec<String> {
+/// Extensions of the package files which makepkg can produce
+pub const PKG_EXTENSIONS: [&str; 10] = [
+    ".pkg.tar.zst",
+    ".pkg.tar.xz",
+    ".pkg.tar.gz",
+    ".pkg.tar.bz2",
+    ".pkg.tar.lz4",
+    ".pkg.tar.lrz",
+    ".pkg.tar.lzo",
+    ".pkg.tar.lz",
+    ".pkg.tar.Z",
+    ".pkg.tar",
+];
+
+// Checks if the file is a package file, by its extension or by one of the extra glob patterns
+pub fn is_pkgfile(file_path: &str, extra_patterns: &[String]) -> bool {
+    let pkg_filename =
+        Path::new(file_path).file_name().and_then(|x| x.to_str()).unwrap_or(file_path);
+    PKG_EXTENSIONS.iter().any(|pkg_ext| pkg_filename.ends_with(pkg_ext))
+        || extra_patterns.iter().any(|pattern| {
+            glob::Pattern::new(pattern).is_ok_and(|glob_pattern| glob_pattern.matches(pkg_filename))
+        })
+}
+
+// Lists package files of the directory, sorted by path
+pub fn list_pkgfiles(dir: &str, extra_patterns: &[String]) -> Result<Vec<String>> {
+    let mut pkg_list: Vec<String> = vec![];
+    // nothing is there yet
+    if !Path::new(dir).is_dir() {
+        return Ok(pkg_list);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_path = entry.path().to_str().unwrap().to_owned();
+        if entry.file_type()?.is_file() && is_pkgfile(&file_path, extra_patterns) {
+            pkg_list.push(file_path);
+        }
+    }
+    pkg_list.sort();
+
+    Ok(pkg_list)
+}
+
+// Get groups of package files which are the same package version with different compression
+pub fn get_compression_conflicts(pkg_list: &[String]) -> Vec<Vec<String>> {
+    let mut pkg_stems: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for pkg_filepath in pkg_list {
+        let pkg_stem = match pkg_filepath.rfind(".pkg.tar") {
+            Some(ext_pos) => &pkg_filepath[..ext_pos],
+            None => pkg_filepath,
+        };
+        pkg_stems.entry(pkg_stem).or_default().push(pkg_filepath.clone());
+    }
+    pkg_stems.into_values().filter(|pkg_files| pkg_files.len() > 1).collect()
+}
+
+pub fn get_debug_packages(pkginfos: &[(String, PackageInfo)]) -> Vec<String> {
     // Identify debug packages from pkg list
     let mut debug_pkgs: Vec<String> = vec![];
-    for pkg_filepath in pkg_list {
-        let pkg_filename = Path::new(pkg_filepath).file_name().unwrap().to_str().unwrap();
-        let pkg_name = crate::pkg_utils::get_pkgname_from_filename(pkg_filename);
-        if pkg_name.ends_with("-debug") {
+    for (pkg_filepath, pkginfo) in pkginfos {
+        if pkginfo.pkgname.ends_with("-debug") {
             debug_pkgs.push(pkg_filepath.clone());
         }
     }
@@ -20,8 +78,8 @@ pub fn get_debug_packages(pkg_list: &[String]) -> Vec<String> {
     debug_pkgs
 }
 
-pub fn get_outdated_pkgs(pkg_list: &[String]) -> Vec<String> {
-    let mut pkg_map = get_pkgs_map(pkg_list);
+pub fn get_outdated_pkgs(pkginfos: &[(String, PackageInfo)]) -> Vec<String> {
+    let mut pkg_map = get_pkgs_map(pkginfos);
 
     // Identify outdated packages for each group
     let mut outdated_pkgs: Vec<String> = vec![];
@@ -41,18 +99,16 @@ pub fn get_outdated_pkgs(pkg_list: &[String]) -> Vec<String> {
 
 // Remove outdated packages from pkg_list
 pub fn remove_outdated_pkgs(pkg_list: &mut Vec<String>) {
-    let outdated_pkgs = get_outdated_pkgs(pkg_list);
+    let pkginfos = pkg_list
+        .iter()
+        .filter_map(|pkg| Some((pkg.clone(), pkginfo::load_pkginfo(pkg)?)))
+        .collect::<Vec<_>>();
+    let outdated_pkgs = get_outdated_pkgs(&pkginfos);
     pkg_list.retain(|pkg| !outdated_pkgs.contains(pkg));
 }
 
-// TODO(vnepogodin): add checking for new packages based on files in separate folder
-// (profile.ref_folder) if very are newer package available in separate repo:
-// 1. check if such exist and gather the list of them
-// 2. copy those package files over to the repo directory
-// 3. update the repo with newer packages
-// 4. handle old packages which were present in the repository at the time, which we dumped before
-pub fn get_new_pkgs(pkg_list: &[String]) -> Vec<String> {
-    let mut pkg_map = get_pkgs_map(pkg_list);
+pub fn get_new_pkgs(pkginfos: &[(String, PackageInfo)]) -> Vec<String> {
+    let mut pkg_map = get_pkgs_map(pkginfos);
 
     let mut new_pkgs: Vec<String> = vec![];
     for (_name, versions) in pkg_map.iter_mut() {
@@ -69,10 +125,43 @@ pub fn get_new_pkgs(pkg_list: &[String]) -> Vec<String> {
     new_pkgs
 }
 
+// Get versions of the package in pkginfos, sorted in descending order
+pub fn get_pkg_versions(
+    pkginfos: &[(String, PackageInfo)],
+    pkgname: &str,
+) -> Vec<(String, alpm::Version)> {
+    let mut pkg_map = get_pkgs_map(pkginfos);
+    let mut versions = pkg_map.remove(pkgname).unwrap_or_default();
+    versions.sort_by(|a, b| b.1.vercmp(&a.1));
+    versions
+}
+
+// Pick the package file to roll back to from pkginfos, where:
+// the requested version, or the latest version older than the current one by default
+pub fn get_rollback_pkg(
+    pkginfos: &[(String, PackageInfo)],
+    pkgname: &str,
+    current_version: Option<&alpm::Version>,
+    target_version: Option<&str>,
+) -> Option<String> {
+    let versions = get_pkg_versions(pkginfos, pkgname);
+    let rollback_pkg = match target_version {
+        // pkgrel can be omitted, the latest release of the version is taken then
+        Some(target_version) => {
+            let target_version = alpm::Version::new(target_version);
+            versions.into_iter().find(|(_, version)| version.vercmp(&target_version).is_eq())
+        },
+        None => versions.into_iter().find(|(_, version)| {
+            current_version.is_none_or(|current_version| version.vercmp(current_version).is_lt())
+        }),
+    };
+    rollback_pkg.map(|(pkg_filepath, _)| pkg_filepath)
+}
+
 // Get list of packages with more than N versions
 // NOTE: if the package has less than N versions, it will be ignored
-pub fn get_stale_pkg_versions(pkg_list: &[String], n_versions: usize) -> PackageMap {
-    let mut pkg_map = get_pkgs_map(pkg_list);
+pub fn get_stale_pkg_versions(pkginfos: &[(String, PackageInfo)], n_versions: usize) -> PackageMap {
+    let mut pkg_map = get_pkgs_map(pkginfos);
 
     let mut n_pkgs_map: PackageMap = HashMap::new();
     for (name, versions) in pkg_map.iter_mut() {
@@ -90,20 +179,15 @@ pub fn get_stale_pkg_versions(pkg_list: &[String], n_versions: usize) -> Package
     n_pkgs_map
 }
 
-// Map of all packages in pkglist. where:
+// Map of all packages in pkginfos. where:
 // (PKGNAME, [FILENAME of each VERSION])
-fn get_pkgs_map(pkg_list: &[String]) -> PackageMap {
+fn get_pkgs_map(pkginfos: &[(String, PackageInfo)]) -> PackageMap {
     let mut pkg_map: PackageMap = HashMap::new();
 
     // Group packages by name and store their versions
-    for pkg_filepath in pkg_list {
-        let pkg_filename = Path::new(pkg_filepath).file_name().unwrap().to_str().unwrap();
-        let pkg_name = crate::pkg_utils::get_pkgname_from_filename(pkg_filename);
-        let pkg_version = crate::pkg_utils::get_pkgver_from_filename(pkg_filename);
-
-        let version = alpm::Version::new(pkg_version);
-
-        pkg_map.entry(pkg_name.into()).or_default().push((pkg_filepath.clone(), version));
+    for (pkg_filepath, pkginfo) in pkginfos {
+        let version = pkginfo.version();
+        pkg_map.entry(pkginfo.pkgname.clone()).or_default().push((pkg_filepath.clone(), version));
     }
     pkg_map
 }
@@ -132,14 +216,31 @@ pub fn get_pkgver_from_filename(filename: &str) -> &str {
     &filename[first_pos..last_pos]
 }
 
+// Splits package filename into (PKGNAME, PKGVER, ARCH),
+// e.g `pkgname-pkgver-pkgrel-arch.pkg.tar.zst`
+pub fn parse_pkg_filename(filename: &str) -> Option<(&str, &str, &str)> {
+    let pkg_stem = &filename[..filename.find(".pkg.tar")?];
+
+    let (rest, arch) = pkg_stem.rsplit_once('-')?;
+    let (rest, pkgrel) = rest.rsplit_once('-')?;
+    let (pkgname, pkgver) = rest.rsplit_once('-')?;
+    if pkgname.is_empty() || pkgver.is_empty() || pkgrel.is_empty() || arch.is_empty() {
+        return None;
+    }
+
+    // pkgver includes pkgrel
+    Some((pkgname, &pkg_stem[pkgname.len() + 1..pkg_stem.len() - arch.len() - 1], arch))
+}
+
 pub fn get_pkg_db_pair_from_path(file_path: &str) -> String {
     // NOTE: we can do here same as for pkgname and pkgver,
     // and just return &str which points to part of file_path
-    let pkg_filename = Path::new(file_path).file_name().unwrap().to_str().unwrap();
-    let pkg_name = get_pkgname_from_filename(pkg_filename);
-    let pkg_ver = get_pkgver_from_filename(pkg_filename);
-
-    format!("{pkg_name}-{pkg_ver}")
+    let pkg_filename =
+        Path::new(file_path).file_name().and_then(|x| x.to_str()).unwrap_or(file_path);
+    match parse_pkg_filename(pkg_filename) {
+        Some((pkg_name, pkg_ver, _)) => format!("{pkg_name}-{pkg_ver}"),
+        None => pkg_filename.to_owned(),
+    }
 }
 
 pub fn get_repo_db_prefix(repo_db_filename: &str) -> String {
@@ -152,15 +253,17 @@ pub fn get_repo_db_prefix(repo_db_filename: &str) -> String {
     repo_db_prefix
 }
 
-pub fn remove_pkgs_without_sig(pkgs_list: &mut Vec<String>) {
-    pkgs_list.retain(|pkg| {
-        let pkg_sig_path = format!("{pkg}.sig");
-        if !Path::new(&pkg_sig_path).exists() {
-            log::error!("package doesn't have required signature {pkg}");
+// Drops packages whose signature is rejected by the provided function
+pub fn remove_pkgs_with_invalid_sig(
+    pkgs_list: &mut Vec<String>,
+    check_sig: impl Fn(&str) -> Result<()>,
+) {
+    pkgs_list.retain(|pkg| match check_sig(pkg) {
+        Ok(()) => true,
+        Err(sig_err) => {
+            log::error!("package signature is rejected {pkg}: {sig_err}");
             false
-        } else {
-            true
-        }
+        },
     });
 }
 
@@ -309,12 +412,121 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_pkg_filename() {
+        assert_eq!(
+            parse_pkg_filename("bcachefs-tools-3:1.11.0-1.1-x86_64.pkg.tar.zst"),
+            Some(("bcachefs-tools", "3:1.11.0-1.1", "x86_64"))
+        );
+        assert_eq!(
+            parse_pkg_filename("lightdm-webkit2-theme-arch-1:0.1-1-any.pkg.tar.xz"),
+            Some(("lightdm-webkit2-theme-arch", "1:0.1-1", "any"))
+        );
+        assert_eq!(parse_pkg_filename("renamed.pkg.tar.zst"), None);
+        assert_eq!(parse_pkg_filename("0.1-1-any.pkg.tar.zst"), None);
+        assert_eq!(parse_pkg_filename("dash-0.5.12-1.1-x86_64"), None);
+        assert_eq!(
+            get_pkg_db_pair_from_path("/to/file/renamed.pkg.tar.zst"),
+            "renamed.pkg.tar.zst"
+        );
+    }
+
+    #[test]
+    fn test_list_pkgfiles() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        for filename in [
+            "dash-0.5.12-1-x86_64.pkg.tar.zst",
+            "dash-0.5.12-1-x86_64.pkg.tar.zst.sig",
+            "dwm-6.2-4-x86_64.pkg.tar.xz",
+            "st-0.8.4-2-x86_64.pkg.tar",
+            "pacman-6.1.0-3-x86_64.pkg.tar.Z",
+            "repof.db.tar.zst",
+            "vim-9.1-1-x86_64.custom",
+        ] {
+            fs::write(format!("{temp_dir}/{filename}"), "").unwrap();
+        }
+
+        assert_eq!(list_pkgfiles(&temp_dir, &[]).unwrap(), vec![
+            format!("{temp_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst"),
+            format!("{temp_dir}/dwm-6.2-4-x86_64.pkg.tar.xz"),
+            format!("{temp_dir}/pacman-6.1.0-3-x86_64.pkg.tar.Z"),
+            format!("{temp_dir}/st-0.8.4-2-x86_64.pkg.tar"),
+        ]);
+        let extra_patterns = vec!["*.custom".to_owned()];
+        assert!(list_pkgfiles(&temp_dir, &extra_patterns)
+            .unwrap()
+            .contains(&format!("{temp_dir}/vim-9.1-1-x86_64.custom")));
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_compression_conflicts() {
+        let pkgs_list: Vec<String> = vec![
+            "local_repo/x86_64/dash-0.5.12-1-x86_64.pkg.tar.xz".into(),
+            "local_repo/x86_64/dash-0.5.12-1-x86_64.pkg.tar.zst".into(),
+            "local_repo/x86_64/dash-0.5.12-2-x86_64.pkg.tar.zst".into(),
+            "local_repo/x86_64/dwm-6.2-4-x86_64.pkg.tar".into(),
+            "local_repo/x86_64/dwm-6.2-4-x86_64.pkg.tar.gz".into(),
+            "local_repo/x86_64/st-0.8.4-2-x86_64.pkg.tar.zst".into(),
+        ];
+        assert_eq!(get_compression_conflicts(&pkgs_list), vec![
+            vec![
+                "local_repo/x86_64/dash-0.5.12-1-x86_64.pkg.tar.xz".to_owned(),
+                "local_repo/x86_64/dash-0.5.12-1-x86_64.pkg.tar.zst".to_owned(),
+            ],
+            vec![
+                "local_repo/x86_64/dwm-6.2-4-x86_64.pkg.tar".to_owned(),
+                "local_repo/x86_64/dwm-6.2-4-x86_64.pkg.tar.gz".to_owned(),
+            ],
+        ]);
+    }
+
     #[test]
     fn test_repo_db_prefix() {
         assert_eq!(get_repo_db_prefix("example.db.tar.zst"), "example".to_owned());
         assert_eq!(get_repo_db_prefix("example.files.tar.zst"), "example.files.tar".to_owned());
     }
 
+    #[test]
+    fn test_rollback_pkg() {
+        let pkgs_list: Vec<String> = vec![
+            "backup_repo/dash-0.5.11-1-x86_64.pkg.tar.zst".into(),
+            "backup_repo/dash-0.5.11-2-x86_64.pkg.tar.zst".into(),
+            "backup_repo/dash-0.5.12-1-x86_64.pkg.tar.zst".into(),
+            "backup_repo/dash-0.5.13-1-x86_64.pkg.tar.zst".into(),
+            "backup_repo/dwm-6.2-4-x86_64.pkg.tar.zst".into(),
+        ];
+        let pkginfos = pkginfo::load_pkginfos(&pkgs_list);
+        let current_version = alpm::Version::new("0.5.13-1");
+
+        // latest older version by default
+        assert_eq!(
+            get_rollback_pkg(&pkginfos, "dash", Some(&current_version), None),
+            Some("backup_repo/dash-0.5.12-1-x86_64.pkg.tar.zst".into())
+        );
+        assert_eq!(
+            get_rollback_pkg(&pkginfos, "dash", None, None),
+            Some("backup_repo/dash-0.5.13-1-x86_64.pkg.tar.zst".into())
+        );
+        assert_eq!(
+            get_rollback_pkg(&pkginfos, "dwm", Some(&alpm::Version::new("6.2-4")), None),
+            None
+        );
+
+        // requested version, with or without pkgrel
+        assert_eq!(
+            get_rollback_pkg(&pkginfos, "dash", Some(&current_version), Some("0.5.11-1")),
+            Some("backup_repo/dash-0.5.11-1-x86_64.pkg.tar.zst".into())
+        );
+        assert_eq!(
+            get_rollback_pkg(&pkginfos, "dash", Some(&current_version), Some("0.5.11")),
+            Some("backup_repo/dash-0.5.11-2-x86_64.pkg.tar.zst".into())
+        );
+        assert_eq!(get_rollback_pkg(&pkginfos, "dash", None, Some("0.5.10")), None);
+        assert_eq!(get_rollback_pkg(&pkginfos, "st", None, None), None);
+    }
+
     #[test]
     fn test_n_pkg_version() {
         let pkgs_list: Vec<String> = vec![
@@ -324,7 +536,7 @@ mod tests {
             "local_repo/x86_64/cachyos-cli-installer-new-0.7.0-2-x86_64.pkg.tar.zst".into(),
             "local_repo/x86_64/cachyos-cli-installer-new-0.7.0-3-x86_64.pkg.tar.zst".into(),
         ];
-        let pkg_version_slice = get_stale_pkg_versions(&pkgs_list, 2);
+        let pkg_version_slice = get_stale_pkg_versions(&pkginfo::load_pkginfos(&pkgs_list), 2);
 
         let expected_version_slice: PackageMap =
             HashMap::from([("cachyos-cli-installer-new".to_string(), vec![(
@@ -334,8 +546,9 @@ mod tests {
 
         assert_eq!(pkg_version_slice, expected_version_slice);
 
-        let mut pkg_version_slice =
-            get_stale_pkg_versions(&pkgs_list, 1).into_iter().collect::<Vec<_>>();
+        let mut pkg_version_slice = get_stale_pkg_versions(&pkginfo::load_pkginfos(&pkgs_list), 1)
+            .into_iter()
+            .collect::<Vec<_>>();
         pkg_version_slice.sort_by(|a, b| a.0.cmp(&b.0));
 
         let expected_version_slice = vec![
@@ -373,7 +586,7 @@ mod tests {
             "local_repo/x86_64/plymouth-theme-hud-3-git-r38.bf2f570-1-any.pkg.tar.zst".into(),
             "local_repo/x86_64/st-0.8.4-2-x86_64.pkg.tar.zst".into(),
         ];
-        let debug_pkg_list = get_debug_packages(&pkgs_list);
+        let debug_pkg_list = get_debug_packages(&pkginfo::load_pkginfos(&pkgs_list));
 
         let expected_debug_pkg_list: Vec<String> =
             vec!["local_repo/x86_64/bcachefs-tools-debug-3:1.11.0-1.1-x86_64.pkg.tar.zst".into()];
@@ -396,7 +609,7 @@ mod tests {
             "local_repo/x86_64/plymouth-theme-hud-3-git-r38.bf2f570-1-any.pkg.tar.zst".into(),
             "local_repo/x86_64/st-0.8.4-2-x86_64.pkg.tar.zst".into(),
         ];
-        let outdated_list = get_outdated_pkgs(&pkgs_list);
+        let outdated_list = get_outdated_pkgs(&pkginfo::load_pkginfos(&pkgs_list));
 
         let expected_outdated_list: Vec<String> = vec![
             "local_repo/x86_64/bcachefs-tools-3:1.9.4-1.1-x86_64.pkg.tar.zst".into(),
@@ -419,7 +632,7 @@ mod tests {
             "local_repo/x86_64/plymouth-theme-hud-3-git-r38.bf2f570-1-any.pkg.tar.zst".into(),
             "local_repo/x86_64/st-0.8.4-2-x86_64.pkg.tar.zst".into(),
         ];
-        let outdated_list = get_outdated_pkgs(&pkgs_list);
+        let outdated_list = get_outdated_pkgs(&pkginfo::load_pkginfos(&pkgs_list));
 
         let expected_outdated_list: Vec<String> = vec![];
         assert_eq!(outdated_list, expected_outdated_list);
@@ -496,7 +709,7 @@ mod tests {
             "local_repo/x86_64/plymouth-theme-hud-3-git-r38.bf2f570-1-any.pkg.tar.zst".into(),
             "local_repo/x86_64/st-0.8.4-2-x86_64.pkg.tar.zst".into(),
         ];
-        let new_pkgs_list = get_new_pkgs(&pkgs_list);
+        let new_pkgs_list = get_new_pkgs(&pkginfo::load_pkginfos(&pkgs_list));
 
         let expected_new_pkgs_list: Vec<String> = vec![
             "local_repo/x86_64/bcachefs-tools-3:1.11.0-1.1-x86_64.pkg.tar.zst".into(),
@@ -518,7 +731,7 @@ mod tests {
             "local_repo/x86_64/plymouth-theme-hud-3-git-r38.bf2f570-1-any.pkg.tar.zst".into(),
             "local_repo/x86_64/st-0.8.4-2-x86_64.pkg.tar.zst".into(),
         ];
-        let new_pkgs_list = get_new_pkgs(&pkgs_list);
+        let new_pkgs_list = get_new_pkgs(&pkginfo::load_pkginfos(&pkgs_list));
 
         let expected_new_pkgs_list: Vec<String> = vec![];
         assert_eq!(new_pkgs_list, expected_new_pkgs_list);
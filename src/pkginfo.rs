@@ -0,0 +1,339 @@
+use crate::pkg_utils;
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use subprocess::{Exec, Redirection};
+
+/// Package metadata as described by the `.PKGINFO` file inside of the package archive
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PackageInfo {
+    pub pkgname: String,
+    pub pkgbase: String,
+    /// Full version of the package, e.g `[epoch:]pkgver-pkgrel`
+    pub pkgver: String,
+    pub pkgdesc: String,
+    pub url: String,
+    pub arch: String,
+    pub licenses: Vec<String>,
+    pub groups: Vec<String>,
+    pub replaces: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub depends: Vec<String>,
+    pub optdepends: Vec<String>,
+    pub makedepends: Vec<String>,
+    pub checkdepends: Vec<String>,
+    pub provides: Vec<String>,
+    pub packager: String,
+    pub builddate: i64,
+    /// Installed size of the package in bytes
+    pub size: u64,
+}
+
+impl PackageInfo {
+    pub fn version(&self) -> alpm::Version {
+        alpm::Version::new(self.pkgver.as_str())
+    }
+
+    /// Returns `pkgname-pkgver` pair as it appears in the repo DB
+    pub fn db_pair(&self) -> String {
+        format!("{}-{}", self.pkgname, self.pkgver)
+    }
+}
+
+/// Parses content of the `.PKGINFO` file
+pub fn parse_pkginfo(content: &str) -> Result<PackageInfo> {
+    let mut pkginfo = PackageInfo::default();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            log::debug!("Skipping malformed .PKGINFO line: '{line}'");
+            continue;
+        };
+        let value = value.trim().to_owned();
+        match key.trim() {
+            "pkgname" => pkginfo.pkgname = value,
+            "pkgbase" => pkginfo.pkgbase = value,
+            "pkgver" => pkginfo.pkgver = value,
+            "pkgdesc" => pkginfo.pkgdesc = value,
+            "url" => pkginfo.url = value,
+            "arch" => pkginfo.arch = value,
+            "license" => pkginfo.licenses.push(value),
+            "group" => pkginfo.groups.push(value),
+            "replaces" => pkginfo.replaces.push(value),
+            "conflict" => pkginfo.conflicts.push(value),
+            "depend" => pkginfo.depends.push(value),
+            "optdepend" => pkginfo.optdepends.push(value),
+            "makedepend" => pkginfo.makedepends.push(value),
+            "checkdepend" => pkginfo.checkdepends.push(value),
+            "provides" => pkginfo.provides.push(value),
+            "packager" => pkginfo.packager = value,
+            "builddate" => {
+                pkginfo.builddate = value.parse().context("Invalid builddate in .PKGINFO")?
+            },
+            "size" => pkginfo.size = value.parse().context("Invalid size in .PKGINFO")?,
+            _ => {},
+        }
+    }
+
+    if pkginfo.pkgname.is_empty() || pkginfo.pkgver.is_empty() {
+        anyhow::bail!(".PKGINFO doesn't contain pkgname or pkgver");
+    }
+    if pkginfo.pkgbase.is_empty() {
+        pkginfo.pkgbase = pkginfo.pkgname.clone();
+    }
+
+    Ok(pkginfo)
+}
+
+/// Reads and parses `.PKGINFO` from the package archive
+pub fn read_pkginfo(pkg_filepath: &str) -> Result<PackageInfo> {
+    let content = read_pkginfo_content(pkg_filepath)
+        .with_context(|| format!("Failed to read .PKGINFO from '{pkg_filepath}'"))?;
+    parse_pkginfo(&content).with_context(|| format!("Failed to parse .PKGINFO of '{pkg_filepath}'"))
+}
+
+/// Gets package metadata from the package file.
+///
+/// The filename is used only as a hint, when the package archive cannot be read (e.g the file
+/// doesn't exist anymore).
+pub fn load_pkginfo(pkg_filepath: &str) -> Option<PackageInfo> {
+    load_pkginfo_with(pkg_filepath, read_pkginfo)
+}
+
+/// Same as [`load_pkginfo`], with `.PKGINFO` of the package archive read by the provided function
+pub fn load_pkginfo_with(
+    pkg_filepath: &str,
+    read_pkginfo: impl FnOnce(&str) -> Result<PackageInfo>,
+) -> Option<PackageInfo> {
+    if Path::new(pkg_filepath).exists() {
+        match read_pkginfo(pkg_filepath) {
+            Ok(pkginfo) => {
+                check_filename_hint(pkg_filepath, &pkginfo);
+                return Some(pkginfo);
+            },
+            Err(pkginfo_err) => log::warn!("{pkginfo_err:#}, falling back to the filename"),
+        }
+    }
+
+    let pkginfo = get_pkginfo_from_filename(pkg_filepath);
+    if pkginfo.is_none() {
+        log::error!("Failed to get package info of '{pkg_filepath}'");
+    }
+    pkginfo
+}
+
+/// Gets package metadata for each of the package files, skipping packages which cannot be
+/// identified. The commands go through [`crate::plan::Executor::load_pkginfos`] instead.
+#[cfg(test)]
+pub fn load_pkginfos(pkg_list: &[String]) -> Vec<(String, PackageInfo)> {
+    pkg_list
+        .iter()
+        .filter_map(|pkg_filepath| Some((pkg_filepath.clone(), load_pkginfo(pkg_filepath)?)))
+        .collect()
+}
+
+/// Builds partial package metadata from the package filename,
+/// e.g `pkgname-pkgver-pkgrel-arch.pkg.tar.zst`
+pub fn get_pkginfo_from_filename(pkg_filepath: &str) -> Option<PackageInfo> {
+    let pkg_filename = Path::new(pkg_filepath).file_name()?.to_str()?;
+    let (pkgname, pkgver, arch) = pkg_utils::parse_pkg_filename(pkg_filename)?;
+
+    Some(PackageInfo {
+        pkgname: pkgname.to_owned(),
+        pkgbase: pkgname.to_owned(),
+        pkgver: pkgver.to_owned(),
+        arch: arch.to_owned(),
+        ..Default::default()
+    })
+}
+
+/// Lists files contained in the package archive, skipping the package metadata files.
+///
+/// Directories are listed with the trailing slash, the list is sorted the same way as repo-add
+/// does.
+pub fn read_pkg_filelist(pkg_filepath: &str) -> Result<Vec<String>> {
+    let mut filelist = match open_pkg_archive(pkg_filepath)? {
+        Some(mut archive) => {
+            let mut filelist = vec![];
+            for entry in archive.entries()? {
+                let entry = entry?;
+                let mut entry_path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+                if entry.header().entry_type().is_dir() && !entry_path.ends_with('/') {
+                    entry_path.push('/');
+                }
+                filelist.push(entry_path);
+            }
+            filelist
+        },
+        None => run_bsdtar(&["-tf", pkg_filepath])?.lines().map(str::to_owned).collect(),
+    };
+    filelist.retain(|entry_path| !entry_path.starts_with('.'));
+    filelist.sort();
+    filelist.dedup();
+
+    Ok(filelist)
+}
+
+fn check_filename_hint(pkg_filepath: &str, pkginfo: &PackageInfo) {
+    let Some(hint) = get_pkginfo_from_filename(pkg_filepath) else {
+        log::debug!("Package file '{pkg_filepath}' doesn't follow the naming scheme");
+        return;
+    };
+    if hint.pkgname != pkginfo.pkgname || hint.pkgver != pkginfo.pkgver {
+        log::warn!(
+            "Package file '{pkg_filepath}' is named differently from its .PKGINFO: '{}'",
+            pkginfo.db_pair()
+        );
+    }
+}
+
+// Opens package archive for reading.
+// Returns None if the compression format is not supported natively
+fn open_pkg_archive(pkg_filepath: &str) -> Result<Option<tar::Archive<Box<dyn Read>>>> {
+    let pkg_file = BufReader::new(File::open(pkg_filepath)?);
+    let reader: Box<dyn Read> = if pkg_filepath.ends_with(".zst") {
+        Box::new(zstd::stream::read::Decoder::with_buffer(pkg_file)?)
+    } else if pkg_filepath.ends_with(".xz") {
+        Box::new(xz2::read::XzDecoder::new(pkg_file))
+    } else if pkg_filepath.ends_with(".gz") {
+        Box::new(flate2::read::GzDecoder::new(pkg_file))
+    } else if pkg_filepath.ends_with(".tar") {
+        Box::new(pkg_file)
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(tar::Archive::new(reader)))
+}
+
+fn read_pkginfo_content(pkg_filepath: &str) -> Result<String> {
+    let Some(mut archive) = open_pkg_archive(pkg_filepath)? else {
+        // let libarchive deal with the rest of compression formats
+        return run_bsdtar(&["-xOf", pkg_filepath, ".PKGINFO"]);
+    };
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_os_str() != ".PKGINFO" {
+            continue;
+        }
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        return Ok(content);
+    }
+
+    anyhow::bail!("Package archive doesn't contain .PKGINFO");
+}
+
+fn run_bsdtar(bsdtar_args: &[&str]) -> Result<String> {
+    let output = Exec::cmd("bsdtar")
+        .args(bsdtar_args)
+        .stderr(Redirection::Merge)
+        .stdout(Redirection::Pipe)
+        .capture()?;
+
+    let proc_output = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.success() {
+        anyhow::bail!("bsdtar failed: {proc_output}");
+    }
+
+    Ok(proc_output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pkginfo::*;
+
+    #[test]
+    fn test_parse_pkginfo() {
+        let content = r#"# Generated by makepkg 6.1.0
+# using fakeroot version 1.34
+pkgname = linux-cachyos-headers
+pkgbase = linux-cachyos
+xdata = pkgtype=split
+pkgver = 6.10.5-1
+pkgdesc = Headers and scripts for building modules for the Linux EEVDF-BORE scheduler
+url = https://github.com/CachyOS/linux-cachyos
+builddate = 1723812345
+packager = CachyOS <admin@cachyos.org>
+size = 171839488
+arch = x86_64_v3
+license = GPL-2.0-only
+provides = linux-headers=6.10.5
+depend = pahole
+depend = glibc
+"#;
+        let expected_pkginfo = PackageInfo {
+            pkgname: "linux-cachyos-headers".into(),
+            pkgbase: "linux-cachyos".into(),
+            pkgver: "6.10.5-1".into(),
+            pkgdesc: "Headers and scripts for building modules for the Linux EEVDF-BORE scheduler"
+                .into(),
+            url: "https://github.com/CachyOS/linux-cachyos".into(),
+            arch: "x86_64_v3".into(),
+            licenses: vec!["GPL-2.0-only".into()],
+            depends: vec!["pahole".into(), "glibc".into()],
+            provides: vec!["linux-headers=6.10.5".into()],
+            packager: "CachyOS <admin@cachyos.org>".into(),
+            builddate: 1723812345,
+            size: 171839488,
+            ..Default::default()
+        };
+        assert_eq!(parse_pkginfo(content).unwrap(), expected_pkginfo);
+    }
+
+    #[test]
+    fn test_parse_pkginfo_without_pkgbase() {
+        let pkginfo = parse_pkginfo("pkgname = dash\npkgver = 0.5.12-1.1\n").unwrap();
+        assert_eq!(pkginfo.pkgbase, "dash");
+        assert_eq!(pkginfo.db_pair(), "dash-0.5.12-1.1");
+
+        assert!(parse_pkginfo("pkgname = dash\n").is_err());
+        assert!(parse_pkginfo("pkgname = dash\npkgver = 1-1\nsize = abc\n").is_err());
+    }
+
+    #[test]
+    fn test_pkginfo_from_filename() {
+        let pkginfo =
+            get_pkginfo_from_filename("/to/file/bcachefs-tools-3:1.11.0-1.1-x86_64.pkg.tar.zst")
+                .unwrap();
+        assert_eq!(pkginfo.pkgname, "bcachefs-tools");
+        assert_eq!(pkginfo.pkgver, "3:1.11.0-1.1");
+        assert_eq!(pkginfo.arch, "x86_64");
+
+        assert_eq!(get_pkginfo_from_filename("/to/file/renamed.pkg.tar.zst"), None);
+        assert_eq!(get_pkginfo_from_filename("/to/file/-1-x86_64.pkg.tar.zst"), None);
+    }
+
+    #[test]
+    fn test_read_pkginfo_from_archive() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        // the filename deliberately doesn't match the package metadata
+        let pkg_filepath = format!("{temp_dir}/renamed.pkg.tar.zst");
+
+        let pkginfo_content = b"pkgname = dash\npkgver = 0.5.12-1.1\narch = x86_64\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(pkginfo_content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        let encoder = zstd::stream::write::Encoder::new(File::create(&pkg_filepath).unwrap(), 0)
+            .unwrap()
+            .auto_finish();
+        let mut builder = tar::Builder::new(encoder);
+        builder.append_data(&mut header, ".PKGINFO", &pkginfo_content[..]).unwrap();
+        builder.into_inner().unwrap();
+
+        let pkginfo = load_pkginfo(&pkg_filepath).unwrap();
+        assert_eq!(pkginfo.db_pair(), "dash-0.5.12-1.1");
+        assert_eq!(pkginfo.arch, "x86_64");
+
+        std::fs::remove_dir_all(temp_dir).unwrap();
+    }
+}
@@ -0,0 +1,521 @@
+use crate::alpm_helper::DbPackage;
+use crate::journal::{self, Journal};
+use crate::pkginfo::{self, PackageInfo};
+use crate::prompt::Prompter;
+use crate::repo_utils::RepoBackend;
+use crate::signature::{SignatureError, SignatureVerifier};
+use crate::{pkg_utils, repo_db};
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::{fmt, fs};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Single mutating step of a command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanStep {
+    MoveFile {
+        src: String,
+        dest: String,
+    },
+    CopyFile {
+        src: String,
+        dest: String,
+    },
+    RemoveFile(String),
+    /// New file with the provided content
+    WriteFile {
+        path: String,
+        content: String,
+    },
+    /// Package files to add into the repo DB
+    RepoAdd(Vec<String>),
+    /// Package names to remove from the repo DB
+    RepoRemove(Vec<String>),
+    /// Package files to recreate the repo DB with
+    RepoRebuild(Vec<String>),
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MoveFile { src, dest } => write!(f, "move '{src}' -> '{dest}'"),
+            Self::CopyFile { src, dest } => write!(f, "copy '{src}' -> '{dest}'"),
+            Self::RemoveFile(path) => write!(f, "delete '{path}'"),
+            Self::WriteFile { path, .. } => write!(f, "write '{path}'"),
+            Self::RepoAdd(pkgfiles) => write!(f, "repo-add {pkgfiles:?}"),
+            Self::RepoRemove(pkgname_list) => write!(f, "repo-remove {pkgname_list:?}"),
+            Self::RepoRebuild(pkgfiles) => write!(f, "recreate repo DB with {pkgfiles:?}"),
+        }
+    }
+}
+
+/// Performs the mutating steps of the commands, or only records them on dry run.
+///
+/// On dry run the planned file moves and removals are tracked, so the later steps of the same
+/// command see the directories as they would look like after a real run.
+pub struct Executor<'a> {
+    backend: &'a mut dyn RepoBackend,
+    dry_run: bool,
+    plan: Vec<PlanStep>,
+    prompter: Prompter,
+    journal: Option<Journal>,
+    // glob patterns of the package files in addition to the known extensions
+    extra_pkg_patterns: Vec<String>,
+    signature_verifier: Option<SignatureVerifier>,
+    // entries of the repo DB, read once until the steps modify it
+    db_pkgs: RefCell<Option<Vec<DbPackage>>>,
+    // `.PKGINFO` of the package files read so far, by the source path
+    pkginfos: RefCell<HashMap<String, PackageInfo>>,
+    // repo DBs which are modified by the steps
+    modified_dbs: BTreeSet<String>,
+    // files which exist only in the plan
+    planned_files: BTreeSet<String>,
+    // files which are gone in the plan
+    removed_files: BTreeSet<String>,
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(backend: &'a mut dyn RepoBackend, dry_run: bool) -> Self {
+        Self {
+            backend,
+            dry_run,
+            plan: vec![],
+            prompter: Prompter::disabled(),
+            journal: None,
+            extra_pkg_patterns: vec![],
+            signature_verifier: None,
+            db_pkgs: RefCell::new(None),
+            pkginfos: RefCell::new(HashMap::new()),
+            modified_dbs: BTreeSet::new(),
+            planned_files: BTreeSet::new(),
+            removed_files: BTreeSet::new(),
+        }
+    }
+
+    /// Asks the user for confirmation of the steps, nothing is asked on dry run
+    pub fn with_prompter(mut self, prompter: Prompter) -> Self {
+        self.prompter = prompter;
+        self
+    }
+
+    /// Records the performed steps in the journal, see [`Journal`]
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Recognizes files matching the glob patterns as package files as well
+    pub fn with_extra_pkg_patterns(mut self, extra_pkg_patterns: &[String]) -> Self {
+        self.extra_pkg_patterns = extra_pkg_patterns.to_vec();
+        self
+    }
+
+    /// Verifies the package signatures against the trusted keys, see [`Executor::check_signature`]
+    pub fn with_signature_verifier(mut self, signature_verifier: SignatureVerifier) -> Self {
+        self.signature_verifier = Some(signature_verifier);
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Makes the following repo DB steps use another backend, returns the previous one
+    pub fn replace_backend(&mut self, backend: &'a mut dyn RepoBackend) -> &'a mut dyn RepoBackend {
+        *self.db_pkgs.get_mut() = None;
+        std::mem::replace(&mut self.backend, backend)
+    }
+
+    /// Steps performed (or planned on dry run) so far, in order
+    pub fn plan(&self) -> &[PlanStep] {
+        &self.plan
+    }
+
+    /// Asks whether to proceed with the single item, see [`Prompter::confirm_item`]
+    pub fn confirm_item(&mut self, question: &str) -> Result<bool> {
+        if self.dry_run {
+            return Ok(true);
+        }
+        self.prompter.confirm_item(question)
+    }
+
+    fn confirm_summary(&mut self, title: &str, items: &[String]) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.prompter.confirm_summary(title, items)
+    }
+
+    /// Lists the entries of the repo DB, which is read only once until the steps modify it
+    pub fn list_entries(&self) -> Result<Vec<DbPackage>> {
+        if let Some(db_pkgs) = self.db_pkgs.borrow().as_ref() {
+            return Ok(db_pkgs.clone());
+        }
+        let db_pkgs = self.backend.list_entries()?;
+        *self.db_pkgs.borrow_mut() = Some(db_pkgs.clone());
+        Ok(db_pkgs)
+    }
+
+    /// Reads `.PKGINFO` of the package file, taking the planned steps into account.
+    ///
+    /// The package archive is read only once, unless the steps replace the file.
+    pub fn read_pkginfo(&self, pkgfile: &str) -> Result<PackageInfo> {
+        let source_path = self.source_path(pkgfile);
+        if let Some(pkginfo) = self.pkginfos.borrow().get(&source_path) {
+            return Ok(pkginfo.clone());
+        }
+        let pkginfo = pkginfo::read_pkginfo(&source_path)?;
+        self.pkginfos.borrow_mut().insert(source_path, pkginfo.clone());
+        Ok(pkginfo)
+    }
+
+    /// Gets package metadata of the package file, see [`pkginfo::load_pkginfo`]
+    pub fn load_pkginfo(&self, pkgfile: &str) -> Option<PackageInfo> {
+        pkginfo::load_pkginfo_with(&self.source_path(pkgfile), |_| self.read_pkginfo(pkgfile))
+    }
+
+    /// Gets package metadata for each of the package files, skipping packages which cannot be
+    /// identified
+    pub fn load_pkginfos(&self, pkg_list: &[String]) -> Vec<(String, PackageInfo)> {
+        pkg_list
+            .iter()
+            .filter_map(|pkgfile| Some((pkgfile.clone(), self.load_pkginfo(pkgfile)?)))
+            .collect()
+    }
+
+    /// Checks if the file exists, taking the planned steps into account
+    pub fn exists(&self, path: &str) -> bool {
+        if self.planned_files.contains(path) {
+            return true;
+        }
+        !self.removed_files.contains(path) && Path::new(path).exists()
+    }
+
+    /// Checks the detached signature of the package file, taking the planned steps into account.
+    ///
+    /// The signature is verified if there is the signature verifier, returns fingerprint of the
+    /// signing key then. The missing signature is an error only if it's required.
+    pub fn check_signature(&self, pkgfile: &str, required: bool) -> Result<Option<String>> {
+        let sig_path = format!("{pkgfile}.sig");
+        if !self.exists(&sig_path) {
+            if required {
+                return Err(SignatureError::Missing(sig_path).into());
+            }
+            return Ok(None);
+        }
+        let Some(signature_verifier) = &self.signature_verifier else {
+            return Ok(None);
+        };
+
+        let fingerprint =
+            signature_verifier.verify(&self.source_path(pkgfile), &self.source_path(&sig_path))?;
+        log::info!(
+            "Verified signature of '{}' by {fingerprint}",
+            pkg_utils::get_pkg_db_pair_from_path(pkgfile)
+        );
+        Ok(Some(fingerprint))
+    }
+
+    /// Finds the file on disk which is planned to be moved or copied to the path on dry run
+    pub fn source_path(&self, path: &str) -> String {
+        let mut path = path.to_owned();
+        if !self.dry_run {
+            return path;
+        }
+        for step in self.plan.iter().rev() {
+            match step {
+                PlanStep::MoveFile { src, dest } | PlanStep::CopyFile { src, dest }
+                    if *dest == path =>
+                {
+                    path = src.clone()
+                },
+                _ => {},
+            }
+        }
+        path
+    }
+
+    /// Lists package files of the directory, taking the planned steps into account
+    pub fn list_pkgfiles(&self, dir: &str) -> Result<Vec<String>> {
+        let mut pkgfiles = pkg_utils::list_pkgfiles(dir, &self.extra_pkg_patterns)?;
+        pkgfiles.retain(|pkgfile| !self.removed_files.contains(pkgfile));
+        pkgfiles.extend(
+            self.planned_files
+                .iter()
+                .filter(|path| {
+                    pkg_utils::is_pkgfile(path, &self.extra_pkg_patterns)
+                        && Path::new(path).parent() == Some(Path::new(dir))
+                })
+                .cloned(),
+        );
+        pkgfiles.sort();
+        pkgfiles.dedup();
+        Ok(pkgfiles)
+    }
+
+    pub fn move_file(&mut self, src: &str, dest: &str) -> Result<()> {
+        self.forget_pkginfos(&[src, dest]);
+        if self.dry_run {
+            if !self.exists(src) {
+                anyhow::bail!("'{src}' doesn't exist");
+            }
+            self.planned_files.remove(src);
+            self.removed_files.insert(src.to_owned());
+            self.removed_files.remove(dest);
+            self.planned_files.insert(dest.to_owned());
+        } else {
+            let step = PlanStep::MoveFile { src: src.to_owned(), dest: dest.to_owned() };
+            self.journaled(&step, None, || journal::move_path(src, dest))?;
+        }
+        self.plan.push(PlanStep::MoveFile { src: src.to_owned(), dest: dest.to_owned() });
+        Ok(())
+    }
+
+    pub fn copy_file(&mut self, src: &str, dest: &str) -> Result<()> {
+        self.forget_pkginfos(&[dest]);
+        if self.dry_run {
+            if !self.exists(src) {
+                anyhow::bail!("'{src}' doesn't exist");
+            }
+            self.removed_files.remove(dest);
+            self.planned_files.insert(dest.to_owned());
+        } else {
+            let step = PlanStep::CopyFile { src: src.to_owned(), dest: dest.to_owned() };
+            self.journaled(&step, None, || Ok(fs::copy(src, dest).map(|_| ())?))?;
+        }
+        self.plan.push(PlanStep::CopyFile { src: src.to_owned(), dest: dest.to_owned() });
+        Ok(())
+    }
+
+    pub fn remove_file(&mut self, path: &str) -> Result<()> {
+        self.forget_pkginfos(&[path]);
+        if self.dry_run {
+            if !self.exists(path) {
+                anyhow::bail!("'{path}' doesn't exist");
+            }
+            self.planned_files.remove(path);
+            self.removed_files.insert(path.to_owned());
+        } else if let Some(journal) = self.journal.as_mut() {
+            // the file is kept in the journal until the command finishes
+            let entry_index = journal.begin(&PlanStep::RemoveFile(path.to_owned()), None)?;
+            journal::move_path(path, journal.entry(entry_index).trash_path.as_ref().unwrap())?;
+            journal.finish(entry_index)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        self.plan.push(PlanStep::RemoveFile(path.to_owned()));
+        Ok(())
+    }
+
+    pub fn write_file(&mut self, path: &str, content: &str) -> Result<()> {
+        let step = PlanStep::WriteFile { path: path.to_owned(), content: content.to_owned() };
+        self.forget_pkginfos(&[path]);
+        if self.dry_run {
+            self.removed_files.remove(path);
+            self.planned_files.insert(path.to_owned());
+        } else {
+            self.journaled(&step, None, || Ok(fs::write(path, content)?))?;
+        }
+        self.plan.push(step);
+        Ok(())
+    }
+
+    pub fn repo_add(&mut self, pkgfiles: &[String]) -> Result<()> {
+        self.confirm_summary("Packages to add into the repo DB", pkgfiles)?;
+        if !self.dry_run {
+            let step = PlanStep::RepoAdd(pkgfiles.to_vec());
+            let db_path = self.backend.db_path().to_owned();
+            let backend = &mut *self.backend;
+            journaled(self.journal.as_mut(), &step, Some(&db_path), || backend.add(pkgfiles))?;
+        }
+        *self.db_pkgs.get_mut() = None;
+        self.modified_dbs.insert(self.backend.db_path().to_owned());
+        self.plan.push(PlanStep::RepoAdd(pkgfiles.to_vec()));
+        Ok(())
+    }
+
+    pub fn repo_remove(&mut self, pkgname_list: &[String]) -> Result<()> {
+        self.confirm_summary("Packages to remove from the repo DB", pkgname_list)?;
+        if !self.dry_run {
+            let step = PlanStep::RepoRemove(pkgname_list.to_vec());
+            let db_path = self.backend.db_path().to_owned();
+            let backend = &mut *self.backend;
+            journaled(self.journal.as_mut(), &step, Some(&db_path), || {
+                backend.remove(pkgname_list)
+            })?;
+        }
+        *self.db_pkgs.get_mut() = None;
+        self.modified_dbs.insert(self.backend.db_path().to_owned());
+        self.plan.push(PlanStep::RepoRemove(pkgname_list.to_vec()));
+        Ok(())
+    }
+
+    pub fn repo_rebuild(&mut self, pkgfiles: &[String]) -> Result<()> {
+        self.confirm_summary("Packages to recreate the repo DB with", pkgfiles)?;
+        if !self.dry_run {
+            let step = PlanStep::RepoRebuild(pkgfiles.to_vec());
+            let db_path = self.backend.db_path().to_owned();
+            let backend = &mut *self.backend;
+            journaled(self.journal.as_mut(), &step, Some(&db_path), || backend.rebuild(pkgfiles))?;
+        }
+        *self.db_pkgs.get_mut() = None;
+        self.modified_dbs.insert(self.backend.db_path().to_owned());
+        self.plan.push(PlanStep::RepoRebuild(pkgfiles.to_vec()));
+        Ok(())
+    }
+
+    /// Drops the journal once the command succeeded.
+    ///
+    /// The journal of the failed command is kept only if some step was interrupted, the finished
+    /// steps are consistent on their own.
+    pub fn finish_journal(&mut self, succeeded: bool) -> Result<()> {
+        let Some(journal) = self.journal.take() else {
+            return Ok(());
+        };
+        if !succeeded && journal.has_unfinished_steps() {
+            log::error!("The command was interrupted, run recover to roll it forward or back");
+            return Ok(());
+        }
+        journal.commit()
+    }
+
+    // Drops `.PKGINFO` read from the files which the step replaces or removes, the files are
+    // left untouched on dry run
+    fn forget_pkginfos(&mut self, paths: &[&str]) {
+        if self.dry_run {
+            return;
+        }
+        let pkginfos = self.pkginfos.get_mut();
+        for path in paths {
+            pkginfos.remove(*path);
+        }
+    }
+
+    fn journaled(
+        &mut self,
+        step: &PlanStep,
+        db_path: Option<&str>,
+        operation: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        journaled(self.journal.as_mut(), step, db_path, operation)
+    }
+
+    /// Prints the steps recorded on dry run
+    pub fn print_plan(&self) {
+        if self.plan().is_empty() {
+            log::info!("[dry-run] Nothing to do");
+            return;
+        }
+
+        log::info!("[dry-run] Nothing was changed, a real run would do the following:");
+        for step in &self.plan {
+            log::info!("[dry-run] {step}");
+        }
+
+        if !self.modified_dbs.is_empty() {
+            let mut db_files = vec![];
+            for db_path in &self.modified_dbs {
+                db_files.push(db_path.clone());
+                if let Ok(files_db_path) = repo_db::get_files_db_path(db_path) {
+                    db_files.push(files_db_path);
+                }
+            }
+            log::info!("[dry-run] DB files to regenerate: {db_files:?}");
+        }
+    }
+}
+
+// Performs the operation, recording it in the journal if there is one
+fn journaled(
+    journal: Option<&mut Journal>,
+    step: &PlanStep,
+    db_path: Option<&str>,
+    operation: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let Some(journal) = journal else {
+        return operation();
+    };
+    let entry_index = journal.begin(step, db_path)?;
+    operation()?;
+    journal.finish(entry_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plan::*;
+    use crate::repo_utils::FakeBackend;
+
+    #[test]
+    fn test_dry_run_overlay() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let dest_dir = format!("{temp_dir}/dest");
+        fs::create_dir(&dest_dir).unwrap();
+        let pkgfile = format!("{temp_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        let moved_pkgfile = format!("{dest_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        fs::write(&pkgfile, "").unwrap();
+
+        let mut backend = FakeBackend::default();
+        let mut executor = Executor::new(&mut backend, true);
+        executor.move_file(&pkgfile, &moved_pkgfile).unwrap();
+        assert!(!executor.exists(&pkgfile));
+        assert!(executor.exists(&moved_pkgfile));
+        assert_eq!(executor.list_pkgfiles(&temp_dir).unwrap(), Vec::<String>::new());
+        assert_eq!(executor.list_pkgfiles(&dest_dir).unwrap(), vec![moved_pkgfile.clone()]);
+
+        // the file is gone in the plan already
+        assert!(executor.remove_file(&pkgfile).is_err());
+        executor.remove_file(&moved_pkgfile).unwrap();
+        executor.repo_add(std::slice::from_ref(&moved_pkgfile)).unwrap();
+        assert_eq!(executor.plan(), &[
+            PlanStep::MoveFile { src: pkgfile.clone(), dest: moved_pkgfile.clone() },
+            PlanStep::RemoveFile(moved_pkgfile.clone()),
+            PlanStep::RepoAdd(vec![moved_pkgfile.clone()]),
+        ]);
+
+        // nothing is touched on the disk and in the DB
+        assert!(Path::new(&pkgfile).exists());
+        assert!(!Path::new(&moved_pkgfile).exists());
+        assert!(backend.added.is_empty());
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_cached_reads() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let pkgfile = format!("{temp_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        let rebuilt_pkgfile = format!("{temp_dir}/rebuilt.pkg.tar.zst");
+        let pkginfo_content = "pkgname = dash\npkgver = 0.5.12-1\narch = x86_64\n";
+        crate::utils::create_test_pkg(&pkgfile, &[(".PKGINFO", pkginfo_content)]);
+        let rebuilt_content = format!("{pkginfo_content}packager = Rebuilder\n");
+        crate::utils::create_test_pkg(&rebuilt_pkgfile, &[(".PKGINFO", &rebuilt_content)]);
+
+        let mut backend = FakeBackend::default();
+        let mut executor = Executor::new(&mut backend, false);
+        assert!(executor.list_entries().unwrap().is_empty());
+        assert_eq!(executor.read_pkginfo(&pkgfile).unwrap().packager, "");
+
+        // the package archive is read once per command
+        fs::copy(&rebuilt_pkgfile, &pkgfile).unwrap();
+        assert_eq!(executor.load_pkginfo(&pkgfile).unwrap().packager, "");
+        // unless the steps replace the file
+        executor.copy_file(&rebuilt_pkgfile, &pkgfile).unwrap();
+        assert_eq!(executor.read_pkginfo(&pkgfile).unwrap().packager, "Rebuilder");
+
+        // the steps modifying the repo DB drop the entries read before
+        executor.repo_add(std::slice::from_ref(&pkgfile)).unwrap();
+        let db_pkgs = executor.list_entries().unwrap();
+        assert_eq!(db_pkgs.iter().map(|x| x.db_pair()).collect::<Vec<_>>(), vec![
+            "dash-0.5.12-1".to_owned()
+        ]);
+        executor.repo_remove(&["dash".to_owned()]).unwrap();
+        assert!(executor.list_entries().unwrap().is_empty());
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+}
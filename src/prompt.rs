@@ -0,0 +1,162 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use anyhow::Result;
+
+/// Answer to the per-item question
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    /// Yes for this and all following items
+    All,
+    /// Stop the command
+    Quit,
+}
+
+impl Answer {
+    fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "y" | "yes" => Some(Self::Yes),
+            "" | "n" | "no" => Some(Self::No),
+            "a" | "all" => Some(Self::All),
+            "q" | "quit" => Some(Self::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// Asks the user for confirmation of destructive steps
+pub struct Prompter {
+    enabled: bool,
+    // user answered 'all' already
+    accept_all: bool,
+    input: Box<dyn BufRead>,
+}
+
+impl Prompter {
+    /// Creates the prompter for the profile setting, it never asks anything when the confirmation
+    /// is overriden or the stdin is not a terminal
+    pub fn new(interactive: bool, assume_yes: bool) -> Self {
+        let is_tty = io::stdin().is_terminal();
+        if interactive && !assume_yes && !is_tty {
+            log::warn!("stdin is not a terminal, falling back to non-interactive mode");
+        }
+
+        Self {
+            enabled: interactive && !assume_yes && is_tty,
+            accept_all: false,
+            input: Box::new(io::BufReader::new(io::stdin())),
+        }
+    }
+
+    /// Creates the prompter which confirms everything
+    pub fn disabled() -> Self {
+        Self { enabled: false, accept_all: false, input: Box::new(io::empty()) }
+    }
+
+    /// Creates the prompter which reads answers from the provided input
+    #[cfg(test)]
+    pub fn with_input(input: &str) -> Self {
+        Self {
+            enabled: true,
+            accept_all: false,
+            input: Box::new(io::Cursor::new(input.as_bytes().to_vec())),
+        }
+    }
+
+    /// Asks whether to proceed with the single item.
+    ///
+    /// Returns error when the user decides to quit.
+    pub fn confirm_item(&mut self, question: &str) -> Result<bool> {
+        if !self.enabled || self.accept_all {
+            return Ok(true);
+        }
+
+        match self.ask(&format!("{question} [y/N/a/q] "))? {
+            Answer::Yes => Ok(true),
+            Answer::No => Ok(false),
+            Answer::All => {
+                self.accept_all = true;
+                Ok(true)
+            },
+            Answer::Quit => anyhow::bail!("Aborted by user"),
+        }
+    }
+
+    /// Shows the summary of the step and asks whether to proceed with it.
+    ///
+    /// Returns error when the user declines.
+    pub fn confirm_summary(&mut self, title: &str, items: &[String]) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        println!("{title}:");
+        for item in items {
+            println!("  {item}");
+        }
+        match self.ask("Proceed? [y/N] ")? {
+            Answer::Yes | Answer::All => Ok(()),
+            Answer::No | Answer::Quit => anyhow::bail!("Aborted by user"),
+        }
+    }
+
+    fn ask(&mut self, question: &str) -> Result<Answer> {
+        loop {
+            print!("{question}");
+            io::stdout().flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                // no more input, be on the safe side
+                println!();
+                return Ok(Answer::Quit);
+            }
+            if let Some(answer) = Answer::parse(&line) {
+                return Ok(answer);
+            }
+            println!("Unknown answer '{}'", line.trim());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prompt::*;
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(Answer::parse("y\n"), Some(Answer::Yes));
+        assert_eq!(Answer::parse("YES"), Some(Answer::Yes));
+        assert_eq!(Answer::parse("\n"), Some(Answer::No));
+        assert_eq!(Answer::parse("a"), Some(Answer::All));
+        assert_eq!(Answer::parse("quit"), Some(Answer::Quit));
+        assert_eq!(Answer::parse("maybe"), None);
+    }
+
+    #[test]
+    fn test_confirm_item() {
+        let mut prompter = Prompter::with_input("n\nwhat\ny\na\n");
+        assert!(!prompter.confirm_item("rm dash?").unwrap());
+        // unknown answer is asked again
+        assert!(prompter.confirm_item("rm dwm?").unwrap());
+        assert!(prompter.confirm_item("rm st?").unwrap());
+        // no more questions after 'all'
+        assert!(prompter.confirm_item("rm pacman?").unwrap());
+
+        let mut prompter = Prompter::with_input("q\n");
+        assert!(prompter.confirm_item("rm dash?").is_err());
+        // end of input quits as well
+        assert!(Prompter::with_input("").confirm_item("rm dash?").is_err());
+
+        assert!(Prompter::disabled().confirm_item("rm dash?").unwrap());
+    }
+
+    #[test]
+    fn test_confirm_summary() {
+        let items = vec!["dash".to_owned()];
+        assert!(Prompter::with_input("y\n").confirm_summary("repo-add", &items).is_ok());
+        assert!(Prompter::with_input("n\n").confirm_summary("repo-add", &items).is_err());
+        assert!(Prompter::disabled().confirm_summary("repo-add", &items).is_ok());
+    }
+}
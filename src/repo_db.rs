@@ -0,0 +1,740 @@
+use crate::pkginfo;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs as unix_fs;
+use std::path::Path;
+use std::{fmt, time};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use subprocess::{Exec, Redirection};
+
+/// Errors reported by the native repo DB writer
+#[derive(Debug, PartialEq)]
+pub enum RepoDbError {
+    /// The repo DB filename doesn't look like `<repo>.db.tar[.ext]`
+    InvalidDbPath(String),
+    /// The compression of the repo DB is not supported by the native writer
+    UnsupportedCompression(String),
+    /// The repo DB contains entry without name or version
+    MalformedEntry(String),
+    /// The package would replace newer version in the repo DB
+    Downgrade { pkgname: String, db_version: String, pkg_version: String },
+    /// Verification of the existing repo DB signature failed
+    VerifyFailed(String),
+    /// Signing of the repo DB failed
+    SignFailed(String),
+}
+
+impl fmt::Display for RepoDbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidDbPath(db_path) => {
+                write!(f, "Invalid repo DB path '{db_path}', expected '<repo>.db.tar[.ext]'")
+            },
+            Self::UnsupportedCompression(db_path) => {
+                write!(f, "Compression of the repo DB '{db_path}' is not supported")
+            },
+            Self::MalformedEntry(entry_dir) => write!(f, "Malformed repo DB entry '{entry_dir}'"),
+            Self::Downgrade { pkgname, db_version, pkg_version } => write!(
+                f,
+                "Refusing to downgrade '{pkgname}' from '{db_version}' to '{pkg_version}'"
+            ),
+            Self::VerifyFailed(output) => write!(f, "Failed to verify repo DB signature: {output}"),
+            Self::SignFailed(output) => write!(f, "Failed to sign repo DB: {output}"),
+        }
+    }
+}
+
+impl std::error::Error for RepoDbError {}
+
+/// Options of the native writer, mirroring the repo-add/repo-remove parameters
+#[derive(Debug, PartialEq, Default)]
+pub struct WriterOptions {
+    /// Sign the repo DB with GnuPG, e.g `--sign`
+    pub sign: bool,
+    /// Key which is used for signing, e.g `--key <key>`
+    pub key: Option<String>,
+    /// Verify the existing repo DB signature before modifying it, e.g `--verify`
+    pub verify: bool,
+    /// Include package signatures into the repo DB, e.g `--include-sigs`
+    pub include_sigs: bool,
+    /// Don't replace packages with older versions, e.g `--prevent-downgrade`
+    pub prevent_downgrade: bool,
+    /// Add only packages which are not yet present in the repo DB, e.g `--new`
+    pub only_new: bool,
+}
+
+impl WriterOptions {
+    pub fn from_params(params: &[String]) -> Self {
+        let mut opts = Self::default();
+        let mut params_iter = params.iter();
+        while let Some(param) = params_iter.next() {
+            match param.as_str() {
+                "-s" | "--sign" => opts.sign = true,
+                "-k" | "--key" => opts.key = params_iter.next().cloned(),
+                "-v" | "--verify" => opts.verify = true,
+                "--include-sigs" => opts.include_sigs = true,
+                "-p" | "--prevent-downgrade" => opts.prevent_downgrade = true,
+                "-n" | "--new" => opts.only_new = true,
+                "-q" | "--quiet" => {},
+                _ => log::warn!("Ignoring unsupported parameter of native backend: '{param}'"),
+            }
+        }
+        opts
+    }
+}
+
+/// Package entry of the repo DB, e.g the `pkgname-pkgver/` directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbEntry {
+    pub name: String,
+    pub version: String,
+    /// Content of the `desc` file
+    pub desc: String,
+    /// Content of the `files` file, present only in the files DB
+    pub files: Option<String>,
+}
+
+impl DbEntry {
+    /// Builds the DB entry from the package file
+    pub fn from_pkgfile(pkg_filepath: &str, include_sig: bool) -> Result<Self> {
+        let pkginfo = pkginfo::read_pkginfo(pkg_filepath)?;
+        let filelist = pkginfo::read_pkg_filelist(pkg_filepath)
+            .with_context(|| format!("Failed to list files of '{pkg_filepath}'"))?;
+
+        let pkg_filename = Path::new(pkg_filepath).file_name().unwrap().to_str().unwrap();
+        let csize = fs::metadata(pkg_filepath)?.len();
+        let sha256sum = get_file_sha256(pkg_filepath)?;
+
+        let sig_filepath = format!("{pkg_filepath}.sig");
+        let pgpsig = if include_sig && Path::new(&sig_filepath).exists() {
+            base64::engine::general_purpose::STANDARD.encode(fs::read(&sig_filepath)?)
+        } else {
+            String::new()
+        };
+
+        let mut desc = String::new();
+        push_desc_section(&mut desc, "FILENAME", &[pkg_filename]);
+        push_desc_section(&mut desc, "NAME", &[&pkginfo.pkgname]);
+        push_desc_section(&mut desc, "BASE", &[&pkginfo.pkgbase]);
+        push_desc_section(&mut desc, "VERSION", &[&pkginfo.pkgver]);
+        push_desc_section(&mut desc, "DESC", &[&pkginfo.pkgdesc]);
+        push_desc_section(&mut desc, "GROUPS", &pkginfo.groups);
+        push_desc_section(&mut desc, "CSIZE", &[&csize.to_string()]);
+        push_desc_section(&mut desc, "ISIZE", &[&pkginfo.size.to_string()]);
+        push_desc_section(&mut desc, "SHA256SUM", &[&sha256sum]);
+        push_desc_section(&mut desc, "PGPSIG", &[&pgpsig]);
+        push_desc_section(&mut desc, "URL", &[&pkginfo.url]);
+        push_desc_section(&mut desc, "LICENSE", &pkginfo.licenses);
+        push_desc_section(&mut desc, "ARCH", &[&pkginfo.arch]);
+        push_desc_section(&mut desc, "BUILDDATE", &[&pkginfo.builddate.to_string()]);
+        push_desc_section(&mut desc, "PACKAGER", &[&pkginfo.packager]);
+        push_desc_section(&mut desc, "REPLACES", &pkginfo.replaces);
+        push_desc_section(&mut desc, "CONFLICTS", &pkginfo.conflicts);
+        push_desc_section(&mut desc, "PROVIDES", &pkginfo.provides);
+        push_desc_section(&mut desc, "DEPENDS", &pkginfo.depends);
+        push_desc_section(&mut desc, "OPTDEPENDS", &pkginfo.optdepends);
+        push_desc_section(&mut desc, "MAKEDEPENDS", &pkginfo.makedepends);
+        push_desc_section(&mut desc, "CHECKDEPENDS", &pkginfo.checkdepends);
+
+        let mut files = "%FILES%\n".to_owned();
+        for file_entry in filelist {
+            files.push_str(&file_entry);
+            files.push('\n');
+        }
+
+        Ok(Self { name: pkginfo.pkgname, version: pkginfo.pkgver, desc, files: Some(files) })
+    }
+
+    /// Name of the entry directory in the repo DB
+    pub fn dirname(&self) -> String {
+        format!("{}-{}", self.name, self.version)
+    }
+
+    /// Gets the first value of the `desc` section, e.g `FILENAME`
+    pub fn desc_value(&self, section_name: &str) -> Option<&str> {
+        parse_desc_sections(&self.desc)
+            .into_iter()
+            .find(|section| section.0 == section_name)
+            .and_then(|section| section.1.first().copied())
+    }
+}
+
+/// Parses `desc` file of the repo DB entry into list of sections,
+/// e.g `%NAME%\npacman\n\n` -> `[("NAME", ["pacman"])]`
+pub fn parse_desc_sections(content: &str) -> Vec<(&str, Vec<&str>)> {
+    let mut sections: Vec<(&str, Vec<&str>)> = vec![];
+    for line in content.lines() {
+        if line.len() > 2 && line.starts_with('%') && line.ends_with('%') {
+            sections.push((&line[1..line.len() - 1], vec![]));
+        } else if let Some(section) = sections.last_mut().filter(|_| !line.is_empty()) {
+            section.1.push(line);
+        }
+    }
+    sections
+}
+
+/// In-process writer of the `<repo>.db` and `<repo>.files` databases
+pub struct RepoDb {
+    db_path: String,
+    files_path: String,
+    entries: BTreeMap<String, DbEntry>,
+}
+
+impl RepoDb {
+    /// Creates an empty repo DB in memory, the existing DB is not read
+    pub fn new(repo_db_path: &str) -> Result<Self> {
+        let files_path = get_files_db_path(repo_db_path)?;
+        // make sure that we are able to write it later
+        get_db_compression(repo_db_path)?;
+
+        Ok(Self { db_path: repo_db_path.to_owned(), files_path, entries: BTreeMap::new() })
+    }
+
+    /// Opens the repo DB, if the DB doesn't exist yet an empty one is created in memory
+    pub fn open(repo_db_path: &str) -> Result<Self> {
+        let mut repo_db = Self::new(repo_db_path)?;
+
+        // the files DB contains both desc and files entries, prefer it over the regular one
+        let entries_path = if Path::new(&repo_db.files_path).exists() {
+            &repo_db.files_path
+        } else {
+            repo_db_path
+        };
+        if !Path::new(entries_path).exists() {
+            log::info!("Repo DB '{repo_db_path}' doesn't exist yet, creating new one");
+            return Ok(repo_db);
+        }
+
+        let entries = read_db_entries(entries_path)
+            .with_context(|| format!("Failed to read repo DB '{entries_path}'"))?;
+        repo_db.entries = entries.into_iter().map(|entry| (entry.name.clone(), entry)).collect();
+
+        Ok(repo_db)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &DbEntry> {
+        self.entries.values()
+    }
+
+    /// Adds or replaces the package entry in the repo DB.
+    /// Returns false if the package was skipped
+    pub fn add_package(&mut self, pkg_filepath: &str, opts: &WriterOptions) -> Result<bool> {
+        let entry = DbEntry::from_pkgfile(pkg_filepath, opts.include_sigs)?;
+
+        if let Some(db_entry) = self.entries.get(&entry.name) {
+            if opts.only_new {
+                log::warn!("Skipping '{}', package is already present in the DB", entry.dirname());
+                return Ok(false);
+            }
+            let db_version = alpm::Version::new(db_entry.version.as_str());
+            let pkg_version = alpm::Version::new(entry.version.as_str());
+            if opts.prevent_downgrade && pkg_version.vercmp(&db_version).is_lt() {
+                return Err(RepoDbError::Downgrade {
+                    pkgname: entry.name,
+                    db_version: db_entry.version.clone(),
+                    pkg_version: entry.version,
+                }
+                .into());
+            }
+            log::debug!("Removing existing entry '{}'..", db_entry.dirname());
+        }
+
+        log::debug!("Adding package entry '{}'..", entry.dirname());
+        self.entries.insert(entry.name.clone(), entry);
+
+        Ok(true)
+    }
+
+    /// Removes the package entry from the repo DB.
+    /// Returns false if there was no such package
+    pub fn remove_package(&mut self, pkgname: &str) -> bool {
+        match self.entries.remove(pkgname) {
+            Some(entry) => {
+                log::debug!("Removing package entry '{}'..", entry.dirname());
+                true
+            },
+            None => {
+                log::warn!("Package matching '{pkgname}' not found in the DB");
+                false
+            },
+        }
+    }
+
+    /// Verifies signature of the existing repo DB, if it has any
+    pub fn verify(&self) -> Result<()> {
+        let sig_path = format!("{}.sig", self.db_path);
+        if !Path::new(&sig_path).exists() {
+            log::warn!("No existing signature found for '{}', skipping verification", self.db_path);
+            return Ok(());
+        }
+
+        let (success, proc_output) = run_gpg(&["--verify", &sig_path, &self.db_path])?;
+        if !success {
+            return Err(RepoDbError::VerifyFailed(proc_output).into());
+        }
+
+        Ok(())
+    }
+
+    /// Writes both `<repo>.db` and `<repo>.files` databases. Both of them are written and signed
+    /// into the temporary files first, which are then swapped with the previous ones in one pass
+    pub fn write(&self, opts: &WriterOptions) -> Result<()> {
+        let mut staged_files = vec![];
+        let stage_result = self.stage_db_files(opts, &mut staged_files);
+        if let Err(stage_err) = stage_result {
+            for (tmp_path, _) in &staged_files {
+                let _ = fs::remove_file(tmp_path);
+            }
+            return Err(stage_err);
+        }
+
+        // the previous signatures don't match the new DBs anymore
+        for db_path in [&self.db_path, &self.files_path] {
+            let sig_path = format!("{db_path}.sig");
+            if !opts.sign && Path::new(&sig_path).exists() {
+                fs::remove_file(&sig_path)?;
+            }
+        }
+        for (tmp_path, dest_path) in &staged_files {
+            fs::rename(tmp_path, dest_path)?;
+        }
+        for (_, dest_path) in &staged_files {
+            create_db_symlink(dest_path)?;
+        }
+
+        Ok(())
+    }
+
+    // Writes and signs the DBs into the temporary files, collecting them with their destinations
+    fn stage_db_files(
+        &self,
+        opts: &WriterOptions,
+        staged_files: &mut Vec<(String, String)>,
+    ) -> Result<()> {
+        for (db_path, with_files) in [(&self.db_path, false), (&self.files_path, true)] {
+            let tmp_path = get_tmp_path(db_path);
+            staged_files.push((tmp_path.clone(), db_path.clone()));
+            write_db_archive(&tmp_path, db_path, self.entries.values(), with_files)
+                .with_context(|| format!("Failed to write repo DB '{db_path}'"))?;
+            if opts.sign {
+                let tmp_sig_path = sign_db(&tmp_path, opts.key.as_deref())?;
+                staged_files.push((tmp_sig_path, format!("{db_path}.sig")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Gets path of the files DB from the repo DB path,
+/// e.g `/repo/repof.db.tar.zst` -> `/repo/repof.files.tar.zst`
+pub fn get_files_db_path(repo_db_path: &str) -> Result<String> {
+    let (db_dir, db_filename) = repo_db_path.rsplit_once('/').unwrap_or(("", repo_db_path));
+    let Some((db_prefix, db_suffix)) = db_filename.split_once(".db.tar") else {
+        return Err(RepoDbError::InvalidDbPath(repo_db_path.to_owned()).into());
+    };
+
+    let files_filename = format!("{db_prefix}.files.tar{db_suffix}");
+    if db_dir.is_empty() {
+        return Ok(files_filename);
+    }
+    Ok(format!("{db_dir}/{files_filename}"))
+}
+
+/// Reads all entries of the repo DB archive
+pub fn read_db_entries(db_path: &str) -> Result<Vec<DbEntry>> {
+    let mut archive = tar::Archive::new(open_db_reader(db_path)?);
+
+    // collect desc and files of each entry directory
+    let mut entry_files: BTreeMap<String, (Option<String>, Option<String>)> = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let Some((entry_dir, entry_filename)) = entry_path.split_once('/') else {
+            continue;
+        };
+        if entry_filename != "desc" && entry_filename != "files" {
+            continue;
+        }
+
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        let entry_files = entry_files.entry(entry_dir.to_owned()).or_default();
+        if entry_filename == "desc" {
+            entry_files.0 = Some(content);
+        } else {
+            entry_files.1 = Some(content);
+        }
+    }
+
+    let mut entries = vec![];
+    for (entry_dir, (desc, files)) in entry_files {
+        let Some(desc) = desc else {
+            return Err(RepoDbError::MalformedEntry(entry_dir).into());
+        };
+
+        let mut entry = DbEntry { name: String::new(), version: String::new(), desc, files };
+        let (Some(name), Some(version)) = (entry.desc_value("NAME"), entry.desc_value("VERSION"))
+        else {
+            return Err(RepoDbError::MalformedEntry(entry_dir).into());
+        };
+        (entry.name, entry.version) = (name.to_owned(), version.to_owned());
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn write_db_archive<'a>(
+    tmp_path: &str,
+    db_path: &str,
+    entries: impl Iterator<Item = &'a DbEntry>,
+    with_files: bool,
+) -> Result<()> {
+    let mut entries = entries.collect::<Vec<_>>();
+    entries.sort_by_cached_key(|entry| entry.dirname());
+
+    let mtime = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?.as_secs();
+
+    let mut builder = tar::Builder::new(open_db_writer(tmp_path, db_path)?);
+    for entry in entries {
+        let entry_dir = entry.dirname();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_mtime(mtime);
+        header.set_size(0);
+        builder.append_data(&mut header, format!("{entry_dir}/"), io::empty())?;
+
+        let mut db_files = vec![("desc", entry.desc.as_str())];
+        if let Some(files) = entry.files.as_deref().filter(|_| with_files) {
+            db_files.push(("files", files));
+        }
+        for (db_filename, content) in db_files {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            header.set_size(content.len() as u64);
+            builder.append_data(
+                &mut header,
+                format!("{entry_dir}/{db_filename}"),
+                content.as_bytes(),
+            )?;
+        }
+    }
+
+    // finish the compression stream and make sure the data is on the disk before renaming
+    let mut writer = builder.into_inner()?;
+    writer.flush()?;
+    drop(writer);
+    File::open(tmp_path)?.sync_all()?;
+
+    Ok(())
+}
+
+fn open_db_reader(db_path: &str) -> Result<Box<dyn Read>> {
+    let db_file = BufReader::new(File::open(db_path)?);
+    let db_reader: Box<dyn Read> = match get_db_compression(db_path)? {
+        ".zst" => Box::new(zstd::stream::read::Decoder::with_buffer(db_file)?),
+        ".xz" => Box::new(xz2::read::XzDecoder::new(db_file)),
+        ".gz" => Box::new(flate2::read::GzDecoder::new(db_file)),
+        _ => Box::new(db_file),
+    };
+    Ok(db_reader)
+}
+
+fn open_db_writer(tmp_path: &str, db_path: &str) -> Result<Box<dyn Write>> {
+    let db_file = BufWriter::new(File::create(tmp_path)?);
+    let db_writer: Box<dyn Write> = match get_db_compression(db_path)? {
+        ".zst" => Box::new(zstd::stream::write::Encoder::new(db_file, 19)?.auto_finish()),
+        ".xz" => Box::new(xz2::write::XzEncoder::new(db_file, 6)),
+        ".gz" => Box::new(flate2::write::GzEncoder::new(db_file, flate2::Compression::default())),
+        _ => Box::new(db_file),
+    };
+    Ok(db_writer)
+}
+
+// Gets compression extension of the repo DB, empty for uncompressed DB
+fn get_db_compression(db_path: &str) -> Result<&str> {
+    let db_ext = db_path.rsplit_once(".tar").map_or("", |(_, db_ext)| db_ext);
+    match db_ext {
+        "" | ".zst" | ".xz" | ".gz" => Ok(db_ext),
+        _ => Err(RepoDbError::UnsupportedCompression(db_path.to_owned()).into()),
+    }
+}
+
+fn get_tmp_path(db_path: &str) -> String {
+    let (db_dir, db_filename) = db_path.rsplit_once('/').unwrap_or((".", db_path));
+    format!("{db_dir}/.{db_filename}.tmp")
+}
+
+/// Creates (or swaps atomically) the `<repo>.db`/`<repo>.files` symlink pointing to the DB file
+pub fn create_db_symlink(db_path: &str) -> Result<()> {
+    let Some(link_path) = get_db_symlink_path(db_path) else {
+        return Ok(());
+    };
+    let db_filename = Path::new(db_path).file_name().unwrap();
+
+    // swap the symlink atomically
+    let tmp_link_path = get_tmp_path(&link_path);
+    if fs::symlink_metadata(&tmp_link_path).is_ok() {
+        fs::remove_file(&tmp_link_path)?;
+    }
+    unix_fs::symlink(db_filename, &tmp_link_path)?;
+    fs::rename(&tmp_link_path, &link_path)?;
+
+    Ok(())
+}
+
+// e.g `repof.db.tar.zst.sig` -> `repof.db.sig`
+fn get_db_symlink_path(db_path: &str) -> Option<String> {
+    let (link_path, _) = db_path.split_once(".tar")?;
+    match db_path.ends_with(".sig") {
+        true => Some(format!("{link_path}.sig")),
+        false => Some(link_path.to_owned()),
+    }
+}
+
+/// Lists the DB files of the repo, e.g `<repo>.db.tar.zst`, `<repo>.files.tar.zst` and their
+/// signatures, skipping the symlinks and the `.old`/`.lck` files of repo-add
+pub fn list_db_files(repo_db_path: &str) -> Result<Vec<String>> {
+    let (db_dir, db_filename) = repo_db_path.rsplit_once('/').unwrap_or((".", repo_db_path));
+    let Some((db_prefix, _)) = db_filename.split_once(".db") else {
+        return Err(RepoDbError::InvalidDbPath(repo_db_path.to_owned()).into());
+    };
+
+    let mut db_files = vec![];
+    for db_kind in ["db", "files"] {
+        let pattern = format!(
+            "{}/{}.{db_kind}.tar*",
+            glob::Pattern::escape(db_dir),
+            glob::Pattern::escape(db_prefix)
+        );
+        for entry in glob::glob(&pattern)? {
+            let entry = entry?;
+            if !entry.is_symlink() && is_db_filename(entry.file_name().unwrap().to_str().unwrap()) {
+                db_files.push(entry.to_str().unwrap().to_owned());
+            }
+        }
+    }
+    Ok(db_files)
+}
+
+// Checks that the file is the DB archive or its signature, where:
+// only the compression extension may follow `.tar`, e.g not `<repo>.db.tar.zst.old`
+fn is_db_filename(db_filename: &str) -> bool {
+    let db_filename = db_filename.strip_suffix(".sig").unwrap_or(db_filename);
+    db_filename.rsplit_once(".tar").is_some_and(|(_, db_ext)| db_ext.matches('.').count() <= 1)
+}
+
+/// Removes the DB file together with its symlink
+pub fn remove_db_file(db_path: &str) -> Result<()> {
+    fs::remove_file(db_path)?;
+    if let Some(link_path) = get_db_symlink_path(db_path) {
+        let is_own_link = fs::read_link(&link_path)
+            .is_ok_and(|target| Some(target.as_os_str()) == Path::new(db_path).file_name());
+        if is_own_link {
+            fs::remove_file(&link_path)?;
+        }
+    }
+    Ok(())
+}
+
+// Signs the DB file, returns path of the detached signature
+fn sign_db(tmp_path: &str, key: Option<&str>) -> Result<String> {
+    let tmp_sig_path = format!("{tmp_path}.sig");
+    let mut gpg_args = vec!["--detach-sign", "--use-agent", "--no-armor"];
+    if let Some(key) = key {
+        gpg_args.extend_from_slice(&["-u", key]);
+    }
+    gpg_args.extend_from_slice(&["--output", &tmp_sig_path, tmp_path]);
+
+    let (success, proc_output) = run_gpg(&gpg_args)?;
+    if !success {
+        return Err(RepoDbError::SignFailed(proc_output).into());
+    }
+
+    Ok(tmp_sig_path)
+}
+
+fn run_gpg(gpg_args: &[&str]) -> Result<(bool, String)> {
+    let output = Exec::cmd("gpg")
+        .args(&["--batch", "--yes"])
+        .args(gpg_args)
+        .stderr(Redirection::Merge)
+        .stdout(Redirection::Pipe)
+        .capture()?;
+
+    Ok((output.success(), String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Computes SHA-256 checksum of the file as hex string
+pub fn get_file_sha256(filepath: &str) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(filepath)?), &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn push_desc_section<S: AsRef<str>>(desc: &mut String, section_name: &str, values: &[S]) {
+    // repo-add skips sections without values
+    if values.iter().all(|value| value.as_ref().is_empty()) {
+        return;
+    }
+
+    desc.push_str(&format!("%{section_name}%\n"));
+    for value in values {
+        desc.push_str(value.as_ref());
+        desc.push('\n');
+    }
+    desc.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repo_db::*;
+
+    fn create_test_pkg(pkg_filepath: &str, pkginfo_content: &str) {
+        crate::utils::create_test_pkg(pkg_filepath, &[
+            (".PKGINFO", pkginfo_content),
+            ("usr/bin/dash", "#!/bin/dash\n"),
+        ]);
+    }
+
+    #[test]
+    fn test_files_db_path() {
+        assert_eq!(
+            get_files_db_path("/home/testuser/repos/repof/repof.db.tar.zst").unwrap(),
+            "/home/testuser/repos/repof/repof.files.tar.zst"
+        );
+        assert_eq!(get_files_db_path("repof.db.tar.gz").unwrap(), "repof.files.tar.gz");
+        assert!(get_files_db_path("/home/testuser/repos/repof/repof.tar.zst").is_err());
+    }
+
+    #[test]
+    fn test_writer_options() {
+        let params: Vec<String> = ["--sign", "--include-sigs", "--key", "ABCDEF", "--verify"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(WriterOptions::from_params(&params), WriterOptions {
+            sign: true,
+            key: Some("ABCDEF".into()),
+            verify: true,
+            include_sigs: true,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_parse_desc_sections() {
+        let desc = concat!(
+            "%FILENAME%\ndash-0.5.12-1-x86_64.pkg.tar.zst\n\n",
+            "%NAME%\ndash\n\n",
+            "%DEPENDS%\nglibc\nlibedit\n\n"
+        );
+        assert_eq!(parse_desc_sections(desc), vec![
+            ("FILENAME", vec!["dash-0.5.12-1-x86_64.pkg.tar.zst"]),
+            ("NAME", vec!["dash"]),
+            ("DEPENDS", vec!["glibc", "libedit"]),
+        ]);
+    }
+
+    #[test]
+    fn test_write_and_read_db() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let repo_db_path = format!("{temp_dir}/repof.db.tar.zst");
+
+        let pkg_filepath = format!("{temp_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        create_test_pkg(
+            &pkg_filepath,
+            "pkgname = dash\npkgver = 0.5.12-1\narch = x86_64\nsize = 42\ndepend = glibc\n",
+        );
+
+        let opts = WriterOptions::default();
+        let mut repo_db = RepoDb::open(&repo_db_path).unwrap();
+        assert!(repo_db.add_package(&pkg_filepath, &opts).unwrap());
+        repo_db.write(&opts).unwrap();
+
+        assert!(Path::new(&format!("{temp_dir}/repof.db")).exists());
+        assert!(Path::new(&format!("{temp_dir}/repof.files")).exists());
+
+        // the regular DB doesn't have file lists
+        let entries = read_db_entries(&repo_db_path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].dirname(), "dash-0.5.12-1");
+        assert_eq!(entries[0].files, None);
+        assert_eq!(parse_desc_sections(&entries[0].desc)[..4], [
+            ("FILENAME", vec!["dash-0.5.12-1-x86_64.pkg.tar.zst"]),
+            ("NAME", vec!["dash"]),
+            ("BASE", vec!["dash"]),
+            ("VERSION", vec!["0.5.12-1"]),
+        ]);
+
+        let mut repo_db = RepoDb::open(&repo_db_path).unwrap();
+        assert_eq!(
+            repo_db.entries().next().unwrap().files.as_deref(),
+            Some("%FILES%\nusr/bin/dash\n")
+        );
+
+        // older version must be refused with prevent-downgrade
+        let old_pkg_filepath = format!("{temp_dir}/dash-0.5.11-1-x86_64.pkg.tar.zst");
+        create_test_pkg(&old_pkg_filepath, "pkgname = dash\npkgver = 0.5.11-1\narch = x86_64\n");
+        let opts = WriterOptions { prevent_downgrade: true, ..Default::default() };
+        let add_err = repo_db.add_package(&old_pkg_filepath, &opts).unwrap_err();
+        assert_eq!(
+            add_err.downcast_ref::<RepoDbError>(),
+            Some(&RepoDbError::Downgrade {
+                pkgname: "dash".into(),
+                db_version: "0.5.12-1".into(),
+                pkg_version: "0.5.11-1".into(),
+            })
+        );
+
+        assert!(repo_db.remove_package("dash"));
+        assert!(!repo_db.remove_package("dash"));
+        repo_db.write(&opts).unwrap();
+        assert_eq!(RepoDb::open(&repo_db_path).unwrap().entries().count(), 0);
+
+        // symlinks are not listed, and removed together with the DB file
+        assert_eq!(list_db_files(&repo_db_path).unwrap(), vec![
+            repo_db_path.clone(),
+            format!("{temp_dir}/repof.files.tar.zst")
+        ]);
+        // nor the backups and the lock of repo-add
+        fs::write(format!("{repo_db_path}.old"), "").unwrap();
+        fs::write(format!("{repo_db_path}.old.sig"), "").unwrap();
+        fs::write(format!("{repo_db_path}.lck"), "").unwrap();
+        assert_eq!(list_db_files(&repo_db_path).unwrap().len(), 2);
+        remove_db_file(&repo_db_path).unwrap();
+        assert!(fs::symlink_metadata(format!("{temp_dir}/repof.db")).is_err());
+        assert!(fs::symlink_metadata(format!("{temp_dir}/repof.files")).is_ok());
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_db_failure() {
+        let temp_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let repo_db_path = format!("{temp_dir}/repof.db.tar.zst");
+        let opts = WriterOptions::default();
+        RepoDb::open(&repo_db_path).unwrap().write(&opts).unwrap();
+
+        // the files DB can't be written, the DB must be left as it was
+        fs::create_dir(format!("{temp_dir}/.repof.files.tar.zst.tmp")).unwrap();
+        let pkg_filepath = format!("{temp_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        create_test_pkg(&pkg_filepath, "pkgname = dash\npkgver = 0.5.12-1\narch = x86_64\n");
+        let mut repo_db = RepoDb::open(&repo_db_path).unwrap();
+        repo_db.add_package(&pkg_filepath, &opts).unwrap();
+        assert!(repo_db.write(&opts).is_err());
+
+        assert!(read_db_entries(&repo_db_path).unwrap().is_empty());
+        assert!(!Path::new(&format!("{temp_dir}/.repof.db.tar.zst.tmp")).exists());
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+}
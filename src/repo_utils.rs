@@ -1,12 +1,200 @@
-use crate::config;
+use crate::alpm_helper::{self, DbPackage};
+use crate::config::{self, BackendKind};
+use crate::repo_db::{self, RepoDb, WriterOptions};
 
-use anyhow::Result;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
 use subprocess::{Exec, Redirection};
 
+/// Tool which modifies the repo DB
+pub trait RepoBackend {
+    /// Path of the repo DB which is modified
+    fn db_path(&self) -> &str;
+
+    /// Adds or replaces package files in the repo DB
+    fn add(&mut self, pkgfiles: &[String]) -> Result<()>;
+
+    /// Removes packages with provided names from the repo DB
+    fn remove(&mut self, pkgname_list: &[String]) -> Result<()>;
+
+    /// Lists packages present in the repo DB
+    fn list_entries(&self) -> Result<Vec<DbPackage>>;
+
+    /// Recreates the repo DB from scratch with provided package files
+    fn rebuild(&mut self, pkgfiles: &[String]) -> Result<()>;
+}
+
+/// Creates the backend which is configured for the profile
+pub fn make_backend(profile: &config::Profile) -> Box<dyn RepoBackend + '_> {
+    match profile.backend {
+        BackendKind::External => Box::new(ExternalBackend { profile }),
+        BackendKind::Native => Box::new(NativeBackend { profile }),
+    }
+}
+
+/// Calls repo-add/repo-remove
+pub struct ExternalBackend<'a> {
+    profile: &'a config::Profile,
+}
+
+impl RepoBackend for ExternalBackend<'_> {
+    fn db_path(&self) -> &str {
+        &self.profile.repo
+    }
+
+    fn add(&mut self, pkgfiles: &[String]) -> Result<()> {
+        with_staged_db(&self.profile.repo, true, |staged_repo| {
+            handle_external_repo_add(self.profile, staged_repo, pkgfiles)
+        })
+    }
+
+    fn remove(&mut self, pkgname_list: &[String]) -> Result<()> {
+        with_staged_db(&self.profile.repo, true, |staged_repo| {
+            handle_external_repo_remove(self.profile, staged_repo, pkgname_list)
+        })
+    }
+
+    fn list_entries(&self) -> Result<Vec<DbPackage>> {
+        alpm_helper::read_repo_db(&self.profile.repo)
+    }
+
+    fn rebuild(&mut self, pkgfiles: &[String]) -> Result<()> {
+        // the new DB is created from scratch beside the old one
+        with_staged_db(&self.profile.repo, false, |staged_repo| {
+            handle_external_repo_add(self.profile, staged_repo, pkgfiles)
+        })
+    }
+}
+
+/// Rewrites the repo DB in-process
+pub struct NativeBackend<'a> {
+    profile: &'a config::Profile,
+}
+
+impl RepoBackend for NativeBackend<'_> {
+    fn db_path(&self) -> &str {
+        &self.profile.repo
+    }
+
+    // Adds package files to the repo DB in-process, rewriting the DB only once
+    fn add(&mut self, pkgfiles: &[String]) -> Result<()> {
+        let opts = WriterOptions::from_params(&self.profile.add_params);
+        let mut repo_db = open_native_repo_db(self.profile, &opts)?;
+        add_native_pkgfiles(&mut repo_db, pkgfiles, &opts)?;
+        repo_db.write(&opts)
+    }
+
+    // Removes packages from the repo DB in-process, rewriting the DB only once
+    fn remove(&mut self, pkgname_list: &[String]) -> Result<()> {
+        let opts = WriterOptions::from_params(&self.profile.rm_params);
+        let mut repo_db = open_native_repo_db(self.profile, &opts)?;
+
+        for pkgname in pkgname_list {
+            repo_db.remove_package(pkgname);
+        }
+        repo_db.write(&opts)
+    }
+
+    fn list_entries(&self) -> Result<Vec<DbPackage>> {
+        alpm_helper::read_repo_db(&self.profile.repo)
+    }
+
+    fn rebuild(&mut self, pkgfiles: &[String]) -> Result<()> {
+        let opts = WriterOptions::from_params(&self.profile.add_params);
+        let mut repo_db = RepoDb::new(&self.profile.repo)?;
+        add_native_pkgfiles(&mut repo_db, pkgfiles, &opts)?;
+        repo_db.write(&opts)
+    }
+}
+
+fn add_native_pkgfiles(
+    repo_db: &mut RepoDb,
+    pkgfiles: &[String],
+    opts: &WriterOptions,
+) -> Result<()> {
+    for pkgfile in pkgfiles {
+        repo_db
+            .add_package(pkgfile, opts)
+            .with_context(|| format!("Failed to add '{pkgfile}' into the repo DB"))?;
+    }
+    Ok(())
+}
+
+fn open_native_repo_db(profile: &config::Profile, opts: &WriterOptions) -> Result<RepoDb> {
+    let repo_db = RepoDb::open(&profile.repo)?;
+    if opts.verify {
+        repo_db.verify()?;
+    }
+    Ok(repo_db)
+}
+
+// Runs the tool on the copy of the repo DB in the staging dir, and swaps the DB files in only
+// once the tool succeeded, so the repo is never left without the DB
+fn with_staged_db(
+    repo: &str,
+    copy_current: bool,
+    run_tool: impl FnOnce(&str) -> Result<()>,
+) -> Result<()> {
+    let repo_path = Path::new(repo);
+    let repo_dir = repo_path.parent().unwrap();
+    let staging_dir = repo_dir.join(".repo-manage-staging");
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)?;
+    }
+    fs::create_dir(&staging_dir)
+        .with_context(|| format!("Failed to create staging dir '{}'", staging_dir.display()))?;
+
+    let result = stage_and_swap_db(repo, &staging_dir, copy_current, run_tool);
+    fs::remove_dir_all(&staging_dir)?;
+    result
+}
+
+fn stage_and_swap_db(
+    repo: &str,
+    staging_dir: &Path,
+    copy_current: bool,
+    run_tool: impl FnOnce(&str) -> Result<()>,
+) -> Result<()> {
+    let repo_dir = Path::new(repo).parent().unwrap();
+    let staged_repo = staging_dir.join(Path::new(repo).file_name().unwrap());
+    if copy_current {
+        for db_file in repo_db::list_db_files(repo)? {
+            fs::copy(&db_file, staging_dir.join(Path::new(&db_file).file_name().unwrap()))?;
+        }
+    }
+
+    run_tool(staged_repo.to_str().unwrap())?;
+
+    let mut swapped_db_files = vec![];
+    for staged_db_file in repo_db::list_db_files(staged_repo.to_str().unwrap())? {
+        let db_file = repo_dir.join(Path::new(&staged_db_file).file_name().unwrap());
+        let db_file = db_file.to_str().unwrap().to_owned();
+        log::debug!("swapping DB file '{db_file}'..");
+        fs::rename(&staged_db_file, &db_file)?;
+        repo_db::create_db_symlink(&db_file)?;
+        swapped_db_files.push(db_file);
+    }
+
+    // remove DB files which are not produced anymore, e.g signature of the unsigned DB
+    for db_file in repo_db::list_db_files(repo)? {
+        if !swapped_db_files.contains(&db_file) {
+            log::debug!("removing DB file '{db_file}'..");
+            repo_db::remove_db_file(&db_file)?;
+        }
+    }
+    Ok(())
+}
+
 // Calls repo-add on provided repo and package files
-pub fn handle_repo_add(profile: &config::Profile, pkgfiles: &[String]) -> Result<()> {
+fn handle_external_repo_add(
+    profile: &config::Profile,
+    repo: &str,
+    pkgfiles: &[String],
+) -> Result<()> {
     let mut repo_add_args = profile.add_params.clone();
-    repo_add_args.push(profile.repo.clone());
+    repo_add_args.push(repo.to_owned());
 
     // push provided package files into repo-add args
     repo_add_args.extend_from_slice(pkgfiles);
@@ -29,9 +217,13 @@ pub fn handle_repo_add(profile: &config::Profile, pkgfiles: &[String]) -> Result
 }
 
 // Calls repo-remove on provided repo and package names
-pub fn handle_repo_remove(profile: &config::Profile, pkgname_list: &[String]) -> Result<()> {
+fn handle_external_repo_remove(
+    profile: &config::Profile,
+    repo: &str,
+    pkgname_list: &[String],
+) -> Result<()> {
     let mut repo_remove_args = profile.rm_params.clone();
-    repo_remove_args.push(profile.repo.clone());
+    repo_remove_args.push(repo.to_owned());
 
     // push provided package names into repo-remove args
     repo_remove_args.extend_from_slice(pkgname_list);
@@ -52,3 +244,92 @@ pub fn handle_repo_remove(profile: &config::Profile, pkgname_list: &[String]) ->
 
     Ok(())
 }
+
+/// In-memory backend for testing the command flow without touching any repo DB
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FakeBackend {
+    pub db_path: String,
+    pub entries: std::collections::BTreeMap<String, DbPackage>,
+    /// Package files passed to the add/rebuild calls, in order
+    pub added: Vec<String>,
+    /// Package names passed to the remove calls, in order
+    pub removed: Vec<String>,
+}
+
+#[cfg(test)]
+impl FakeBackend {
+    pub fn with_entries(pkgfiles: &[&str]) -> Self {
+        let mut backend = Self::default();
+        for pkgfile in pkgfiles {
+            backend.insert_entry(pkgfile);
+        }
+        backend
+    }
+
+    fn insert_entry(&mut self, pkgfile: &str) {
+        let pkginfo = crate::pkginfo::load_pkginfo(pkgfile).expect("Invalid package");
+        let filename = Path::new(pkgfile).file_name().unwrap().to_str().unwrap().to_owned();
+        self.entries.insert(pkginfo.pkgname.clone(), DbPackage::from_pkginfo(&pkginfo, &filename));
+    }
+}
+
+#[cfg(test)]
+impl RepoBackend for FakeBackend {
+    fn db_path(&self) -> &str {
+        &self.db_path
+    }
+
+    fn add(&mut self, pkgfiles: &[String]) -> Result<()> {
+        for pkgfile in pkgfiles {
+            self.insert_entry(pkgfile);
+            self.added.push(pkgfile.clone());
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, pkgname_list: &[String]) -> Result<()> {
+        for pkgname in pkgname_list {
+            self.entries.remove(pkgname);
+            self.removed.push(pkgname.clone());
+        }
+        Ok(())
+    }
+
+    fn list_entries(&self) -> Result<Vec<DbPackage>> {
+        Ok(self.entries.values().cloned().collect())
+    }
+
+    fn rebuild(&mut self, pkgfiles: &[String]) -> Result<()> {
+        self.entries.clear();
+        self.add(pkgfiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repo_utils::*;
+
+    #[test]
+    fn test_staged_db_swap() {
+        let repo_dir = crate::utils::create_temporary_directory(None).unwrap();
+        let repo_db_path = format!("{repo_dir}/repof.db.tar.zst");
+        fs::write(&repo_db_path, "old db").unwrap();
+        repo_db::create_db_symlink(&repo_db_path).unwrap();
+
+        // repo-add keeps the previous DB as `.old`
+        with_staged_db(&repo_db_path, true, |staged_repo| {
+            fs::rename(staged_repo, format!("{staged_repo}.old"))?;
+            fs::write(staged_repo, "new db")?;
+            Ok(())
+        })
+        .unwrap();
+
+        let link_path = format!("{repo_dir}/repof.db");
+        assert_eq!(fs::read_link(&link_path).unwrap(), Path::new("repof.db.tar.zst"));
+        assert_eq!(fs::read_to_string(&link_path).unwrap(), "new db");
+        assert!(!Path::new(&format!("{repo_db_path}.old")).exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+}
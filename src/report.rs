@@ -0,0 +1,442 @@
+use crate::alpm_helper::{self, DbPackage};
+use crate::pkginfo;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// Output format of the reports
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Log lines for humans
+    #[default]
+    Text,
+    /// Structured report on stdout
+    Json,
+}
+
+/// Report of the repo state, printed as log lines or as JSON
+pub trait Report: Serialize {
+    /// Whether nothing needs attention
+    fn is_clean(&self) -> bool;
+
+    /// Logs the entries of the report for humans
+    fn log_entries(&self);
+}
+
+/// Prints the report in the requested format, returns the exit code: `1` unless the report is
+/// clean
+pub fn print_report(report: &impl Report, format: OutputFormat) -> anyhow::Result<i32> {
+    match format {
+        OutputFormat::Text => report.log_entries(),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+    }
+    Ok(if report.is_clean() { 0 } else { 1 })
+}
+
+/// Package which needs attention in the repo
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReportEntry {
+    pub name: String,
+    pub version: String,
+    pub filename: String,
+    pub reason: String,
+    /// Fingerprint of the key which the package signature is verified with
+    pub signer: Option<String>,
+}
+
+impl ReportEntry {
+    /// Creates the entry from the package file, using its metadata
+    pub fn from_pkgfile(pkg_filepath: &str, reason: &str) -> Self {
+        let pkginfo = pkginfo::load_pkginfo(pkg_filepath).unwrap_or_default();
+        let filename = Path::new(pkg_filepath).file_name().unwrap().to_str().unwrap().to_owned();
+        Self {
+            name: pkginfo.pkgname,
+            version: pkginfo.pkgver,
+            filename,
+            reason: reason.to_owned(),
+            signer: None,
+        }
+    }
+
+    /// Creates the entry from the repo DB record
+    pub fn from_db_pkg(db_pkg: &DbPackage, reason: &str) -> Self {
+        Self {
+            name: db_pkg.name.clone(),
+            version: db_pkg.version.clone(),
+            filename: db_pkg.filename.clone(),
+            reason: reason.to_owned(),
+            signer: None,
+        }
+    }
+
+    /// Returns `pkgname-pkgver` pair as it appears in the repo DB
+    pub fn db_pair(&self) -> String {
+        format!("{}-{}", self.name, self.version)
+    }
+}
+
+/// Result of the repo checkup
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct CheckupReport {
+    pub repo: String,
+    pub clean: bool,
+    pub brand_new: Vec<ReportEntry>,
+    pub new: Vec<ReportEntry>,
+    pub outdated: Vec<ReportEntry>,
+    pub stale: Vec<ReportEntry>,
+    pub pkgbase_mismatch: Vec<ReportEntry>,
+    pub compression_conflicts: Vec<ReportEntry>,
+    pub file_conflicts: Vec<ReportEntry>,
+    pub invalid_signature: Vec<ReportEntry>,
+    pub newer_in_reference: Vec<ReportEntry>,
+}
+
+impl CheckupReport {
+    pub fn new(repo: &str) -> Self {
+        Self { repo: repo.to_owned(), clean: true, ..Default::default() }
+    }
+
+    /// Updates the `clean` flag, must be called after all entries are collected
+    pub fn finish(&mut self) {
+        self.clean = self.brand_new.is_empty()
+            && self.new.is_empty()
+            && self.outdated.is_empty()
+            && self.stale.is_empty()
+            && self.pkgbase_mismatch.is_empty()
+            && self.compression_conflicts.is_empty()
+            && self.file_conflicts.is_empty()
+            && self.invalid_signature.is_empty()
+            && self.newer_in_reference.is_empty();
+    }
+}
+
+impl Report for CheckupReport {
+    fn is_clean(&self) -> bool {
+        self.clean
+    }
+
+    fn log_entries(&self) {
+        let repo_db_prefix = &self.repo;
+        for entry in &self.brand_new {
+            log::info!("Found brand new package in repo '{repo_db_prefix}': '{}'", entry.db_pair());
+        }
+        for entry in &self.new {
+            log::info!("Found new package in repo '{repo_db_prefix}': '{}'", entry.db_pair());
+        }
+        for entry in &self.outdated {
+            log::info!("Found outdated package in repo '{repo_db_prefix}': '{}'", entry.db_pair());
+        }
+        for entry in &self.stale {
+            log::info!("Found stale package in repo '{repo_db_prefix}': '{}'", entry.db_pair());
+        }
+        for entry in &self.pkgbase_mismatch {
+            log::info!(
+                "Found split package at different version in repo '{repo_db_prefix}': '{}': {}",
+                entry.db_pair(),
+                entry.reason
+            );
+        }
+        for entry in &self.compression_conflicts {
+            log::info!(
+                "Found conflicting package file in repo '{repo_db_prefix}': '{}'",
+                entry.filename
+            );
+        }
+        for entry in &self.file_conflicts {
+            log::info!(
+                "Found package with conflicting files in repo '{repo_db_prefix}': '{}': {}",
+                entry.db_pair(),
+                entry.reason
+            );
+        }
+        for entry in &self.invalid_signature {
+            log::info!(
+                "Found package with invalid signature in repo '{repo_db_prefix}': '{}': {}",
+                entry.db_pair(),
+                entry.reason
+            );
+        }
+        if !self.newer_in_reference.is_empty() {
+            let new_pkgname_list =
+                self.newer_in_reference.iter().map(ReportEntry::db_pair).collect::<Vec<_>>();
+            log::info!("Found new pkgs from ref repo '{repo_db_prefix}': {new_pkgname_list:?}");
+        }
+    }
+}
+
+/// Package which has different versions in the sibling repos
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct VersionMismatch {
+    pub name: String,
+    /// Version of the package in each of the profiles
+    pub versions: BTreeMap<String, String>,
+}
+
+/// Package which is present only in some of the sibling repos
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MissingPackage {
+    pub name: String,
+    pub present_in: Vec<String>,
+    pub missing_from: Vec<String>,
+}
+
+/// Package which is built for another architecture than its repo
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchMismatch {
+    pub profile: String,
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    pub expected_arch: String,
+}
+
+/// Packages of the sibling repo, as read from its DB
+pub struct RepoPackages {
+    pub profile: String,
+    /// Architecture of the repo, the check is skipped if unknown
+    pub arch: Option<String>,
+    pub packages: Vec<DbPackage>,
+}
+
+/// Result of the consistency check of the sibling repos
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConsistencyReport {
+    pub group: String,
+    pub clean: bool,
+    pub version_mismatch: Vec<VersionMismatch>,
+    pub missing: Vec<MissingPackage>,
+    pub arch_mismatch: Vec<ArchMismatch>,
+}
+
+impl ConsistencyReport {
+    /// Compares the packages of the sibling repos
+    pub fn check(group: &str, repos: &[RepoPackages]) -> Self {
+        let mut report = Self { group: group.to_owned(), ..Default::default() };
+
+        // profile -> version of each package
+        let mut pkg_versions: BTreeMap<&str, BTreeMap<String, String>> = BTreeMap::new();
+        for repo in repos {
+            for db_pkg in &repo.packages {
+                pkg_versions
+                    .entry(&db_pkg.name)
+                    .or_default()
+                    .insert(repo.profile.clone(), db_pkg.version.clone());
+
+                let Some(expected_arch) = &repo.arch else { continue };
+                if db_pkg.arch != *expected_arch && db_pkg.arch != "any" {
+                    report.arch_mismatch.push(ArchMismatch {
+                        profile: repo.profile.clone(),
+                        name: db_pkg.name.clone(),
+                        version: db_pkg.version.clone(),
+                        arch: db_pkg.arch.clone(),
+                        expected_arch: expected_arch.clone(),
+                    });
+                }
+            }
+        }
+
+        for (name, versions) in pkg_versions {
+            if versions.len() < repos.len() {
+                report.missing.push(MissingPackage {
+                    name: name.to_owned(),
+                    present_in: versions.keys().cloned().collect(),
+                    missing_from: repos
+                        .iter()
+                        .filter(|repo| !versions.contains_key(&repo.profile))
+                        .map(|repo| repo.profile.clone())
+                        .collect(),
+                });
+            }
+            if versions.values().collect::<BTreeSet<_>>().len() > 1 {
+                report.version_mismatch.push(VersionMismatch { name: name.to_owned(), versions });
+            }
+        }
+
+        report.clean = report.version_mismatch.is_empty()
+            && report.missing.is_empty()
+            && report.arch_mismatch.is_empty();
+        report
+    }
+}
+
+impl Report for ConsistencyReport {
+    fn is_clean(&self) -> bool {
+        self.clean
+    }
+
+    fn log_entries(&self) {
+        let group = &self.group;
+        for entry in &self.version_mismatch {
+            log::info!(
+                "Found version mismatch in group '{group}': '{}' {:?}",
+                entry.name,
+                entry.versions
+            );
+        }
+        for entry in &self.missing {
+            log::info!(
+                "Found package missing in group '{group}': '{}' is missing from {:?}",
+                entry.name,
+                entry.missing_from
+            );
+        }
+        for entry in &self.arch_mismatch {
+            log::info!(
+                "Found arch mismatch in profile '{}': '{}-{}' has arch '{}', expected '{}'",
+                entry.profile,
+                entry.name,
+                entry.version,
+                entry.arch,
+                entry.expected_arch
+            );
+        }
+        if self.clean {
+            log::info!("Repos of group '{group}' are consistent");
+        }
+    }
+}
+
+/// Package of the repo with unsatisfied runtime dependencies
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BrokenPackage {
+    pub name: String,
+    pub version: String,
+    pub missing: Vec<String>,
+}
+
+/// Result of the dependency check of the repo
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct DepsReport {
+    pub repo: String,
+    pub clean: bool,
+    pub broken: Vec<BrokenPackage>,
+}
+
+impl DepsReport {
+    /// Resolves the dependencies of the repo packages against them and the upstream packages
+    pub fn check(repo: &str, repo_pkgs: &[DbPackage], upstream_pkgs: &[DbPackage]) -> Self {
+        let all_pkgs = repo_pkgs.iter().chain(upstream_pkgs).collect::<Vec<_>>();
+        let broken = alpm_helper::get_broken_dependencies(repo_pkgs, &all_pkgs)
+            .into_iter()
+            .map(|(db_pkg, missing)| BrokenPackage {
+                name: db_pkg.name.clone(),
+                version: db_pkg.version.clone(),
+                missing,
+            })
+            .collect::<Vec<_>>();
+        Self { repo: repo.to_owned(), clean: broken.is_empty(), broken }
+    }
+}
+
+impl Report for DepsReport {
+    fn is_clean(&self) -> bool {
+        self.clean
+    }
+
+    fn log_entries(&self) {
+        let repo_db_prefix = &self.repo;
+        for entry in &self.broken {
+            log::info!(
+                "Found unsatisfied dependencies in repo '{repo_db_prefix}': '{}-{}' {:?}",
+                entry.name,
+                entry.version,
+                entry.missing
+            );
+        }
+        if self.clean {
+            log::info!("Dependencies of repo '{repo_db_prefix}' are satisfied");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alpm_helper::test_utils::db_pkg;
+    use crate::report::*;
+
+    #[test]
+    fn test_checkup_report_json() {
+        let mut report = CheckupReport::new("repof");
+        report.finish();
+        assert!(report.clean);
+
+        report.stale.push(ReportEntry::from_db_pkg(
+            &DbPackage {
+                name: "st".into(),
+                version: "0.8.4-2".into(),
+                filename: "st-0.8.4-2-x86_64.pkg.tar.zst".into(),
+                ..Default::default()
+            },
+            "missing",
+        ));
+        report.finish();
+        assert!(!report.clean);
+
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["clean"], false);
+        assert_eq!(json["stale"][0]["name"], "st");
+        assert_eq!(json["stale"][0]["version"], "0.8.4-2");
+        assert_eq!(json["stale"][0]["filename"], "st-0.8.4-2-x86_64.pkg.tar.zst");
+        assert_eq!(json["stale"][0]["reason"], "missing");
+        assert_eq!(json["compression_conflicts"], serde_json::json!([]));
+        assert_eq!(json["file_conflicts"], serde_json::json!([]));
+        assert_eq!(json["newer_in_reference"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_consistency_report() {
+        let db_pkg = |name: &str, version: &str, arch: &str| DbPackage {
+            arch: arch.into(),
+            ..db_pkg(name, version, &[], &[])
+        };
+        let repos = [
+            RepoPackages {
+                profile: "core-v3".into(),
+                arch: Some("x86_64_v3".into()),
+                packages: vec![
+                    db_pkg("dash", "0.5.12-1", "x86_64_v3"),
+                    db_pkg("dwm", "6.2-4", "x86_64_v3"),
+                    db_pkg("st", "0.8.4-2", "x86_64"),
+                    db_pkg("ca-certificates", "20240618-1", "any"),
+                ],
+            },
+            RepoPackages {
+                profile: "core-v4".into(),
+                arch: Some("x86_64_v4".into()),
+                packages: vec![
+                    db_pkg("dash", "0.5.12-2", "x86_64_v4"),
+                    db_pkg("st", "0.8.4-2", "x86_64_v4"),
+                    db_pkg("ca-certificates", "20240618-1", "any"),
+                ],
+            },
+        ];
+
+        let report = ConsistencyReport::check("core", &repos);
+        assert!(!report.clean);
+        assert_eq!(report.version_mismatch, vec![VersionMismatch {
+            name: "dash".into(),
+            versions: BTreeMap::from([
+                ("core-v3".into(), "0.5.12-1".into()),
+                ("core-v4".into(), "0.5.12-2".into()),
+            ]),
+        }]);
+        assert_eq!(report.missing, vec![MissingPackage {
+            name: "dwm".into(),
+            present_in: vec!["core-v3".into()],
+            missing_from: vec!["core-v4".into()],
+        }]);
+        assert_eq!(report.arch_mismatch, vec![ArchMismatch {
+            profile: "core-v3".into(),
+            name: "st".into(),
+            version: "0.8.4-2".into(),
+            arch: "x86_64".into(),
+            expected_arch: "x86_64_v3".into(),
+        }]);
+
+        let report = ConsistencyReport::check("core", &repos[1..]);
+        assert!(report.clean);
+    }
+}
@@ -0,0 +1,309 @@
+use std::io::BufReader;
+use std::path::Path;
+use std::time::SystemTime;
+use std::{fmt, fs};
+
+use anyhow::{Context, Result};
+use pgp::composed::{Deserializable, SignedPublicKey, StandaloneSignature};
+use pgp::packet::{Signature, SignatureType};
+use pgp::types::{KeyDetails, PublicKeyTrait};
+
+// extensions of the key files taken from the keyring directory
+const KEY_FILE_EXTENSIONS: [&str; 3] = ["asc", "gpg", "pgp"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The detached signature file doesn't exist
+    Missing(String),
+    /// The signing key is not in the keyring
+    UnknownKey(String),
+    /// The signing key is expired
+    ExpiredKey(String),
+    /// The signing key is revoked
+    RevokedKey(String),
+    /// The signature itself is expired
+    ExpiredSignature(String),
+    /// The signature doesn't match the file
+    BadSignature(String),
+    /// The signature can't be verified, e.g the signature is malformed
+    VerifyFailed(String),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(sig_path) => write!(f, "Signature '{sig_path}' doesn't exist"),
+            Self::UnknownKey(key_id) => write!(f, "Signed by unknown key {key_id}"),
+            Self::ExpiredKey(key_id) => write!(f, "Signed by expired key {key_id}"),
+            Self::RevokedKey(key_id) => write!(f, "Signed by revoked key {key_id}"),
+            Self::ExpiredSignature(key_id) => write!(f, "Signature by key {key_id} is expired"),
+            Self::BadSignature(key_id) => write!(f, "Bad signature by key {key_id}"),
+            Self::VerifyFailed(output) => write!(f, "Failed to verify signature: {output}"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Verifies detached signatures against the trusted packager keys.
+#[derive(Debug)]
+pub struct SignatureVerifier {
+    keys: Vec<SignedPublicKey>,
+}
+
+impl SignatureVerifier {
+    /// Loads the keys from the keyring file, or from the key files (`*.asc`, `*.gpg`, `*.pgp`) of
+    /// the keyring directory
+    pub fn new(keyring: &str) -> Result<Self> {
+        let key_files = if Path::new(keyring).is_dir() {
+            let mut key_files = vec![];
+            for entry in fs::read_dir(keyring)? {
+                let entry = entry?;
+                let path = entry.path();
+                let is_key_file = path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| KEY_FILE_EXTENSIONS.contains(&ext));
+                if entry.file_type()?.is_file() && is_key_file {
+                    key_files.push(path.to_str().unwrap().to_owned());
+                }
+            }
+            key_files.sort();
+            key_files
+        } else {
+            vec![keyring.to_owned()]
+        };
+
+        let mut keys = vec![];
+        for key_file in &key_files {
+            keys.extend(
+                load_keys(key_file)
+                    .with_context(|| format!("Failed to load keys from '{key_file}'"))?,
+            );
+        }
+        if keys.is_empty() {
+            anyhow::bail!("No keys found in the keyring '{keyring}'");
+        }
+
+        Ok(Self { keys })
+    }
+
+    /// Verifies the detached signature of the file, returns fingerprint of the signing key
+    pub fn verify(&self, file_path: &str, sig_path: &str) -> Result<String, SignatureError> {
+        if !Path::new(sig_path).exists() {
+            return Err(SignatureError::Missing(sig_path.to_owned()));
+        }
+
+        let sig = fs::File::open(sig_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|sig_file| Ok(StandaloneSignature::from_reader_single(sig_file)?.0))
+            .map_err(|err| SignatureError::VerifyFailed(err.to_string()))?
+            .signature;
+
+        let Some(key_id) = get_issuer_id(&sig) else {
+            return Err(SignatureError::VerifyFailed("signature has no issuer".to_owned()));
+        };
+        let is_issuer = |key: &dyn KeyDetails| {
+            sig.issuer().contains(&&key.key_id())
+                || sig.issuer_fingerprint().contains(&&key.fingerprint())
+        };
+
+        for key in &self.keys {
+            // revocation of the primary key revokes the subkeys as well
+            let is_key_revoked = !key.details.revocation_signatures.is_empty();
+            if is_issuer(&key.primary_key) {
+                let expires_at = key.expires_at().map(SystemTime::from);
+                verify_with_key(
+                    &sig,
+                    &key.primary_key,
+                    &key_id,
+                    expires_at,
+                    is_key_revoked,
+                    file_path,
+                )?;
+                return Ok(hex::encode_upper(key.fingerprint().as_bytes()));
+            }
+            for subkey in &key.public_subkeys {
+                if !is_issuer(subkey) {
+                    continue;
+                }
+                let is_revoked = is_key_revoked
+                    || subkey
+                        .signatures
+                        .iter()
+                        .any(|s| s.typ() == Some(SignatureType::SubkeyRevocation));
+                let expires_at = subkey
+                    .signatures
+                    .iter()
+                    .find(|s| s.typ() == Some(SignatureType::SubkeyBinding))
+                    .and_then(|s| s.key_expiration_time())
+                    .map(|expires_in| SystemTime::from(*subkey.key.created_at() + *expires_in));
+                verify_with_key(&sig, &subkey.key, &key_id, expires_at, is_revoked, file_path)?;
+                return Ok(hex::encode_upper(key.fingerprint().as_bytes()));
+            }
+        }
+
+        Err(SignatureError::UnknownKey(key_id))
+    }
+}
+
+// Reads the public keys of the key file, armored or binary, checking their self-signatures
+fn load_keys(key_file: &str) -> Result<Vec<SignedPublicKey>> {
+    let (keys, _) = SignedPublicKey::from_reader_many(fs::File::open(key_file)?)?;
+    let mut loaded_keys = vec![];
+    for key in keys {
+        let key = key?;
+        key.verify()?;
+        loaded_keys.push(key);
+    }
+    Ok(loaded_keys)
+}
+
+// Gets the id of the signing key, as gpg shows it
+fn get_issuer_id(sig: &Signature) -> Option<String> {
+    if let Some(key_id) = sig.issuer().first() {
+        return Some(hex::encode_upper(key_id));
+    }
+    sig.issuer_fingerprint().first().map(|fingerprint| hex::encode_upper(fingerprint.as_bytes()))
+}
+
+// Checks the signing key is valid and verifies the signature of the file with it
+fn verify_with_key(
+    sig: &Signature,
+    signing_key: &impl PublicKeyTrait,
+    key_id: &str,
+    expires_at: Option<SystemTime>,
+    is_revoked: bool,
+    file_path: &str,
+) -> Result<(), SignatureError> {
+    let now = SystemTime::now();
+    if is_revoked {
+        return Err(SignatureError::RevokedKey(key_id.to_owned()));
+    }
+    if expires_at.is_some_and(|expires_at| expires_at < now) {
+        return Err(SignatureError::ExpiredKey(key_id.to_owned()));
+    }
+    if let (Some(created), Some(expires_in)) = (sig.created(), sig.signature_expiration_time()) {
+        if SystemTime::from(*created + *expires_in) < now {
+            return Err(SignatureError::ExpiredSignature(key_id.to_owned()));
+        }
+    }
+
+    let file = fs::File::open(file_path)
+        .map_err(|err| SignatureError::VerifyFailed(format!("'{file_path}': {err}")))?;
+    sig.verify(signing_key, BufReader::new(file))
+        .map_err(|_| SignatureError::BadSignature(key_id.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signature::*;
+    use crate::utils;
+
+    use pgp::composed::{ArmorOptions, KeyType, SecretKeyParamsBuilder, SignedSecretKey};
+    use pgp::crypto::hash::HashAlgorithm;
+    use pgp::packet::{PacketTrait, SignatureConfig, Subpacket, SubpacketData};
+    use pgp::ser::Serialize;
+    use pgp::types::Password;
+
+    fn generate_key(user_id: &str) -> SignedSecretKey {
+        SecretKeyParamsBuilder::default()
+            .key_type(KeyType::Ed25519Legacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id(user_id.to_owned())
+            .build()
+            .unwrap()
+            .generate(rand::thread_rng())
+            .unwrap()
+            .sign(&mut rand::thread_rng(), &Password::empty())
+            .unwrap()
+    }
+
+    fn sign_file(key: &SignedSecretKey, file_path: &str) {
+        let mut sig_config =
+            SignatureConfig::v4(SignatureType::Binary, key.algorithm(), HashAlgorithm::Sha256);
+        sig_config.hashed_subpackets = vec![
+            Subpacket::regular(SubpacketData::SignatureCreationTime(SystemTime::now().into()))
+                .unwrap(),
+            Subpacket::regular(SubpacketData::IssuerFingerprint(key.fingerprint())).unwrap(),
+        ];
+        sig_config.unhashed_subpackets =
+            vec![Subpacket::regular(SubpacketData::Issuer(key.key_id())).unwrap()];
+        let sig = sig_config
+            .sign(&key.primary_key, &Password::empty(), fs::File::open(file_path).unwrap())
+            .unwrap();
+
+        let mut sig_file = fs::File::create(format!("{file_path}.sig")).unwrap();
+        sig.to_writer_with_header(&mut sig_file).unwrap();
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let temp_dir = utils::create_temporary_directory(None).unwrap();
+
+        // packager key, and the key which is not trusted
+        let packager_key = generate_key("Packager <packager@example.org>");
+        let stranger_key = generate_key("Stranger <stranger@example.org>");
+        let keyring_dir = format!("{temp_dir}/keys");
+        fs::create_dir(&keyring_dir).unwrap();
+        let key_path = format!("{keyring_dir}/packager.asc");
+        let armored_key =
+            packager_key.signed_public_key().to_armored_string(ArmorOptions::default()).unwrap();
+        fs::write(&key_path, armored_key).unwrap();
+        // binary keys are loaded too, other files of the keyring dir are skipped
+        let maintainer_key = generate_key("Maintainer <maintainer@example.org>");
+        fs::write(
+            format!("{keyring_dir}/maintainer.gpg"),
+            maintainer_key.signed_public_key().to_bytes().unwrap(),
+        )
+        .unwrap();
+        fs::write(format!("{keyring_dir}/README"), "trusted packager keys").unwrap();
+
+        let pkgfile = format!("{temp_dir}/dash-0.5.12-1-x86_64.pkg.tar.zst");
+        let other_pkgfile = format!("{temp_dir}/dwm-6.2-4-x86_64.pkg.tar.zst");
+        let maintainer_pkgfile = format!("{temp_dir}/lzo-2.10-3-x86_64.pkg.tar.zst");
+        fs::write(&pkgfile, "dash").unwrap();
+        fs::write(&other_pkgfile, "dwm").unwrap();
+        fs::write(&maintainer_pkgfile, "lzo").unwrap();
+        sign_file(&packager_key, &pkgfile);
+        sign_file(&stranger_key, &other_pkgfile);
+        sign_file(&maintainer_key, &maintainer_pkgfile);
+
+        let packager_fingerprint = hex::encode_upper(packager_key.fingerprint().as_bytes());
+        for keyring in [&keyring_dir, &key_path] {
+            let verifier = SignatureVerifier::new(keyring).unwrap();
+            assert_eq!(
+                verifier.verify(&pkgfile, &format!("{pkgfile}.sig")),
+                Ok(packager_fingerprint.clone())
+            );
+
+            assert_eq!(
+                verifier.verify(&other_pkgfile, &format!("{other_pkgfile}.sig")),
+                Err(SignatureError::UnknownKey(hex::encode_upper(stranger_key.key_id())))
+            );
+            // the signature of another file
+            assert_eq!(
+                verifier.verify(&other_pkgfile, &format!("{pkgfile}.sig")),
+                Err(SignatureError::BadSignature(hex::encode_upper(packager_key.key_id())))
+            );
+            assert!(matches!(
+                verifier.verify(&pkgfile, &format!("{pkgfile}.missing.sig")),
+                Err(SignatureError::Missing(_))
+            ));
+            assert!(matches!(
+                verifier.verify(&pkgfile, &pkgfile),
+                Err(SignatureError::VerifyFailed(_))
+            ));
+        }
+        let verifier = SignatureVerifier::new(&keyring_dir).unwrap();
+        assert!(verifier.verify(&maintainer_pkgfile, &format!("{maintainer_pkgfile}.sig")).is_ok());
+
+        // the keyring dir without key files
+        fs::remove_file(&key_path).unwrap();
+        fs::remove_file(format!("{keyring_dir}/maintainer.gpg")).unwrap();
+        assert!(SignatureVerifier::new(&keyring_dir).is_err());
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+}
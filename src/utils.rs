@@ -1,5 +1,8 @@
-use std::{env, fs, slice, str};
+#[cfg(test)]
+use std::{env, fs};
+use std::{slice, str};
 
+#[cfg(test)]
 use rand::Rng;
 
 #[inline]
@@ -24,6 +27,7 @@ pub const fn string_substr(src_str: &str, pos: usize, n: usize) -> Result<&str,
     s
 }
 
+#[cfg(test)]
 pub fn create_temporary_directory(max_tries: Option<u32>) -> Option<String> {
     let tmp_dir = env::temp_dir();
     let max_tries = max_tries.unwrap_or(1000);
@@ -42,6 +46,22 @@ pub fn create_temporary_directory(max_tries: Option<u32>) -> Option<String> {
     }
 }
 
+/// Creates zstd compressed package archive with provided entries, e.g `.PKGINFO`
+#[cfg(test)]
+pub fn create_test_pkg(pkg_filepath: &str, entries: &[(&str, &str)]) {
+    let encoder = zstd::stream::write::Encoder::new(fs::File::create(pkg_filepath).unwrap(), 0)
+        .unwrap()
+        .auto_finish();
+    let mut builder = tar::Builder::new(encoder);
+    for (entry_path, content) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, entry_path, content.as_bytes()).unwrap();
+    }
+    builder.into_inner().unwrap();
+}
+
 #[cfg(test)]
 mod tests {
     #[test]